@@ -0,0 +1,245 @@
+#![allow(clippy::manual_div_ceil)]
+
+use deku::prelude::*;
+
+// POSIX ACLs are kept in the system.posix_acl_access and system.posix_acl_default
+// xattrs. The encoding is a version header followed by the entries, the ones that
+// don't name a user or a group are stored without the id:
+// ┌─────────┬─────┬──────┬──────┬─────┬─────┐
+// │ Version │ Tag │ Perm │ [Id] │ ... │ Tag │ ...
+// └─────────┴─────┴──────┴──────┴─────┴─────┘
+pub const ACL_VERSION: u32 = 1;
+
+pub const ACL_ACCESS_XATTR: &str = "system.posix_acl_access";
+pub const ACL_DEFAULT_XATTR: &str = "system.posix_acl_default";
+
+// Entry tags, same values as in Linux
+pub const ACL_USER_OBJ: u16 = 0x01;
+pub const ACL_USER: u16 = 0x02;
+pub const ACL_GROUP_OBJ: u16 = 0x04;
+pub const ACL_GROUP: u16 = 0x08;
+pub const ACL_MASK: u16 = 0x10;
+pub const ACL_OTHER: u16 = 0x20;
+
+// Permissions, also what `access` takes as a mask
+pub const ACL_READ: u16 = 0x04;
+pub const ACL_WRITE: u16 = 0x02;
+pub const ACL_EXECUTE: u16 = 0x01;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AclType {
+    Access,
+    Default,
+}
+
+impl AclType {
+    pub fn xattr_name(&self) -> &'static str {
+        match self {
+            AclType::Access => ACL_ACCESS_XATTR,
+            AclType::Default => ACL_DEFAULT_XATTR,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct AclEntry {
+    pub tag: u16,
+    pub perm: u16,
+    #[deku(cond = "*tag == ACL_USER || *tag == ACL_GROUP")]
+    pub id: Option<u32>,
+}
+
+impl AclEntry {
+    pub fn new(tag: u16, perm: u16, id: Option<u32>) -> Self {
+        Self { tag, perm, id }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Acl {
+    entries: Vec<AclEntry>,
+}
+
+impl Acl {
+    pub fn new(mut entries: Vec<AclEntry>) -> Result<Self, std::io::Error> {
+        entries.sort_by_key(|entry| (entry.tag, entry.id));
+        let acl = Self { entries };
+        acl.validate()?;
+        Ok(acl)
+    }
+
+    // The ACL equivalent to the permission bits of `mode`
+    pub fn from_mode(mode: u16) -> Self {
+        Self {
+            entries: vec![
+                AclEntry::new(ACL_USER_OBJ, (mode >> 6) & 0o7, None),
+                AclEntry::new(ACL_GROUP_OBJ, (mode >> 3) & 0o7, None),
+                AclEntry::new(ACL_OTHER, mode & 0o7, None),
+            ],
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        if bytes.len() < 4 || bytes[..4] != ACL_VERSION.to_le_bytes() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Unsupported ACL version",
+            )));
+        }
+
+        let mut entries = Vec::new();
+        let mut rest = &bytes[4..];
+        while !rest.is_empty() {
+            let ((next, _), entry) = AclEntry::from_bytes((rest, 0))?;
+            rest = next;
+            entries.push(entry);
+        }
+        Ok(Self::new(entries)?)
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, DekuError> {
+        let mut buffer = ACL_VERSION.to_le_bytes().to_vec();
+        for entry in &self.entries {
+            buffer.extend_from_slice(&entry.to_bytes()?);
+        }
+        Ok(buffer)
+    }
+
+    pub fn entries(&self) -> &[AclEntry] {
+        &self.entries
+    }
+
+    // Needs exactly one owner, owning group and other entry, a mask as soon as
+    // there are named users or groups, and no name twice
+    fn validate(&self) -> Result<(), std::io::Error> {
+        let count = |tag| self.entries.iter().filter(|entry| entry.tag == tag).count();
+        let named = count(ACL_USER) + count(ACL_GROUP);
+        let masks = count(ACL_MASK);
+        let mut ids: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|entry| entry.id.is_some())
+            .map(|entry| (entry.tag, entry.id))
+            .collect();
+        ids.sort();
+        ids.dedup();
+
+        let valid = count(ACL_USER_OBJ) == 1
+            && count(ACL_GROUP_OBJ) == 1
+            && count(ACL_OTHER) == 1
+            && masks <= 1
+            && (named == 0 || masks == 1)
+            && ids.len() == named
+            && self.entries.iter().all(|entry| {
+                entry.perm & !0o7 == 0
+                    && entry.id.is_some() == matches!(entry.tag, ACL_USER | ACL_GROUP)
+            });
+        if !valid {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid ACL",
+            ));
+        }
+        Ok(())
+    }
+
+    // an ACL with only the three base entries says nothing the mode doesn't
+    pub fn is_minimal(&self) -> bool {
+        self.entries.len() == 3
+    }
+
+    fn has_mask(&self) -> bool {
+        self.entries.iter().any(|entry| entry.tag == ACL_MASK)
+    }
+
+    fn perm_of(&self, tag: u16) -> u16 {
+        self.entries
+            .iter()
+            .find(|entry| entry.tag == tag)
+            .map_or(0, |entry| entry.perm)
+    }
+
+    // The permission bits an access ACL maps to, the group class is the mask when
+    // there is one
+    pub fn mode_bits(&self) -> u16 {
+        let group = if self.has_mask() {
+            self.perm_of(ACL_MASK)
+        } else {
+            self.perm_of(ACL_GROUP_OBJ)
+        };
+        (self.perm_of(ACL_USER_OBJ) << 6) | (group << 3) | self.perm_of(ACL_OTHER)
+    }
+
+    // Make the ACL agree with new permission bits, the reverse of `mode_bits`
+    pub fn apply_mode(&mut self, mode: u16) {
+        let has_mask = self.has_mask();
+        for entry in self.entries.iter_mut() {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm = (mode >> 6) & 0o7,
+                ACL_MASK => entry.perm = (mode >> 3) & 0o7,
+                ACL_GROUP_OBJ if !has_mask => entry.perm = (mode >> 3) & 0o7,
+                ACL_OTHER => entry.perm = mode & 0o7,
+                _ => {}
+            }
+        }
+    }
+
+    // The access ACL a new inode gets out of its parent's default ACL, restricted
+    // by the mode it's created with (see acl(5), "object creation")
+    pub fn inherit(&self, mode: u16) -> Self {
+        let mut acl = self.clone();
+        let has_mask = acl.has_mask();
+        for entry in acl.entries.iter_mut() {
+            match entry.tag {
+                ACL_USER_OBJ => entry.perm &= (mode >> 6) & 0o7,
+                ACL_MASK => entry.perm &= (mode >> 3) & 0o7,
+                ACL_GROUP_OBJ if !has_mask => entry.perm &= (mode >> 3) & 0o7,
+                ACL_OTHER => entry.perm &= mode & 0o7,
+                _ => {}
+            }
+        }
+        acl
+    }
+
+    // The POSIX access check algorithm: the first matching entry of owner, named
+    // user, groups and other decides, named users and all groups go through the mask
+    pub fn permits(&self, owner: u32, group: u32, uid: u32, gids: &[u32], mask: u16) -> bool {
+        let mask_perm = if self.has_mask() {
+            self.perm_of(ACL_MASK)
+        } else {
+            0o7
+        };
+
+        if uid == owner {
+            return self.perm_of(ACL_USER_OBJ) & mask == mask;
+        }
+        if let Some(entry) = self
+            .entries
+            .iter()
+            .find(|entry| entry.tag == ACL_USER && entry.id == Some(uid))
+        {
+            return entry.perm & mask_perm & mask == mask;
+        }
+
+        let mut group_matched = false;
+        for entry in &self.entries {
+            let matches = match entry.tag {
+                ACL_GROUP_OBJ => gids.contains(&group),
+                ACL_GROUP => entry.id.is_some_and(|id| gids.contains(&id)),
+                _ => false,
+            };
+            if matches {
+                if entry.perm & mask_perm & mask == mask {
+                    return true;
+                }
+                group_matched = true;
+            }
+        }
+        if group_matched {
+            return false;
+        }
+
+        self.perm_of(ACL_OTHER) & mask == mask
+    }
+}
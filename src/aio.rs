@@ -0,0 +1,482 @@
+use std::{
+    future::Future,
+    io::SeekFrom,
+    os::unix::fs::FileExt,
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    task::JoinHandle,
+};
+
+use crate::{
+    dir::DirItem,
+    inode,
+    partition::CfsPartition,
+    shared::{Extent, SharedPartition},
+};
+
+// The device an AsyncPartition reads and writes file contents through. The
+// metadata still goes through the partition's own File on the blocking pool, so
+// it has to be the same image, say an io_uring handle on it.
+pub trait AsyncBlockDevice: Send + Sync + 'static {
+    // Read exactly `len` bytes at `offset`
+    fn read_at(
+        &self,
+        offset: u64,
+        len: usize,
+    ) -> impl Future<Output = std::io::Result<Vec<u8>>> + Send;
+
+    // Write all of `data` at `offset`
+    fn write_at(
+        &self,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> impl Future<Output = std::io::Result<()>> + Send;
+}
+
+// The default AsyncBlockDevice, positioned I/O on a File run on the blocking pool
+#[derive(Clone)]
+pub struct FileDevice {
+    file: Arc<std::fs::File>,
+}
+
+impl FileDevice {
+    pub fn new(file: std::fs::File) -> Self {
+        Self {
+            file: Arc::new(file),
+        }
+    }
+}
+
+impl AsyncBlockDevice for FileDevice {
+    async fn read_at(&self, offset: u64, len: usize) -> std::io::Result<Vec<u8>> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; len];
+            file.read_exact_at(&mut buf, offset)?;
+            Ok(buf)
+        })
+        .await
+        .map_err(std::io::Error::other)?
+    }
+
+    async fn write_at(&self, offset: u64, data: Vec<u8>) -> std::io::Result<()> {
+        let file = self.file.clone();
+        tokio::task::spawn_blocking(move || file.write_all_at(&data, offset))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+}
+
+// An async front for SharedPartition. Metadata operations run on tokio's blocking
+// pool so executor threads never wait on the device, and they go through the
+// same code as the sync API, so images come out exactly the same either way.
+// File contents are read and written through an AsyncBlockDevice, with the
+// inode locked the same way SharedPartition does it.
+// Errors are plain io::Errors, the ones that weren't already keep their message.
+pub struct AsyncPartition<D: AsyncBlockDevice = FileDevice> {
+    shared: SharedPartition,
+    device: Arc<D>,
+}
+
+impl<D: AsyncBlockDevice> Clone for AsyncPartition<D> {
+    fn clone(&self) -> Self {
+        Self {
+            shared: self.shared.clone(),
+            device: self.device.clone(),
+        }
+    }
+}
+
+impl AsyncPartition {
+    // File contents go through a FileDevice on the partition's device
+    pub fn new(shared: SharedPartition) -> std::io::Result<Self> {
+        let device = FileDevice::new(shared.data_dev().try_clone()?);
+        Ok(Self::with_device(shared, device))
+    }
+
+    // Open an existing image for writing, like CfsPartition's TryFrom
+    pub async fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        spawn(move || {
+            let blk_dev = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(path)?;
+            Ok(Self::new(SharedPartition::new(CfsPartition::try_from(
+                blk_dev,
+            )?)?)?)
+        })
+        .await
+    }
+
+    pub async fn open_read_only(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        spawn(move || {
+            let blk_dev = std::fs::File::open(path)?;
+            Ok(Self::new(SharedPartition::new(
+                CfsPartition::open_read_only(blk_dev)?,
+            )?)?)
+        })
+        .await
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncPartition<D> {
+    pub fn with_device(shared: SharedPartition, device: D) -> Self {
+        Self {
+            shared,
+            device: Arc::new(device),
+        }
+    }
+
+    pub fn shared(&self) -> &SharedPartition {
+        &self.shared
+    }
+
+    // Run `f` with the partition all to itself, see SharedPartition::exclusive
+    pub async fn exclusive<R, F>(&self, f: F) -> std::io::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut CfsPartition) -> R + Send + 'static,
+    {
+        self.run(move |shared| Ok(shared.exclusive(f))).await
+    }
+
+    pub async fn read_dir(&self, inode_idx: usize) -> std::io::Result<Vec<DirItem>> {
+        self.run(move |shared| Ok(shared.read_dir(inode_idx)?))
+            .await
+    }
+
+    pub async fn stat(&self, inode_idx: usize) -> std::io::Result<inode::Stat> {
+        self.run(move |shared| shared.stat(inode_idx)).await
+    }
+
+    pub fn open_file(&self, inode_idx: usize) -> AsyncFileHandle<D> {
+        AsyncFileHandle {
+            partition: self.clone(),
+            inode_idx,
+            pos: 0,
+            pending: None,
+            pending_write: None,
+        }
+    }
+
+    // Read up to `len` bytes at `offset`, only ever one block like read_at
+    pub async fn read_at(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        len: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let _lock = self
+            .run(move |shared| Ok(shared.read_inode(inode_idx)))
+            .await?;
+        self.read_locked(inode_idx, offset, len).await
+    }
+
+    pub async fn get_data_from_inode(&self, inode_idx: usize) -> std::io::Result<Vec<u8>> {
+        let _lock = self
+            .run(move |shared| Ok(shared.read_inode(inode_idx)))
+            .await?;
+        let mut data = Vec::new();
+        loop {
+            let block = self
+                .read_locked(inode_idx, data.len() as u64, usize::MAX)
+                .await?;
+            if block.is_empty() {
+                return Ok(data);
+            }
+            data.extend_from_slice(&block);
+        }
+    }
+
+    // Write to a file at `offset`, only ever one block like SharedPartition's
+    // write_at. The number of bytes written is returned.
+    pub async fn write_at(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        data: &[u8],
+    ) -> std::io::Result<usize> {
+        let data = data.to_vec();
+        let (_lock, len, device_offset, mut data) = self
+            .run(move |shared| {
+                let lock = shared.write_inode(inode_idx);
+                let (len, device_offset) = shared.prepare_write(inode_idx, offset, &data)?;
+                Ok((lock, len, device_offset, data))
+            })
+            .await?;
+        if let Some(device_offset) = device_offset {
+            data.truncate(len);
+            self.device.write_at(device_offset, data).await?;
+        }
+        Ok(len)
+    }
+
+    pub async fn add_file_to_inode(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+        file: tokio::fs::File,
+    ) -> std::io::Result<()> {
+        let name = name.to_string();
+        let mut file = file.into_std().await;
+        self.run(move |shared| shared.add_file_to_inode(parent_inode_idx, &name, &mut file))
+            .await
+    }
+
+    pub async fn add_dir_to_inode(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+    ) -> std::io::Result<()> {
+        let name = name.to_string();
+        self.run(move |shared| shared.add_dir_to_inode(parent_inode_idx, &name))
+            .await
+    }
+
+    pub async fn mknod(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+        mode: u16,
+        rdev: u32,
+    ) -> std::io::Result<usize> {
+        let name = name.to_string();
+        self.run(move |shared| shared.mknod(parent_inode_idx, &name, mode, rdev))
+            .await
+    }
+
+    pub async fn remove_dir_from_inode(
+        &self,
+        parent_inode_idx: usize,
+        inode_idx: u32,
+    ) -> std::io::Result<()> {
+        self.run(move |shared| shared.remove_dir_from_inode(parent_inode_idx, inode_idx))
+            .await
+    }
+
+    pub async fn truncate(&self, inode_idx: usize, new_len: u64) -> std::io::Result<()> {
+        self.run(move |shared| shared.truncate(inode_idx, new_len))
+            .await
+    }
+
+    pub async fn fallocate(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        len: u64,
+        mode: u32,
+    ) -> std::io::Result<()> {
+        self.run(move |shared| shared.fallocate(inode_idx, offset, len, mode))
+            .await
+    }
+
+    pub async fn setattr(&self, inode_idx: usize, attr: inode::SetAttr) -> std::io::Result<()> {
+        self.run(move |shared| shared.setattr(inode_idx, &attr))
+            .await
+    }
+
+    pub async fn getxattr(&self, inode_idx: usize, name: &str) -> std::io::Result<Vec<u8>> {
+        let name = name.to_string();
+        self.run(move |shared| shared.getxattr(inode_idx, &name))
+            .await
+    }
+
+    pub async fn setxattr(
+        &self,
+        inode_idx: usize,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> std::io::Result<()> {
+        let (name, value) = (name.to_string(), value.to_vec());
+        self.run(move |shared| shared.setxattr(inode_idx, &name, &value, flags))
+            .await
+    }
+
+    pub async fn listxattr(&self, inode_idx: usize) -> std::io::Result<Vec<String>> {
+        self.run(move |shared| shared.listxattr(inode_idx)).await
+    }
+
+    pub async fn removexattr(&self, inode_idx: usize, name: &str) -> std::io::Result<()> {
+        let name = name.to_string();
+        self.run(move |shared| shared.removexattr(inode_idx, &name))
+            .await
+    }
+
+    // a read with the inode already locked, the bytes come from the device unless
+    // they are known without it
+    async fn read_locked(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        len: usize,
+    ) -> std::io::Result<Vec<u8>> {
+        let extent = self
+            .run(move |shared| Ok(shared.plan_read(inode_idx, offset, len)))
+            .await?;
+        match extent {
+            Extent::Data(data) => Ok(data),
+            Extent::Device { offset, len } => self.device.read_at(offset, len).await,
+        }
+    }
+
+    fn run<T, F>(&self, f: F) -> impl Future<Output = std::io::Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&SharedPartition) -> Result<T, Box<dyn std::error::Error>> + Send + 'static,
+    {
+        let shared = self.shared.clone();
+        spawn(move || f(&shared))
+    }
+}
+
+// A cursor over the contents of a file inode, the async FileHandle
+pub struct AsyncFileHandle<D: AsyncBlockDevice = FileDevice> {
+    partition: AsyncPartition<D>,
+    inode_idx: usize,
+    pos: u64,
+    // the read poll_read is waiting for
+    pending: Option<JoinHandle<std::io::Result<Vec<u8>>>>,
+    // the write poll_write is waiting for and the data it was started with
+    pending_write: Option<(JoinHandle<std::io::Result<usize>>, Vec<u8>)>,
+}
+
+impl<D: AsyncBlockDevice> AsyncFileHandle<D> {
+    pub fn inode_idx(&self) -> usize {
+        self.inode_idx
+    }
+
+    pub async fn size(&self) -> std::io::Result<u64> {
+        Ok(self.partition.stat(self.inode_idx).await?.size)
+    }
+
+    pub async fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        // a write may already be on its way to the device, let it land where it
+        // was meant to
+        if let Some((pending, _)) = self.pending_write.take() {
+            let len = pending.await.map_err(std::io::Error::other)??;
+            self.pos += len as u64;
+        }
+
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().await?.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        match pos {
+            Some(pos) => {
+                // a read started before the seek would land at the old position
+                if let Some(pending) = self.pending.take() {
+                    pending.abort();
+                }
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative offset",
+            )),
+        }
+    }
+}
+
+impl<D: AsyncBlockDevice> AsyncRead for AsyncFileHandle<D> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = &mut *self;
+        let pending = this.pending.get_or_insert_with(|| {
+            let partition = this.partition.clone();
+            let (inode_idx, offset, len) = (this.inode_idx, this.pos, buf.remaining());
+            tokio::spawn(async move { partition.read_at(inode_idx, offset, len).await })
+        });
+
+        let result = match Pin::new(pending).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.pending = None;
+
+        // the buffer may have shrunk since the read was started
+        let data = result.map_err(std::io::Error::other)??;
+        let len = data.len().min(buf.remaining());
+        buf.put_slice(&data[..len]);
+        this.pos += len as u64;
+        Poll::Ready(Ok(()))
+    }
+}
+
+// Every write is on the device by the time poll_write returns it, there is
+// nothing to flush
+impl<D: AsyncBlockDevice> AsyncWrite for AsyncFileHandle<D> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = &mut *self;
+        let (pending, data) = this.pending_write.get_or_insert_with(|| {
+            let partition = this.partition.clone();
+            let (inode_idx, offset, data) = (this.inode_idx, this.pos, buf.to_vec());
+            let pending = tokio::spawn({
+                let data = data.clone();
+                async move { partition.write_at(inode_idx, offset, &data).await }
+            });
+            (pending, data)
+        });
+        // what's written is only known once it's done, it has to be the start of
+        // the buffer the caller is asking about
+        if !buf.starts_with(data) {
+            return Poll::Ready(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "poll_write called with different data while a write is pending",
+            )));
+        }
+
+        let result = match Pin::new(pending).poll(cx) {
+            Poll::Ready(result) => result,
+            Poll::Pending => return Poll::Pending,
+        };
+        this.pending_write = None;
+
+        let len = result.map_err(std::io::Error::other)??;
+        this.pos += len as u64;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+async fn spawn<T, F>(f: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T, Box<dyn std::error::Error>> + Send + 'static,
+{
+    tokio::task::spawn_blocking(move || f().map_err(into_io_error))
+        .await
+        .map_err(std::io::Error::other)?
+}
+
+fn into_io_error(e: Box<dyn std::error::Error>) -> std::io::Error {
+    match e.downcast::<std::io::Error>() {
+        Ok(e) => *e,
+        Err(e) => std::io::Error::other(e.to_string()),
+    }
+}
@@ -0,0 +1,53 @@
+use cfs::{partition::CfsPartition, superblock::CheckPolicy};
+
+// Parses sizes like 4096, 512K, 64M or 2G
+fn parse_size(size: &str) -> Option<u64> {
+    let (digits, shift) = match size.chars().last()?.to_ascii_uppercase() {
+        'K' => (&size[..size.len() - 1], 10),
+        'M' => (&size[..size.len() - 1], 20),
+        'G' => (&size[..size.len() - 1], 30),
+        _ => (size, 0),
+    };
+    digits.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    cfs::init_library_logger();
+
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("Usage: {} <image> <size>[K|M|G]", args[0]);
+        std::process::exit(1);
+    }
+
+    let new_size = match parse_size(&args[2]) {
+        Some(new_size) => new_size,
+        None => {
+            eprintln!("Invalid size: {}", args[2]);
+            std::process::exit(1);
+        }
+    };
+
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&args[1])?;
+    // moving blocks around an inconsistent image would only make things worse
+    let policy = CheckPolicy::default();
+    let mut partition =
+        CfsPartition::open_with_policy(blk_dev, |super_block| policy.check(super_block))?;
+    partition.resize(new_size)?;
+
+    let statfs = partition.statfs();
+    println!(
+        "{}: {} blocks of {} bytes, {} free, {} inodes, {} free",
+        args[1],
+        statfs.total_blocks,
+        statfs.block_size,
+        statfs.free_blocks,
+        statfs.total_inodes,
+        statfs.free_inodes
+    );
+
+    Ok(())
+}
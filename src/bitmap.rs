@@ -25,19 +25,83 @@ pub trait Bitmap {
         data[byte] & (1 << bit) != 0
     }
 
+    // number of clear bits among the first `len` ones
+    fn count_free(&mut self, len: usize) -> usize {
+        let data = self.get_data();
+        let full_bytes = len / 8;
+        let mut free = data[..full_bytes]
+            .iter()
+            .map(|byte| byte.count_zeros() as usize)
+            .sum();
+        for bit_index in 0..len % 8 {
+            if data[full_bytes] & (1 << bit_index) == 0 {
+                free += 1;
+            }
+        }
+        free
+    }
+
     fn first_free(&mut self) -> Option<usize> {
+        let len = self.get_data().len() * 8;
+        self.find_free_run(1, 0, len)
+    }
+
+    // Look for `len` consecutive clear bits in [start, end), whole 64-bit words are
+    // checked at once so full and empty regions are skipped quickly
+    fn find_free_run(&mut self, len: usize, start: usize, end: usize) -> Option<usize> {
         let data = self.get_data();
-        for (byte_index, byte) in data.iter().enumerate() {
-            if *byte != 0xff {
-                for bit_index in 0..8 {
-                    if byte & (1 << bit_index) == 0 {
-                        return Some(byte_index * 8 + bit_index);
+        let end = end.min(data.len() * 8);
+        let mut run_start = start;
+        let mut run_len = 0;
+        let mut index = start;
+
+        while index < end {
+            let byte = index / 8;
+            if index.is_multiple_of(64) && index + 64 <= end && byte + 8 <= data.len() {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&data[byte..byte + 8]);
+                match u64::from_le_bytes(bytes) {
+                    u64::MAX => {
+                        run_len = 0;
+                        index += 64;
+                        continue;
+                    }
+                    0 => {
+                        if run_len == 0 {
+                            run_start = index;
+                        }
+                        run_len += 64;
+                        if run_len >= len {
+                            return Some(run_start);
+                        }
+                        index += 64;
+                        continue;
                     }
+                    _ => {}
                 }
             }
+
+            if data[byte] & (1 << (index % 8)) == 0 {
+                if run_len == 0 {
+                    run_start = index;
+                }
+                run_len += 1;
+                if run_len >= len {
+                    return Some(run_start);
+                }
+            } else {
+                run_len = 0;
+            }
+            index += 1;
         }
         None
     }
+
+    fn set_run(&mut self, start: usize, len: usize) {
+        for index in start..start + len {
+            self.set(index);
+        }
+    }
 }
 
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
@@ -49,8 +113,7 @@ pub struct Bam {
 
 impl Bam {
     pub fn new(size: usize) -> Self {
-        let mut data = Vec::new();
-        data.resize(size, 0);
+        let data = vec![0; size];
         Self { data }
     }
 }
@@ -64,14 +127,13 @@ impl Bitmap for Bam {
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
 #[deku(ctx = "super_block: superblock::SuperBlock")]
 pub struct Iam {
-    #[deku(count = "super_block.bam_blocks * super_block.blocksize")]
+    #[deku(count = "super_block.iam_blocks * super_block.blocksize")]
     pub data: Vec<u8>,
 }
 
 impl Iam {
     pub fn new(size: usize) -> Self {
-        let mut data = Vec::new();
-        data.resize(size, 0);
+        let data = vec![0; size];
         Self { data }
     }
 }
@@ -0,0 +1,183 @@
+use std::collections::{BTreeMap, HashMap};
+
+// How writes reach the device when the block cache is on
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum WritePolicy {
+    // every write goes straight to the device and stays cached for reading
+    WriteThrough,
+    // writes stay in the cache until the block is evicted or the cache flushed
+    WriteBack,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CacheConfig {
+    // in blocks
+    pub capacity: usize,
+    pub policy: WritePolicy,
+    // how many blocks a file handle reading sequentially loads ahead of itself
+    pub read_ahead: usize,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 256,
+            policy: WritePolicy::WriteThrough,
+            read_ahead: 8,
+        }
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    // blocks loaded by read-ahead
+    pub read_ahead: u64,
+    pub evictions: u64,
+    // dirty blocks written to the device, on eviction or flush
+    pub writebacks: u64,
+}
+
+struct CachedBlock {
+    data: Vec<u8>,
+    dirty: bool,
+    // when it was last used, the key of `lru`
+    tick: u64,
+}
+
+// A least recently used cache of data blocks, keyed by block index. It doesn't
+// do any I/O itself: dirty blocks it lets go of are handed back to the caller to
+// be written.
+pub struct BlockCache {
+    config: CacheConfig,
+    blocks: HashMap<usize, CachedBlock>,
+    lru: BTreeMap<u64, usize>,
+    tick: u64,
+    pub(crate) stats: CacheStats,
+}
+
+impl BlockCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self {
+            config,
+            blocks: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn config(&self) -> CacheConfig {
+        self.config
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn contains(&self, block_idx: usize) -> bool {
+        self.blocks.contains_key(&block_idx)
+    }
+
+    // Look a block up, counting a hit or a miss
+    pub fn get(&mut self, block_idx: usize) -> Option<&[u8]> {
+        let tick = self.next_tick();
+        match self.blocks.get_mut(&block_idx) {
+            Some(block) => {
+                self.stats.hits += 1;
+                self.lru.remove(&block.tick);
+                self.lru.insert(tick, block_idx);
+                block.tick = tick;
+                Some(&block.data)
+            }
+            None => {
+                self.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Cache a block, returning the dirty blocks evicted to make room for it. A
+    // clean copy never replaces a dirty one, that would lose a write.
+    pub fn insert(
+        &mut self,
+        block_idx: usize,
+        data: Vec<u8>,
+        dirty: bool,
+    ) -> Vec<(usize, Vec<u8>)> {
+        let tick = self.next_tick();
+        if let Some(block) = self.blocks.get_mut(&block_idx) {
+            self.lru.remove(&block.tick);
+            self.lru.insert(tick, block_idx);
+            block.tick = tick;
+            if dirty || !block.dirty {
+                block.data = data;
+                block.dirty = dirty;
+            }
+            return Vec::new();
+        }
+
+        let mut evicted = Vec::new();
+        while self.blocks.len() >= self.config.capacity.max(1) {
+            let Some((_, victim)) = self.lru.pop_first() else {
+                break;
+            };
+            self.stats.evictions += 1;
+            if let Some(block) = self.blocks.remove(&victim) {
+                if block.dirty {
+                    self.stats.writebacks += 1;
+                    evicted.push((victim, block.data));
+                }
+            }
+        }
+
+        self.lru.insert(tick, block_idx);
+        self.blocks
+            .insert(block_idx, CachedBlock { data, dirty, tick });
+        evicted
+    }
+
+    // Forget a block, handing it back when it still has to be written
+    pub fn remove(&mut self, block_idx: usize) -> Option<Vec<u8>> {
+        let block = self.blocks.remove(&block_idx)?;
+        self.lru.remove(&block.tick);
+        match block.dirty {
+            true => {
+                self.stats.writebacks += 1;
+                Some(block.data)
+            }
+            false => None,
+        }
+    }
+
+    // Every dirty block, which are clean from now on
+    pub fn take_dirty(&mut self) -> Vec<(usize, Vec<u8>)> {
+        let mut dirty: Vec<_> = self
+            .blocks
+            .iter_mut()
+            .filter(|(_, block)| block.dirty)
+            .map(|(block_idx, block)| {
+                block.dirty = false;
+                (*block_idx, block.data.clone())
+            })
+            .collect();
+        self.stats.writebacks += dirty.len() as u64;
+        // in device order, for the sake of spinning disks
+        dirty.sort_by_key(|(block_idx, _)| *block_idx);
+        dirty
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+}
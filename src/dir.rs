@@ -0,0 +1,106 @@
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+
+use crate::{
+    dir_entry, inode,
+    inode::FileType,
+    partition::{dentry_slot_inode, CfsPartition, DIR_ENTRY_SIZE},
+};
+
+// An entry yielded by ReadDir. `cookie` is the position right after it, handing it
+// to CfsPartition::read_dir_from resumes the listing from there.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DirItem {
+    pub name: OsString,
+    pub inode: u32,
+    pub file_type: Option<FileType>,
+    pub cookie: u64,
+}
+
+// A lazy iterator over the entries of a directory. Positions are dentry slots,
+// which never move while the entry is there (removing an entry only frees its
+// slot), so a cookie stays valid across changes to the directory. The dentry
+// block is only read on the first call to next().
+pub struct ReadDir<'a> {
+    partition: &'a mut CfsPartition,
+    inode_idx: usize,
+    block: Option<Vec<u8>>,
+    pos: u64,
+}
+
+impl<'a> ReadDir<'a> {
+    pub fn new(partition: &'a mut CfsPartition, inode_idx: usize, cookie: u64) -> Self {
+        Self {
+            partition,
+            inode_idx,
+            block: None,
+            pos: cookie,
+        }
+    }
+
+    // where the next call to next() picks up
+    pub fn cookie(&self) -> u64 {
+        self.pos
+    }
+
+    fn load_block(&mut self) -> std::io::Result<&[u8]> {
+        if self.block.is_none() {
+            let inode = self.partition.cfs.inode_list.get(self.inode_idx);
+            let mut block = vec![0; self.partition.cfs.super_block.blocksize as usize];
+            self.partition
+                .read_block(inode.blkaddr[0] as usize, &mut block)
+                .map_err(|err| std::io::Error::other(err.to_string()))?;
+            self.block = Some(block);
+        }
+        Ok(self.block.as_deref().unwrap_or_default())
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = std::io::Result<DirItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let nchildren = self.partition.cfs.inode_list.get(self.inode_idx).nchildren as u64;
+        let typed = self.partition.has_typed_dentries();
+        while self.pos < nchildren {
+            let slot = self.pos as usize;
+            self.pos += 1;
+
+            let block = match self.load_block() {
+                Ok(block) => block,
+                Err(err) => return Some(Err(err)),
+            };
+            if dentry_slot_inode(block, slot) == inode::BAD_INODE {
+                continue;
+            }
+            let chunk = &block[slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE];
+            let dentry = match dir_entry::DirEntry::from_bytes_typed(chunk, typed) {
+                Ok(dentry) => dentry,
+                Err(err) => return Some(Err(std::io::Error::other(err))),
+            };
+
+            // older images don't keep the type in the dentries
+            let file_type = match typed {
+                true => dentry.file_type(),
+                false => self
+                    .partition
+                    .cfs
+                    .inode_list
+                    .get(dentry.inode as usize)
+                    .file_type(),
+            };
+            let len = dentry
+                .name
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or(dentry.name.len());
+            return Some(Ok(DirItem {
+                name: OsString::from_vec(dentry.name[..len].to_vec()),
+                inode: dentry.inode,
+                file_type,
+                cookie: self.pos,
+            }));
+        }
+        None
+    }
+}
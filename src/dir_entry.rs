@@ -1,15 +1,90 @@
+#![allow(clippy::manual_div_ceil)]
+
 use deku::prelude::*;
 
-const MAX_NAME_LEN: usize = 60;
+use crate::inode::FileType;
+
+pub const MAX_NAME_LEN: usize = 60;
+
+// With superblock::FEATURE_DIR_FILE_TYPE the last byte of the name holds the file
+// type of the entry, so names are a byte shorter
+pub const MAX_TYPED_NAME_LEN: usize = MAX_NAME_LEN - 1;
+
+// File type codes, same values as ext2's
+pub const FT_UNKNOWN: u8 = 0;
+pub const FT_REG_FILE: u8 = 1;
+pub const FT_DIR: u8 = 2;
+pub const FT_CHRDEV: u8 = 3;
+pub const FT_BLKDEV: u8 = 4;
+pub const FT_FIFO: u8 = 5;
+pub const FT_SOCK: u8 = 6;
+pub const FT_SYMLINK: u8 = 7;
 
 #[derive(Debug, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
 pub struct DirEntry {
     pub name: [u8; MAX_NAME_LEN],
     pub inode: u32,
+    // one of the FT_* codes, see from_bytes_typed
+    #[deku(skip)]
+    pub file_type: u8,
 }
 
 impl DirEntry {
     pub fn new(name: [u8; MAX_NAME_LEN], inode: u32) -> Self {
-        Self { name, inode }
+        Self {
+            name,
+            inode,
+            file_type: FT_UNKNOWN,
+        }
+    }
+
+    pub fn with_file_type(mut self, file_type: Option<FileType>) -> Self {
+        self.file_type = file_type_code(file_type);
+        self
+    }
+
+    // Parse an on-disk entry, when `typed` its file type is split off the name
+    pub fn from_bytes_typed(bytes: &[u8], typed: bool) -> Result<Self, DekuError> {
+        let mut dentry = Self::try_from(bytes)?;
+        if typed {
+            dentry.file_type = dentry.name[MAX_TYPED_NAME_LEN];
+            dentry.name[MAX_TYPED_NAME_LEN] = 0;
+        }
+        Ok(dentry)
+    }
+
+    pub fn to_bytes_typed(&self, typed: bool) -> Result<Vec<u8>, DekuError> {
+        let mut bytes = self.to_bytes()?;
+        if typed {
+            bytes[MAX_TYPED_NAME_LEN] = self.file_type;
+        }
+        Ok(bytes)
+    }
+
+    pub fn file_type(&self) -> Option<FileType> {
+        match self.file_type {
+            FT_REG_FILE => Some(FileType::Regular),
+            FT_DIR => Some(FileType::Directory),
+            FT_CHRDEV => Some(FileType::CharDevice),
+            FT_BLKDEV => Some(FileType::BlockDevice),
+            FT_FIFO => Some(FileType::Fifo),
+            FT_SOCK => Some(FileType::Socket),
+            FT_SYMLINK => Some(FileType::Symlink),
+            _ => None,
+        }
+    }
+}
+
+pub fn file_type_code(file_type: Option<FileType>) -> u8 {
+    match file_type {
+        Some(FileType::Regular) => FT_REG_FILE,
+        Some(FileType::Directory) => FT_DIR,
+        Some(FileType::CharDevice) => FT_CHRDEV,
+        Some(FileType::BlockDevice) => FT_BLKDEV,
+        Some(FileType::Fifo) => FT_FIFO,
+        Some(FileType::Socket) => FT_SOCK,
+        Some(FileType::Symlink) => FT_SYMLINK,
+        None => FT_UNKNOWN,
     }
 }
@@ -0,0 +1,167 @@
+use std::io::{Read, Seek, SeekFrom};
+
+use crate::partition::CfsPartition;
+
+// fallocate modes, same values as in Linux
+pub const FALLOC_FL_KEEP_SIZE: u32 = 0x01;
+pub const FALLOC_FL_PUNCH_HOLE: u32 = 0x02;
+
+// A cursor over the contents of a file inode, holes read back as zeros
+pub struct FileHandle<'a> {
+    partition: &'a mut CfsPartition,
+    inode_idx: usize,
+    pos: u64,
+    // the file block the last read came from, to spot sequential reads
+    last_block: Option<u64>,
+}
+
+impl<'a> FileHandle<'a> {
+    pub fn new(partition: &'a mut CfsPartition, inode_idx: usize) -> Self {
+        Self {
+            partition,
+            inode_idx,
+            pos: 0,
+            last_block: None,
+        }
+    }
+
+    pub fn inode_idx(&self) -> usize {
+        self.inode_idx
+    }
+
+    pub fn size(&self) -> u64 {
+        self.partition
+            .cfs
+            .inode_list
+            .get(self.inode_idx)
+            .file_size()
+    }
+
+    // lseek(SEEK_DATA): move to the first byte of data at or after `offset`
+    pub fn seek_data(&mut self, offset: u64) -> std::io::Result<u64> {
+        let inode = self.partition.cfs.inode_list.get(self.inode_idx);
+        let block_size = self.partition.cfs.super_block.blocksize as u64;
+        let size = inode.file_size();
+        if offset >= size {
+            return Err(past_end());
+        }
+
+        // inline contents have no holes
+        if inode.is_inline() {
+            self.pos = offset;
+            return Ok(self.pos);
+        }
+
+        let data = (offset / block_size..size.div_ceil(block_size))
+            .find(|n| inode.data_block(*n as usize).is_some())
+            .ok_or_else(past_end)?;
+        self.pos = offset.max(data * block_size);
+        Ok(self.pos)
+    }
+
+    // lseek(SEEK_HOLE): move to the first byte of a hole at or after `offset`, the
+    // end of the file counts as one
+    pub fn seek_hole(&mut self, offset: u64) -> std::io::Result<u64> {
+        let inode = self.partition.cfs.inode_list.get(self.inode_idx);
+        let block_size = self.partition.cfs.super_block.blocksize as u64;
+        let size = inode.file_size();
+        if offset >= size {
+            return Err(past_end());
+        }
+
+        if inode.is_inline() {
+            self.pos = size;
+            return Ok(self.pos);
+        }
+
+        self.pos = (offset / block_size..size.div_ceil(block_size))
+            .find(|n| inode.data_block(*n as usize).is_none())
+            .map_or(size, |hole| offset.max(hole * block_size));
+        Ok(self.pos)
+    }
+}
+
+impl Read for FileHandle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let inode = self.partition.cfs.inode_list.get(self.inode_idx);
+        let block_size = self.partition.cfs.super_block.blocksize as u64;
+        let size = inode.file_size();
+        if self.pos >= size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if inode.is_inline() {
+            let data = inode.inline_data();
+            let len = buf.len().min(data.len() - self.pos as usize);
+            buf[..len].copy_from_slice(&data[self.pos as usize..self.pos as usize + len]);
+            self.pos += len as u64;
+            return Ok(len);
+        }
+
+        // never read across a block boundary, callers will just come back for more
+        let in_block = (self.pos % block_size) as usize;
+        let len = (buf.len() as u64)
+            .min(block_size - in_block as u64)
+            .min(size - self.pos) as usize;
+
+        // moving on to the next block, with a block cache the ones after it are
+        // loaded along
+        let n = self.pos / block_size;
+        if self.last_block.is_some_and(|last| last + 1 == n) {
+            self.partition
+                .read_ahead(&inode, n as usize + 1)
+                .map_err(to_io_error)?;
+        }
+        self.last_block = Some(n);
+
+        match inode.data_block(n as usize) {
+            Some(block_idx) => {
+                let mut block = vec![0; block_size as usize];
+                self.partition
+                    .read_block(block_idx, &mut block)
+                    .map_err(to_io_error)?;
+                buf[..len].copy_from_slice(&block[in_block..in_block + len]);
+            }
+            None => buf[..len].fill(0),
+        }
+
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl Seek for FileHandle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.size().checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.pos.checked_add_signed(offset),
+        };
+
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative offset",
+            )),
+        }
+    }
+}
+
+fn to_io_error(e: Box<dyn std::error::Error>) -> std::io::Error {
+    match e.downcast::<std::io::Error>() {
+        Ok(e) => *e,
+        Err(e) => std::io::Error::other(e.to_string()),
+    }
+}
+
+// what lseek reports as ENXIO
+fn past_end() -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidInput,
+        "no data or hole past the given offset",
+    )
+}
@@ -0,0 +1,49 @@
+#![allow(clippy::manual_div_ceil)]
+
+use deku::prelude::*;
+
+pub const GROUP_DESC_SIZE: u64 = 32;
+
+// The group descriptor table can only grow into the blocks reserved for it when
+// the image is created, leave room for this many times the initial groups
+pub const GDT_GROWTH_FACTOR: u64 = 1024;
+
+// With block groups enabled the layout becomes:
+// ┌────────────┬─────┬─────────┬─────┬─────────┐
+// │Super Block │ GDT │ Group 0 │ ... │ Group N │
+// └────────────┴─────┴─────────┴─────┴─────────┘
+// where every group is laid out as:
+// ┌──────────────┬──────────────┬─────────────┬──────────────┬─────┬──────────────┐
+// │ Block Bitmap │ Inode Bitmap │ Inode Table │ Data Block 0 │ ... │ Data Block N │
+// └──────────────┴──────────────┴─────────────┴──────────────┴─────┴──────────────┘
+// Data block numbers stay global, block `n` lives in group `n / blocks_per_group`,
+// and so does inode `n / inodes_per_group`.
+#[derive(Debug, Default, Copy, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct GroupDesc {
+    pub block_bitmap: u32,
+    pub inode_bitmap: u32,
+    pub inode_table: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub reserved: [u32; 3],
+}
+
+impl GroupDesc {
+    pub fn new(
+        block_bitmap: u32,
+        inode_bitmap: u32,
+        inode_table: u32,
+        free_blocks: u32,
+        free_inodes: u32,
+    ) -> Self {
+        Self {
+            block_bitmap,
+            inode_bitmap,
+            inode_table,
+            free_blocks,
+            free_inodes,
+            reserved: [0; 3],
+        }
+    }
+}
@@ -1,10 +1,135 @@
-use crate::superblock;
-use deku::prelude::*;
+#![allow(clippy::manual_div_ceil)]
+
+use crate::{superblock, utils::Timespec};
+use deku::{
+    bitvec::{BitSlice, BitVec, Msb0},
+    prelude::*,
+};
 
 pub const BAD_INODE: u32 = 0;
 pub const ROOT_INODE: u32 = 1;
 
+// blkaddr[0] is kept for dentries, the rest holds file data
+pub const MAX_FILE_BLOCKS: usize = 9;
+
+// Images created before the inode size was recorded in the super block only have
+// the fields up to blkaddr, newer ones have room for the extended fields as well
+pub const LEGACY_INODE_SIZE: u32 = 64;
+pub const INODE_SIZE: u32 = 128;
+// anything past INODE_SIZE is in-inode xattr space, see `xattr`
+pub const MAX_INODE_SIZE: u32 = 256;
+const XATTR_SPACE: usize = (MAX_INODE_SIZE - INODE_SIZE) as usize;
+
+// Inode flags
+// the file contents live in blkaddr instead of data blocks
+pub const INODE_INLINE_DATA: u32 = 1 << 0;
+
+// how much file data fits in blkaddr
+pub const MAX_INLINE_DATA: usize = 40;
+
+// File type bits of the mode, same values as in Linux
+pub const S_IFMT: u16 = 0o170000;
+pub const S_IFSOCK: u16 = 0o140000;
+pub const S_IFLNK: u16 = 0o120000;
+pub const S_IFREG: u16 = 0o100000;
+pub const S_IFBLK: u16 = 0o060000;
+pub const S_IFDIR: u16 = 0o040000;
+pub const S_IFCHR: u16 = 0o020000;
+pub const S_IFIFO: u16 = 0o010000;
+pub const S_ISUID: u16 = 0o4000;
+pub const S_ISGID: u16 = 0o2000;
+pub const S_ISVTX: u16 = 0o1000;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Fifo,
+    Socket,
+    Symlink,
+}
+
+impl FileType {
+    // Directories made before the type bits were set have none, nothing else does
+    pub fn from_mode(mode: u16) -> Option<Self> {
+        match mode & S_IFMT {
+            S_IFREG => Some(FileType::Regular),
+            S_IFDIR | 0 => Some(FileType::Directory),
+            S_IFCHR => Some(FileType::CharDevice),
+            S_IFBLK => Some(FileType::BlockDevice),
+            S_IFIFO => Some(FileType::Fifo),
+            S_IFSOCK => Some(FileType::Socket),
+            S_IFLNK => Some(FileType::Symlink),
+            _ => None,
+        }
+    }
+
+    pub fn mode_bits(&self) -> u16 {
+        match self {
+            FileType::Regular => S_IFREG,
+            FileType::Directory => S_IFDIR,
+            FileType::CharDevice => S_IFCHR,
+            FileType::BlockDevice => S_IFBLK,
+            FileType::Fifo => S_IFIFO,
+            FileType::Socket => S_IFSOCK,
+            FileType::Symlink => S_IFLNK,
+        }
+    }
+}
+
+// What `CfsPartition::stat` reports about an inode
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub inode: u32,
+    pub file_type: Option<FileType>,
+    // permission bits along with setuid, setgid and sticky
+    pub perm: u16,
+    pub nlink: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub size: u64,
+    // blocks used, data and xattr block included, in units of `block_size`
+    pub blocks: u64,
+    pub block_size: u64,
+    pub rdev: u32,
+    pub atime: Timespec,
+    pub mtime: Timespec,
+    pub ctime: Timespec,
+    pub crtime: Timespec,
+}
+
+// The changes `CfsPartition::setattr` makes, `None` leaves a field as it is.
+// `mode` only touches the permission bits, the file type can't change.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetAttr {
+    pub mode: Option<u16>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub atime: Option<Timespec>,
+    pub mtime: Option<Timespec>,
+    pub size: Option<u64>,
+}
+
+// Device numbers are stored like Linux's new_encode_dev does, 12 bits of major
+// and 20 bits of minor
+pub fn makedev(major: u32, minor: u32) -> u32 {
+    (minor & 0xff) | ((major & 0xfff) << 8) | ((minor & !0xff) << 12)
+}
+
+pub fn major(rdev: u32) -> u32 {
+    (rdev & 0xfff00) >> 8
+}
+
+pub fn minor(rdev: u32) -> u32 {
+    (rdev & 0xff) | ((rdev >> 12) & 0xfff00)
+}
+
+// Little endian on disk whatever the host, inline data is stored in blkaddr and
+// has to come back byte for byte
 #[derive(Debug, Copy, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
 pub struct Inode {
     pub mode: u16,
     pub nchildren: u16,
@@ -15,49 +140,278 @@ pub struct Inode {
     pub mtime: u32,
     pub ctime: u32,
     pub blkaddr: [u32; 10],
+    // Extended fields, only stored when the inode size allows it
+    pub flags: u32,
+    pub xattr_block: u32,
+    // upper halves of uid, gid and size, only used with FEATURE_LARGE_INODE
+    pub uid_high: u16,
+    pub gid_high: u16,
+    pub size_high: u32,
+    // blocks allocated to the inode, kept up to date by InodeList::set
+    pub blocks: u32,
+    // nanoseconds and upper halves of the seconds of the timestamps, along with
+    // the creation time. Legacy inodes only have the 32-bit seconds.
+    pub atime_nsec: u32,
+    pub mtime_nsec: u32,
+    pub ctime_nsec: u32,
+    pub atime_high: u32,
+    pub mtime_high: u32,
+    pub ctime_high: u32,
+    pub crtime: u32,
+    pub crtime_nsec: u32,
+    pub crtime_high: u32,
+    // device number of character and block device inodes, see makedev
+    pub rdev: u32,
+    pub reserved: [u8; 4],
+    // the rest of an inode record bigger than INODE_SIZE
+    #[deku(skip, default = "[0; XATTR_SPACE]")]
+    pub xattr_space: [u8; XATTR_SPACE],
 }
 
 impl Inode {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         mode: u16,
         nchildren: u16,
-        uid: u16,
-        gid: u16,
-        size: u32,
-        atime: u32,
-        mtime: u32,
-        ctime: u32,
+        uid: u32,
+        gid: u32,
+        size: u64,
+        atime: Timespec,
+        mtime: Timespec,
+        ctime: Timespec,
         blkaddr: [u32; 10],
     ) -> Self {
-        Self {
+        let mut inode = Self {
             mode,
             nchildren,
-            uid,
-            gid,
-            size,
-            atime,
-            mtime,
-            ctime,
+            uid: uid as u16,
+            gid: gid as u16,
+            size: size as u32,
+            atime: 0,
+            mtime: 0,
+            ctime: 0,
             blkaddr,
+            flags: 0,
+            xattr_block: 0,
+            uid_high: (uid >> 16) as u16,
+            gid_high: (gid >> 16) as u16,
+            size_high: (size >> 32) as u32,
+            blocks: 0,
+            atime_nsec: 0,
+            mtime_nsec: 0,
+            ctime_nsec: 0,
+            atime_high: 0,
+            mtime_high: 0,
+            ctime_high: 0,
+            crtime: 0,
+            crtime_nsec: 0,
+            crtime_high: 0,
+            rdev: 0,
+            reserved: [0; 4],
+            xattr_space: [0; XATTR_SPACE],
+        };
+        inode.set_atime(atime);
+        inode.set_mtime(mtime);
+        inode.set_ctime(ctime);
+        inode.set_crtime(ctime);
+        inode
+    }
+
+    // Parse an inode record of any size, the fields it doesn't have are zeroed
+    pub fn from_bytes_sized(bytes: &[u8]) -> Result<Self, DekuError> {
+        let mut buffer = bytes.to_vec();
+        buffer.resize(buffer.len().max(INODE_SIZE as usize), 0);
+        let mut inode = Self::try_from(&buffer[..INODE_SIZE as usize])?;
+
+        let extra = &buffer[INODE_SIZE as usize..];
+        let len = extra.len().min(XATTR_SPACE);
+        inode.xattr_space[..len].copy_from_slice(&extra[..len]);
+        Ok(inode)
+    }
+
+    // Serialize the inode into a record of `inode_size` bytes
+    pub fn to_bytes_sized(&self, inode_size: u32) -> Result<Vec<u8>, DekuError> {
+        let mut buffer = self.to_bytes()?;
+        let extra = (inode_size.saturating_sub(INODE_SIZE) as usize).min(XATTR_SPACE);
+        buffer.extend_from_slice(&self.xattr_space[..extra]);
+        buffer.resize(inode_size as usize, 0);
+        Ok(buffer)
+    }
+
+    pub fn uid(&self) -> u32 {
+        (self.uid_high as u32) << 16 | self.uid as u32
+    }
+
+    pub fn set_uid(&mut self, uid: u32) {
+        self.uid = uid as u16;
+        self.uid_high = (uid >> 16) as u16;
+    }
+
+    pub fn gid(&self) -> u32 {
+        (self.gid_high as u32) << 16 | self.gid as u32
+    }
+
+    pub fn set_gid(&mut self, gid: u32) {
+        self.gid = gid as u16;
+        self.gid_high = (gid >> 16) as u16;
+    }
+
+    pub fn file_size(&self) -> u64 {
+        (self.size_high as u64) << 32 | self.size as u64
+    }
+
+    pub fn set_file_size(&mut self, size: u64) {
+        self.size = size as u32;
+        self.size_high = (size >> 32) as u32;
+    }
+
+    pub fn atime(&self) -> Timespec {
+        join_time(self.atime, self.atime_high, self.atime_nsec)
+    }
+
+    pub fn set_atime(&mut self, time: Timespec) {
+        (self.atime, self.atime_high, self.atime_nsec) = split_time(time);
+    }
+
+    pub fn mtime(&self) -> Timespec {
+        join_time(self.mtime, self.mtime_high, self.mtime_nsec)
+    }
+
+    pub fn set_mtime(&mut self, time: Timespec) {
+        (self.mtime, self.mtime_high, self.mtime_nsec) = split_time(time);
+    }
+
+    pub fn ctime(&self) -> Timespec {
+        join_time(self.ctime, self.ctime_high, self.ctime_nsec)
+    }
+
+    pub fn set_ctime(&mut self, time: Timespec) {
+        (self.ctime, self.ctime_high, self.ctime_nsec) = split_time(time);
+    }
+
+    // birth time, when the inode was created
+    pub fn crtime(&self) -> Timespec {
+        join_time(self.crtime, self.crtime_high, self.crtime_nsec)
+    }
+
+    pub fn set_crtime(&mut self, time: Timespec) {
+        (self.crtime, self.crtime_high, self.crtime_nsec) = split_time(time);
+    }
+
+    pub fn file_type(&self) -> Option<FileType> {
+        FileType::from_mode(self.mode)
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.file_type() == Some(FileType::Directory)
+    }
+
+    pub fn is_inline(&self) -> bool {
+        self.flags & INODE_INLINE_DATA != 0
+    }
+
+    // the file contents when they are stored inline
+    pub fn inline_data(&self) -> Vec<u8> {
+        let mut data: Vec<u8> = self
+            .blkaddr
+            .iter()
+            .flat_map(|addr| addr.to_le_bytes())
+            .collect();
+        data.truncate(self.file_size() as usize);
+        data
+    }
+
+    pub fn set_inline_data(&mut self, data: &[u8]) {
+        let mut buffer = [0; MAX_INLINE_DATA];
+        buffer[..data.len()].copy_from_slice(data);
+        for (addr, bytes) in self.blkaddr.iter_mut().zip(buffer.chunks_exact(4)) {
+            *addr = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
         }
+        self.flags |= INODE_INLINE_DATA;
+        self.set_file_size(data.len() as u64);
+    }
+
+    // The block holding the `n`th block of a file's contents, blkaddr[0] is kept for
+    // dentries so the data starts at blkaddr[1]. A zero address is a hole, it has no
+    // block behind it and reads back as zeros.
+    pub fn data_block(&self, n: usize) -> Option<usize> {
+        if self.is_inline() {
+            return None;
+        }
+        match self.blkaddr.get(n + 1) {
+            Some(0) | None => None,
+            Some(addr) => Some(*addr as usize),
+        }
+    }
+
+    // the blocks this inode points to, including its xattr block. Block 0 belongs to
+    // the root directory so a zero address means there's nothing there
+    pub fn block_addrs(&self) -> impl Iterator<Item = &u32> {
+        let count = if self.is_inline() {
+            0
+        } else {
+            self.blkaddr.len()
+        };
+        self.blkaddr
+            .iter()
+            .take(count)
+            .chain(std::iter::once(&self.xattr_block))
+            .filter(|addr| **addr != 0)
+    }
+
+    pub fn block_addrs_mut(&mut self) -> impl Iterator<Item = &mut u32> {
+        let count = if self.is_inline() {
+            0
+        } else {
+            self.blkaddr.len()
+        };
+        self.blkaddr
+            .iter_mut()
+            .take(count)
+            .chain(std::iter::once(&mut self.xattr_block))
+            .filter(|addr| **addr != 0)
     }
 
     #[inline(always)]
     pub fn inodes_per_block(&self, block_size: u64) -> u64 {
-        block_size / std::mem::size_of::<Self>() as u64
+        block_size / INODE_SIZE as u64
     }
 }
 
+// The low 32 bits of the seconds are where legacy inodes kept them, so legacy
+// timestamps read back as unsigned seconds since the epoch
+fn join_time(low: u32, high: u32, nsec: u32) -> Timespec {
+    Timespec::new(((high as u64) << 32 | low as u64) as i64, nsec)
+}
+
+fn split_time(time: Timespec) -> (u32, u32, u32) {
+    let sec = time.sec as u64;
+    (sec as u32, (sec >> 32) as u32, time.nsec)
+}
+
 impl Default for Inode {
     fn default() -> Self {
-        Self::new(0, 0, 0, 0, 0, 0, 0, 0, [0; 10])
+        Self::new(
+            0,
+            0,
+            0,
+            0,
+            0,
+            Timespec::default(),
+            Timespec::default(),
+            Timespec::default(),
+            [0; 10],
+        )
     }
 }
 
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
 #[deku(ctx = "super_block: superblock::SuperBlock")]
 pub struct InodeList {
-    #[deku(count = "super_block.ninodes")]
+    #[deku(
+        reader = "InodeList::read_inodes(deku::rest, &super_block)",
+        writer = "InodeList::write_inodes(deku::output, inodes, &super_block)"
+    )]
     inodes: Vec<Inode>,
 }
 
@@ -69,9 +423,9 @@ impl InodeList {
             0,
             0,
             0,
-            0,
-            0,
-            0,
+            Timespec::default(),
+            Timespec::default(),
+            Timespec::default(),
             [0, 0, 0, 0, 0, 0, 0, 0, 0, 0],
         );
 
@@ -83,11 +437,56 @@ impl InodeList {
         Self { inodes }
     }
 
+    // the on-disk records are `inode_size` bytes long, not size_of::<Inode>()
+    fn read_inodes<'a>(
+        rest: &'a BitSlice<u8, Msb0>,
+        super_block: &superblock::SuperBlock,
+    ) -> Result<(&'a BitSlice<u8, Msb0>, Vec<Inode>), DekuError> {
+        let inode_size = super_block.inode_size() as usize;
+        let bits = super_block.ninodes as usize * inode_size * 8;
+        if rest.len() < bits {
+            return Err(DekuError::Incomplete(deku::error::NeedSize::new(bits)));
+        }
+
+        let (inodes, rest) = rest.split_at(bits);
+        let inodes = inodes
+            .to_bitvec()
+            .into_vec()
+            .chunks_exact(inode_size)
+            .map(Inode::from_bytes_sized)
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((rest, inodes))
+    }
+
+    fn write_inodes(
+        output: &mut BitVec<u8, Msb0>,
+        inodes: &[Inode],
+        super_block: &superblock::SuperBlock,
+    ) -> Result<(), DekuError> {
+        for inode in inodes {
+            output.extend_from_raw_slice(&inode.to_bytes_sized(super_block.inode_size())?);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_inodes(inodes: Vec<Inode>) -> Self {
+        Self { inodes }
+    }
+
+    pub(crate) fn resize(&mut self, size: usize) {
+        self.inodes.resize(size, Inode::default());
+    }
+
+    pub fn inodes(&self) -> &[Inode] {
+        &self.inodes
+    }
+
     pub fn get(&self, index: usize) -> Inode {
         self.inodes[index]
     }
 
-    pub fn set(&mut self, index: usize, inode: Inode) {
+    pub fn set(&mut self, index: usize, mut inode: Inode) {
+        inode.blocks = inode.block_addrs().count() as u32;
         self.inodes[index] = inode;
     }
 
@@ -1,11 +1,24 @@
+pub mod acl;
+#[cfg(feature = "tokio")]
+pub mod aio;
 pub mod bitmap;
+pub mod cache;
+pub mod dir;
 pub mod dir_entry;
+pub mod file;
+pub mod group;
 pub mod inode;
 pub mod partition;
+pub mod shared;
+pub mod snapshot;
 pub mod superblock;
+pub mod transaction;
 pub mod utils;
+pub mod xattr;
 
-use deku::prelude::*;
+use std::collections::BTreeMap;
+
+use bitmap::Bitmap;
 
 pub const MAGIC: u32 = 0x0CF5B10C;
 pub const DEFAULT_BLOCK_SIZE: usize = 4096;
@@ -16,18 +29,60 @@ pub fn init_library_logger() {
     env_logger::builder().format_timestamp(None).init();
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StatFs {
+    pub block_size: u64,
+    pub total_blocks: u64,
+    pub free_blocks: u64,
+    pub available_blocks: u64,
+    pub total_inodes: u64,
+    pub free_inodes: u64,
+    pub max_name_len: u64,
+}
+
 // ┌────────────┬─────────────────────────┬─────────────────────────┬────────────┬──────────────┬─────┬──────────────┐
 // │Super Block │ Block Allocation Bitmap │ Inode Allocation Bitmap │ Inode List │ Data Block 0 │ ... │ Data Block N │
 // └────────────┴─────────────────────────┴─────────────────────────┴────────────┴──────────────┴─────┴──────────────┘
-#[derive(Debug, PartialEq, DekuRead, DekuWrite)]
-pub struct Cfs {
-    super_block: superblock::SuperBlock,
-    #[deku(ctx = "super_block.clone()")]
-    bam: bitmap::Bam,
-    #[deku(ctx = "super_block.clone()")]
-    iam: bitmap::Iam,
-    #[deku(ctx = "super_block.clone()")]
-    inode_list: inode::InodeList,
+// This is the flat layout, see `group` for the block group one. Only the flat
+// layout goes through deku as a whole, block groups are (de)serialized piecewise
+// by the partition.
+//
+// What deku derives takes its lint levels from the module around the type, and
+// DekuRead rounds bits up to bytes by hand, hence the module of its own.
+pub use layout::Cfs;
+
+#[allow(clippy::manual_div_ceil)]
+mod layout {
+    use std::collections::BTreeMap;
+
+    use deku::prelude::*;
+
+    use crate::{bitmap, group, inode, superblock};
+
+    #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+    pub struct Cfs {
+        pub(crate) super_block: superblock::SuperBlock,
+        #[deku(ctx = "super_block.clone()")]
+        pub(crate) bam: bitmap::Bam,
+        #[deku(ctx = "super_block.clone()")]
+        pub(crate) iam: bitmap::Iam,
+        #[deku(ctx = "super_block.clone()")]
+        pub(crate) inode_list: inode::InodeList,
+        // where the last block allocation ended, the next search without a goal
+        // starts from here
+        #[deku(skip)]
+        pub(crate) block_cursor: usize,
+        #[deku(skip)]
+        pub(crate) group_descs: Vec<group::GroupDesc>,
+        // references to blocks shared by snapshots or clones on top of the first
+        // one, a shared block is only freed along with its last reference
+        #[deku(skip)]
+        pub(crate) refcounts: BTreeMap<usize, u32>,
+        // the refcounts as the chain on the device has them, it's only written
+        // again when they differ
+        #[deku(skip)]
+        pub(crate) stored_refcounts: BTreeMap<usize, u32>,
+    }
 }
 
 impl Cfs {
@@ -42,15 +97,44 @@ impl Cfs {
             bam,
             iam,
             inode_list,
+            block_cursor: 0,
+            group_descs: Vec::new(),
+            refcounts: BTreeMap::new(),
+            stored_refcounts: BTreeMap::new(),
         }
     }
 
+    // read-only, the partition keeps the super block in sync with the rest
+    pub fn super_block(&self) -> &superblock::SuperBlock {
+        &self.super_block
+    }
+
+    // read-only as well, changes go through alloc_* and free_*
+    pub fn bam(&self) -> &bitmap::Bam {
+        &self.bam
+    }
+
+    pub fn iam(&self) -> &bitmap::Iam {
+        &self.iam
+    }
+
+    pub fn inode_list(&self) -> &inode::InodeList {
+        &self.inode_list
+    }
+
+    pub fn has_block_groups(&self) -> bool {
+        self.super_block
+            .has_feature(superblock::FEATURE_BLOCK_GROUPS)
+    }
+
     pub fn super_block_offset(&self) -> u64 {
         0
     }
 
+    // With block groups these describe group 0, the bam/iam/inode block counts in
+    // the super block are per group in that case
     pub fn bam_offset(&self) -> u64 {
-        self.super_block.blocksize as u64 * RESERVED_BLOCKS
+        self.super_block.blocksize as u64 * (RESERVED_BLOCKS + self.super_block.gdt_blocks as u64)
     }
 
     pub fn iam_offset(&self) -> u64 {
@@ -65,4 +149,292 @@ impl Cfs {
         self.inode_list_offset()
             + (self.super_block.inode_blocks as u64 * self.super_block.blocksize as u64)
     }
+
+    // first device block of the data area
+    pub fn data_start_block(&self) -> u64 {
+        self.data_blocks_offset() / self.super_block.blocksize as u64
+    }
+
+    // blocks taken by the bitmaps and the inode table of a single group
+    pub fn group_metadata_blocks(&self) -> u64 {
+        self.super_block.bam_blocks as u64
+            + self.super_block.iam_blocks as u64
+            + self.super_block.inode_blocks as u64
+    }
+
+    // blocks covered by a whole group, metadata included
+    pub fn group_span(&self) -> u64 {
+        self.group_metadata_blocks() + self.super_block.blocks_per_group as u64
+    }
+
+    // first device block of a group, i.e. its block bitmap
+    pub fn group_start_block(&self, group: usize) -> u64 {
+        RESERVED_BLOCKS + self.super_block.gdt_blocks as u64 + group as u64 * self.group_span()
+    }
+
+    // number of data blocks that actually fit in the device, the BAM is rounded up
+    // to whole blocks so it always has more bits than this
+    pub fn data_blocks(&self) -> u64 {
+        if !self.has_block_groups() {
+            return (self.super_block.nblocks as u64).saturating_sub(self.data_start_block());
+        }
+
+        // only the last group can be cut short
+        let groups = self.super_block.groups as u64;
+        let metadata = RESERVED_BLOCKS
+            + self.super_block.gdt_blocks as u64
+            + groups * self.group_metadata_blocks();
+        (self.super_block.nblocks as u64)
+            .saturating_sub(metadata)
+            .min(groups * self.super_block.blocks_per_group as u64)
+    }
+
+    // byte offset of a data block in the device
+    pub fn block_offset(&self, block_idx: usize) -> u64 {
+        let block_size = self.super_block.blocksize as u64;
+        if !self.has_block_groups() {
+            return self.data_blocks_offset() + block_idx as u64 * block_size;
+        }
+
+        let blocks_per_group = self.super_block.blocks_per_group as usize;
+        let group = block_idx / blocks_per_group;
+        let block = self.group_start_block(group)
+            + self.group_metadata_blocks()
+            + (block_idx % blocks_per_group) as u64;
+        block * block_size
+    }
+
+    // the data block living at a given device block, if that is a data block at all
+    pub fn data_index_of_block(&self, block: u64) -> Option<usize> {
+        let block_idx = if !self.has_block_groups() {
+            block.checked_sub(self.data_start_block())?
+        } else {
+            let block = block.checked_sub(self.group_start_block(0))?;
+            let group = block / self.group_span();
+            let offset = (block % self.group_span()).checked_sub(self.group_metadata_blocks())?;
+            group * self.super_block.blocks_per_group as u64 + offset
+        };
+
+        (block_idx < self.data_blocks()).then_some(block_idx as usize)
+    }
+
+    // whether a device block belongs to the super block, GDT, bitmaps or inode tables
+    pub fn is_metadata_block(&self, block: u64) -> bool {
+        if block < self.group_start_block(0) {
+            return true;
+        }
+        if !self.has_block_groups() {
+            return block < self.data_start_block();
+        }
+
+        let block = block - self.group_start_block(0);
+        block / self.group_span() < self.super_block.groups as u64
+            && block % self.group_span() < self.group_metadata_blocks()
+    }
+
+    pub fn group_of_inode(&self, inode_idx: usize) -> usize {
+        match self.has_block_groups() {
+            true => inode_idx / self.super_block.inodes_per_group as usize,
+            false => 0,
+        }
+    }
+
+    pub fn group_of_block(&self, block_idx: usize) -> usize {
+        match self.has_block_groups() {
+            true => block_idx / self.super_block.blocks_per_group as usize,
+            false => 0,
+        }
+    }
+
+    // where to start looking for data blocks of a new inode, next to its parent
+    // directory when they share a group, otherwise at the start of its own group
+    pub fn block_goal(&self, parent_inode_idx: usize, inode_idx: usize) -> usize {
+        let group = self.group_of_inode(inode_idx);
+        let parent_block = self.inode_list.get(parent_inode_idx).blkaddr[0] as usize;
+        if self.group_of_block(parent_block) == group {
+            parent_block
+        } else {
+            group * self.super_block.blocks_per_group as usize
+        }
+    }
+
+    pub fn group_descs(&self) -> &[group::GroupDesc] {
+        &self.group_descs
+    }
+
+    // Refresh the group descriptors from the bitmaps, they are only informative so
+    // they just need to be right whenever they hit the disk
+    pub fn update_group_descs(&mut self) {
+        let groups = self.super_block.groups as usize;
+        let blocks_per_group = self.super_block.blocks_per_group as usize;
+        let inodes_per_group = self.super_block.inodes_per_group as usize;
+        let data_blocks = self.data_blocks() as usize;
+
+        let mut group_descs = Vec::with_capacity(groups);
+        for group in 0..groups {
+            let start = self.group_start_block(group) as u32;
+            let blocks = (group * blocks_per_group
+                ..data_blocks.min((group + 1) * blocks_per_group))
+                .filter(|block_idx| !self.bam.get(*block_idx))
+                .count();
+            let inodes = (group * inodes_per_group..(group + 1) * inodes_per_group)
+                .filter(|inode_idx| !self.iam.get(*inode_idx))
+                .count();
+            group_descs.push(group::GroupDesc::new(
+                start,
+                start + self.super_block.bam_blocks,
+                start + self.super_block.bam_blocks + self.super_block.iam_blocks,
+                blocks as u32,
+                inodes as u32,
+            ));
+        }
+        self.group_descs = group_descs;
+    }
+
+    // The bitmaps are rounded up, so the bits past the end of the device and of the
+    // inode list must never be handed out, and neither must the data blocks
+    // holding the super block backups
+    pub fn reserve_unusable(&mut self) {
+        let data_blocks = self.data_blocks() as usize;
+        for block_idx in data_blocks..self.bam.data.len() * 8 {
+            self.bam.set(block_idx);
+        }
+        for inode_idx in self.super_block.ninodes as usize..self.iam.data.len() * 8 {
+            self.iam.set(inode_idx);
+        }
+        for block_idx in self.backup_blocks() {
+            self.bam.set(block_idx);
+        }
+        self.update_free_counts();
+    }
+
+    // The data blocks holding the super block backups. Locations that fall on
+    // metadata get no backup, the partition refuses layouts left with none.
+    pub fn backup_blocks(&self) -> Vec<usize> {
+        superblock::backup_locations(self.super_block.nblocks as u64)
+            .into_iter()
+            .filter_map(|location| self.data_index_of_block(location))
+            .collect()
+    }
+
+    // Recount the free blocks and inodes from the bitmaps, returns whether the
+    // counts were off
+    pub fn update_free_counts(&mut self) -> bool {
+        let data_blocks = self.data_blocks() as usize;
+        let ninodes = self.super_block.ninodes as usize;
+        let free_blocks = self.bam.count_free(data_blocks) as u32;
+        let free_inodes = self.iam.count_free(ninodes) as u32;
+        let changed = free_blocks != self.super_block.free_blocks
+            || free_inodes != self.super_block.free_inodes;
+        self.super_block.free_blocks = free_blocks;
+        self.super_block.free_inodes = free_inodes;
+        changed
+    }
+
+    // Every allocation and free must go through these so the super block
+    // counters stay in sync with the bitmaps
+    pub fn alloc_block(&mut self) -> Option<usize> {
+        self.alloc_blocks(1, None)
+    }
+
+    // Allocate `count` contiguous blocks, as close after `goal` as possible (or
+    // after the last allocation when there is no goal), returning the first one
+    pub fn alloc_blocks(&mut self, count: usize, goal: Option<usize>) -> Option<usize> {
+        let data_blocks = self.data_blocks() as usize;
+        let goal = goal.unwrap_or(self.block_cursor).min(data_blocks);
+        let block_idx = self
+            .bam
+            .find_free_run(count, goal, data_blocks)
+            .or_else(|| {
+                self.bam
+                    .find_free_run(count, 0, (goal + count).saturating_sub(1))
+            })?;
+
+        self.bam.set_run(block_idx, count);
+        self.super_block.free_blocks = self.super_block.free_blocks.saturating_sub(count as u32);
+        self.block_cursor = block_idx + count;
+        Some(block_idx)
+    }
+
+    // Drop a reference to a block, see share_block
+    pub fn free_block(&mut self, block_idx: usize) {
+        if let Some(extra) = self.refcounts.get_mut(&block_idx) {
+            *extra -= 1;
+            if *extra == 0 {
+                self.refcounts.remove(&block_idx);
+            }
+            return;
+        }
+        if self.bam.get(block_idx) {
+            self.bam.clear(block_idx);
+            self.super_block.free_blocks += 1;
+        }
+    }
+
+    // Add a reference to an allocated block, which then takes as many free_block
+    // calls to be freed. Shared blocks are copied before being changed.
+    pub fn share_block(&mut self, block_idx: usize) {
+        *self.refcounts.entry(block_idx).or_insert(0) += 1;
+    }
+
+    pub fn is_shared(&self, block_idx: usize) -> bool {
+        self.refcounts.contains_key(&block_idx)
+    }
+
+    // how many inodes, live or in snapshots, point to a block
+    pub fn block_refcount(&mut self, block_idx: usize) -> u32 {
+        match self.bam.get(block_idx) {
+            true => 1 + self.refcounts.get(&block_idx).copied().unwrap_or(0),
+            false => 0,
+        }
+    }
+
+    pub fn alloc_inode(&mut self) -> Option<usize> {
+        self.alloc_inode_near(0)
+    }
+
+    // Allocate an inode, preferably in the same group as `parent_inode_idx`
+    pub fn alloc_inode_near(&mut self, parent_inode_idx: usize) -> Option<usize> {
+        let ninodes = self.super_block.ninodes as usize;
+        let goal = match self.has_block_groups() {
+            true => {
+                self.group_of_inode(parent_inode_idx) * self.super_block.inodes_per_group as usize
+            }
+            false => 0,
+        };
+        let inode_idx = self
+            .iam
+            .find_free_run(1, goal, ninodes)
+            .or_else(|| self.iam.find_free_run(1, 0, goal))?;
+        self.iam.set(inode_idx);
+        self.super_block.free_inodes = self.super_block.free_inodes.saturating_sub(1);
+        Some(inode_idx)
+    }
+
+    pub fn free_inode(&mut self, inode_idx: usize) {
+        if self.iam.get(inode_idx) {
+            self.iam.clear(inode_idx);
+            self.inode_list.clear(inode_idx);
+            self.super_block.free_inodes += 1;
+        }
+    }
+
+    pub fn statfs(&self) -> StatFs {
+        StatFs {
+            block_size: self.super_block.blocksize as u64,
+            total_blocks: self.super_block.nblocks as u64,
+            free_blocks: self.super_block.free_blocks as u64,
+            // nothing is reserved for privileged users (yet)
+            available_blocks: self.super_block.free_blocks as u64,
+            total_inodes: self.super_block.ninodes as u64,
+            free_inodes: self.super_block.free_inodes as u64,
+            max_name_len: match self
+                .super_block
+                .has_feature(superblock::FEATURE_DIR_FILE_TYPE)
+            {
+                true => dir_entry::MAX_TYPED_NAME_LEN as u64,
+                false => dir_entry::MAX_NAME_LEN as u64,
+            },
+        }
+    }
 }
@@ -1,21 +1,33 @@
 use std::{
     io::{Read, Seek, Write},
     os::unix::prelude::{MetadataExt, PermissionsExt},
-    time::UNIX_EPOCH,
 };
 
 use deku::prelude::*;
 
 use crate::{
+    acl,
     bitmap::{self, Bitmap},
-    dir_entry, inode, superblock,
+    cache, dir, dir_entry, file, group, inode, snapshot, superblock,
+    transaction::{Journal, Transaction},
     utils::{self, bits_per_block},
-    Cfs, DEFAULT_BLOCK_SIZE, MAGIC,
+    xattr, Cfs, StatFs, DEFAULT_BLOCK_SIZE, MAGIC, RESERVED_BLOCKS,
 };
 
 pub struct CfsPartition {
     pub blk_dev: std::fs::File,
     pub cfs: Cfs,
+    // see open_read_only
+    read_only: bool,
+    // the image is marked dirty on the device and has to be marked clean on close
+    mounted: bool,
+    // see set_block_cache
+    cache: Option<cache::BlockCache>,
+    // owned by a SharedPartition, which reads and writes file contents around
+    // the partition, so a block cache would go stale
+    pub(crate) shared: bool,
+    // one per open transaction, innermost last
+    journals: Vec<Journal>,
 }
 
 impl CfsPartition {
@@ -23,20 +35,37 @@ impl CfsPartition {
         blk_dev: std::fs::File,
         block_size: u64,
     ) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::new_with_inode_size(blk_dev, block_size, inode::INODE_SIZE)
+    }
+
+    // Same as `new`, inodes bigger than INODE_SIZE keep the extra room for xattrs
+    pub fn new_with_inode_size(
+        blk_dev: std::fs::File,
+        block_size: u64,
+        inode_size: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if !inode_size.is_power_of_two()
+            || !(inode::INODE_SIZE..=inode::MAX_INODE_SIZE).contains(&inode_size)
+        {
+            return Err(Box::new(std::io::Error::other("Invalid inode size")));
+        }
+
         let blk_dev_metadata = blk_dev.metadata()?;
         let size = blk_dev_metadata.len();
         let nblocks = size / block_size;
         let bits_per_block = bits_per_block(block_size);
 
-        let bam_blocks = (nblocks + bits_per_block - 1) / bits_per_block;
+        let bam_blocks = nblocks.div_ceil(bits_per_block);
 
-        let inode_size = std::mem::size_of::<inode::Inode>() as u64;
-        let inodes_per_block = block_size / inode_size;
+        // the inode ratio was set when inodes were LEGACY_INODE_SIZE bytes, keep it so
+        // bigger inodes don't mean fewer of them
+        let inode_size = inode_size as u64;
+        let inodes_per_block = block_size / inode::LEGACY_INODE_SIZE as u64;
         let ninodes = (nblocks / 4096) * inodes_per_block;
 
-        let iam_blocks = (ninodes + bits_per_block - 1) / bits_per_block;
+        let iam_blocks = ninodes.div_ceil(bits_per_block);
 
-        let inode_list_blocks = (ninodes * inode_size + block_size - 1) / block_size;
+        let inode_list_blocks = (ninodes * inode_size).div_ceil(block_size);
 
         log::debug!("Partition information:");
         log::debug!("inodes_per_block: {inodes_per_block}");
@@ -49,7 +78,7 @@ impl CfsPartition {
         log::debug!("iam_blocks: {iam_blocks}");
 
         // Super block
-        let super_block = superblock::SuperBlock::new(
+        let mut super_block = superblock::SuperBlock::new(
             MAGIC,
             block_size as u32,
             bam_blocks as u32,
@@ -58,6 +87,8 @@ impl CfsPartition {
             nblocks as u32,
             ninodes as u32,
         );
+        super_block.inode_size = inode_size as u32;
+        super_block.features |= superblock::FEATURE_LARGE_INODE | superblock::FEATURE_DIR_FILE_TYPE;
 
         // BAM - Allocate a bitmap with the first block occupied by the root directory
         // and all other blocks free
@@ -71,10 +102,7 @@ impl CfsPartition {
         iam.set(0);
         iam.set(1);
 
-        log::debug!(
-            "ninodes * std::mem::size_of::<inode::Inode>() = {}",
-            ninodes * std::mem::size_of::<inode::Inode>() as u64
-        );
+        log::debug!("ninodes * inode_size = {}", ninodes * inode_size);
 
         // Inode List - Allocate the first inode for the root directory
         let inode_list = inode::InodeList::new(ninodes as usize);
@@ -84,14 +112,218 @@ impl CfsPartition {
         log::debug!("total_blocks: {total_blocks}");
 
         // Create the CFS
-        let cfs = Cfs::new(super_block, bam, iam, inode_list);
+        let mut cfs = Cfs::new(super_block, bam, iam, inode_list);
+        check_backup_blocks(&cfs)?;
+        cfs.reserve_unusable();
+        log::debug!("free_blocks: {}", cfs.super_block.free_blocks);
+        log::debug!("free_inodes: {}", cfs.super_block.free_inodes);
 
         log::debug!("bam is located @ {}", cfs.bam_offset());
         log::debug!("iam is located @ {}", cfs.iam_offset());
         log::debug!("inode_list is located @ {}", cfs.inode_list_offset());
         log::debug!("data_blocks_offset: {}\n", cfs.data_blocks_offset());
 
-        Ok(Self { blk_dev, cfs })
+        Ok(Self {
+            blk_dev,
+            cfs,
+            read_only: false,
+            mounted: false,
+            cache: None,
+            shared: false,
+            journals: Vec::new(),
+        })
+    }
+
+    // Same as `new`, but the data blocks are split in groups of `blocks_per_group`
+    // blocks, each one with its own bitmaps and slice of the inode table
+    pub fn new_with_block_groups(
+        blk_dev: std::fs::File,
+        block_size: u64,
+        blocks_per_group: u64,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let size = blk_dev.metadata()?.len();
+        let nblocks = size / block_size;
+        let bits_per_block = bits_per_block(block_size);
+
+        // a group bitmap must fit in one block and be made of whole bytes
+        if blocks_per_group == 0
+            || !blocks_per_group.is_multiple_of(8)
+            || blocks_per_group > bits_per_block
+        {
+            return Err(Box::new(std::io::Error::other(
+                "Invalid number of blocks per group",
+            )));
+        }
+
+        // same inode ratio as the flat layout, but at least a block of inodes per group
+        let inode_size = inode::INODE_SIZE as u64;
+        let inodes_per_block = block_size / inode::LEGACY_INODE_SIZE as u64;
+        let inodes_per_group = (blocks_per_group / 4096).max(1) * inodes_per_block;
+        let inode_table_blocks = (inodes_per_group * inode_size).div_ceil(block_size);
+        let group_span = 2 + inode_table_blocks + blocks_per_group;
+
+        // leave room in the group descriptor table for the image to grow
+        let max_groups = (nblocks.div_ceil(group_span) * group::GDT_GROWTH_FACTOR)
+            .min(u32::MAX as u64 / group_span + 1);
+        let gdt_blocks = (max_groups * group::GROUP_DESC_SIZE).div_ceil(block_size);
+
+        let groups = block_group_count(nblocks, gdt_blocks, group_span, blocks_per_group);
+        if groups == 0 {
+            return Err(Box::new(std::io::Error::other("Device too small")));
+        }
+        let ninodes = groups * inodes_per_group;
+
+        log::debug!("Partition information:");
+        log::debug!("block_size: {block_size}");
+        log::debug!("nblocks: {nblocks}");
+        log::debug!("groups: {groups}");
+        log::debug!("gdt_blocks: {gdt_blocks}");
+        log::debug!("blocks_per_group: {blocks_per_group}");
+        log::debug!("inodes_per_group: {inodes_per_group}");
+        log::debug!("inode_table_blocks: {inode_table_blocks}");
+
+        // Super block, the bam/iam/inode block counts are per group
+        let mut super_block = superblock::SuperBlock::new(
+            MAGIC,
+            block_size as u32,
+            1,
+            1,
+            inode_table_blocks as u32,
+            nblocks as u32,
+            ninodes as u32,
+        );
+        super_block.inode_size = inode::INODE_SIZE;
+        super_block.features |= superblock::FEATURE_BLOCK_GROUPS
+            | superblock::FEATURE_LARGE_INODE
+            | superblock::FEATURE_DIR_FILE_TYPE;
+        super_block.groups = groups as u32;
+        super_block.blocks_per_group = blocks_per_group as u32;
+        super_block.inodes_per_group = inodes_per_group as u32;
+        super_block.gdt_blocks = gdt_blocks as u32;
+
+        // The bitmaps are kept whole in memory, each group gets its slice on disk
+        let mut bam = bitmap::Bam::new((groups * blocks_per_group / 8) as usize);
+        bam.set(0);
+        let mut iam = bitmap::Iam::new((ninodes / 8) as usize);
+        iam.set(0);
+        iam.set(1);
+        let inode_list = inode::InodeList::new(ninodes as usize);
+
+        let mut cfs = Cfs::new(super_block, bam, iam, inode_list);
+        check_backup_blocks(&cfs)?;
+        cfs.reserve_unusable();
+        cfs.update_group_descs();
+        log::debug!("free_blocks: {}", cfs.super_block.free_blocks);
+        log::debug!("free_inodes: {}", cfs.super_block.free_inodes);
+
+        Ok(Self {
+            blk_dev,
+            cfs,
+            read_only: false,
+            mounted: false,
+            cache: None,
+            shared: false,
+            journals: Vec::new(),
+        })
+    }
+
+    // Open an existing image without ever writing to it, even through Drop. Every
+    // operation that would change the image fails with ReadOnlyFilesystem instead,
+    // so `blk_dev` may be opened read-only.
+    pub fn open_read_only(blk_dev: std::fs::File) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut partition = load(blk_dev)?;
+        partition.read_only = true;
+        Ok(partition)
+    }
+
+    // Open an existing image for writing once `policy` accepts its super block,
+    // which is how tools demand a check after an unclean close or too many opens
+    // (see superblock::CheckPolicy). The image stays marked dirty until it's
+    // closed, TryFrom is the same with a policy that accepts anything.
+    pub fn open_with_policy<F>(
+        blk_dev: std::fs::File,
+        policy: F,
+    ) -> Result<Self, Box<dyn std::error::Error>>
+    where
+        F: FnOnce(&superblock::SuperBlock) -> Result<(), std::io::Error>,
+    {
+        let mut partition = load(blk_dev)?;
+        policy(&partition.cfs.super_block)?;
+
+        let super_block = &mut partition.cfs.super_block;
+        super_block.mount_count = super_block.mount_count.saturating_add(1);
+        super_block.mount_time = utils::unix_time();
+        partition.mark_dirty()?;
+        Ok(partition)
+    }
+
+    // Mark the image clean and flush it, like dropping the partition does but
+    // with the errors reported
+    pub fn close(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.unmount()?;
+        self.blk_dev.sync_all()?;
+        Ok(())
+    }
+
+    // Record that something is wrong with the image, it stays that way until
+    // mark_checked
+    pub fn mark_errors(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.cfs.super_block.state = superblock::STATE_ERRORS;
+        self.write_super_block()
+    }
+
+    // Meant for checkers once the image is known to be consistent: clears the
+    // errors and starts counting opens again. An image that's open stays dirty
+    // until it's closed.
+    pub fn mark_checked(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let super_block = &mut self.cfs.super_block;
+        super_block.state = match self.mounted {
+            true => superblock::STATE_DIRTY,
+            false => superblock::STATE_CLEAN,
+        };
+        super_block.mount_count = 0;
+        super_block.check_time = utils::unix_time();
+        self.write_super_block()
+    }
+
+    // the image is dirty on the device as long as the partition is open
+    fn mark_dirty(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.cfs.super_block.state != superblock::STATE_ERRORS {
+            self.cfs.super_block.state = superblock::STATE_DIRTY;
+        }
+        self.mounted = true;
+        self.write_super_block()
+    }
+
+    fn unmount(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.flush_cache()?;
+        if !self.mounted {
+            return Ok(());
+        }
+        if self.cfs.super_block.state == superblock::STATE_DIRTY {
+            self.cfs.super_block.state = superblock::STATE_CLEAN;
+        }
+        self.mounted = false;
+        self.write_super_block()
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn check_writable(&self) -> Result<(), std::io::Error> {
+        if self.read_only {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ReadOnlyFilesystem,
+                "Partition is opened read-only",
+            ));
+        }
+        Ok(())
     }
 
     pub fn info(&self) -> (String, String, String, String) {
@@ -103,274 +335,2053 @@ impl CfsPartition {
         )
     }
 
-    // serialize the CFS to the block device
+    pub fn statfs(&self) -> StatFs {
+        self.cfs.statfs()
+    }
+
+    fn device_size(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        device_size(&mut self.blk_dev)
+    }
+
+    // serialize the CFS to the block device, along with the super block backups
     pub fn write_cfs(&mut self) -> Result<(), Box<dyn std::error::Error>> {
-        let buffer = self.cfs.to_bytes()?;
+        self.check_writable()?;
+        // a transaction writes the metadata once, when it commits
+        if !self.journals.is_empty() {
+            return Ok(());
+        }
+        if self.cfs.refcounts != self.cfs.stored_refcounts {
+            self.write_refcounts()?;
+        }
+        // a new image is open from the moment it's first written
+        if !self.mounted && self.cfs.super_block.state != superblock::STATE_ERRORS {
+            self.cfs.super_block.state = superblock::STATE_DIRTY;
+        }
+        self.mounted = true;
+        self.cfs.super_block.write_time = utils::unix_time();
+        self.cfs.super_block.update_checksum()?;
+        if self.cfs.has_block_groups() {
+            self.write_block_groups()?;
+        } else {
+            let buffer = self.cfs.to_bytes()?;
+            self.blk_dev.seek(std::io::SeekFrom::Start(0))?;
+            self.blk_dev.write_all(&buffer)?;
+        }
+        self.write_super_block_backups()?;
+        Ok(())
+    }
+
+    fn write_block_groups(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let block_size = self.cfs.super_block.blocksize as usize;
+        let blocks_per_group = self.cfs.super_block.blocks_per_group as usize;
+        let inodes_per_group = self.cfs.super_block.inodes_per_group as usize;
+        let inode_size = self.cfs.super_block.inode_size();
+
+        // super block followed by the group descriptor table
+        self.cfs.update_group_descs();
+        let mut buffer = self.cfs.super_block.to_bytes()?;
+        for group_desc in self.cfs.group_descs() {
+            buffer.extend_from_slice(&group_desc.to_bytes()?);
+        }
+        buffer.resize(self.cfs.group_start_block(0) as usize * block_size, 0);
+        self.blk_dev.seek(std::io::SeekFrom::Start(0))?;
+        self.blk_dev.write_all(&buffer)?;
+
+        // and every group's bitmaps and inode table
+        for group in 0..self.cfs.super_block.groups as usize {
+            let bam_bytes = group * blocks_per_group / 8..(group + 1) * blocks_per_group / 8;
+            let iam_bytes = group * inodes_per_group / 8..(group + 1) * inodes_per_group / 8;
+            let inodes = group * inodes_per_group..(group + 1) * inodes_per_group;
+
+            let mut buffer = self.cfs.bam.data[bam_bytes].to_vec();
+            buffer.resize(block_size, 0);
+            buffer.extend_from_slice(&self.cfs.iam.data[iam_bytes]);
+            buffer.resize(2 * block_size, 0);
+            for inode in &self.cfs.inode_list.inodes()[inodes] {
+                buffer.extend_from_slice(&inode.to_bytes_sized(inode_size)?);
+            }
+            buffer.resize(self.cfs.group_metadata_blocks() as usize * block_size, 0);
+
+            let offset = self.cfs.group_start_block(group) * block_size as u64;
+            self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
+            self.blk_dev.write_all(&buffer)?;
+        }
+        Ok(())
+    }
+
+    // only the super block and its backups, the rest of the metadata is left alone
+    fn write_super_block(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.cfs.super_block.write_time = utils::unix_time();
+        self.cfs.super_block.update_checksum()?;
+        let buffer = self.cfs.super_block.to_bytes()?;
         self.blk_dev.seek(std::io::SeekFrom::Start(0))?;
         self.blk_dev.write_all(&buffer)?;
+        self.write_super_block_backups()
+    }
+
+    fn write_super_block_backups(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let buffer = self.cfs.super_block.to_bytes()?;
+        for block_idx in self.cfs.backup_blocks() {
+            self.blk_dev
+                .seek(std::io::SeekFrom::Start(self.cfs.block_offset(block_idx)))?;
+            self.blk_dev.write_all(&buffer)?;
+        }
         Ok(())
     }
 
-    pub fn add_dentry_to_inode(
+    // Restore the primary super block (and the backups) from the one in memory,
+    // meant to be called after the partition was opened from a backup copy.
+    // Returns whether anything had to be rewritten.
+    pub fn repair_super_block(&mut self) -> Result<bool, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        self.cfs.super_block.update_checksum()?;
+        let expected = self.cfs.super_block.to_bytes()?;
+
+        let mut primary = vec![0; expected.len()];
+        self.blk_dev.seek(std::io::SeekFrom::Start(0))?;
+        self.blk_dev.read_exact(&mut primary)?;
+
+        let mut damaged = primary != expected;
+        for block_idx in self.cfs.backup_blocks() {
+            let mut backup = vec![0; expected.len()];
+            self.blk_dev
+                .seek(std::io::SeekFrom::Start(self.cfs.block_offset(block_idx)))?;
+            self.blk_dev.read_exact(&mut backup)?;
+            damaged |= backup != expected;
+        }
+
+        if !damaged {
+            return Ok(false);
+        }
+
+        log::warn!("Restoring the primary super block and its backups");
+        self.blk_dev.seek(std::io::SeekFrom::Start(0))?;
+        self.blk_dev.write_all(&expected)?;
+        self.write_super_block_backups()?;
+        Ok(true)
+    }
+
+    pub fn read_block(
         &mut self,
-        parent_inode_idx: usize,
-        dentry_name: &str,
-        inode_idx: usize,
+        block_idx: usize,
+        buffer: &mut [u8],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        log::debug!(
-            "add_dentry_to_inode(parent_inode_idx: {}, dentry_name: {}, inode_idx: {})",
-            parent_inode_idx,
-            dentry_name,
-            inode_idx
-        );
-        // a dentry_name must be at most [u8; 60]
-        let dentry_name = utils::str_to_u8_60(dentry_name);
-        let dentry = dir_entry::DirEntry::new(dentry_name, inode_idx as u32);
-        let mut inode = self.cfs.inode_list.get(parent_inode_idx);
-        let nchildren = inode.nchildren;
+        if let Some(data) = self.journaled_block(block_idx) {
+            buffer.copy_from_slice(&data[..buffer.len()]);
+            return Ok(());
+        }
 
-        // now that we have the inode, we can write the dentries to the inode.blkaddr, we must
-        // follow two rules:
-        // 1. The first block in inode.blkaddr is reserved for the dentries
-        // 2. The rest of the blocks are for the data
+        let block_size = self.cfs.super_block.blocksize as usize;
+        let Some(cache) = self.cache.as_mut().filter(|_| buffer.len() == block_size) else {
+            return self.read_block_uncached(block_idx, buffer);
+        };
+        if let Some(data) = cache.get(block_idx) {
+            buffer.copy_from_slice(data);
+            return Ok(());
+        }
 
-        // read inode.blkaddr[0] into a buffer
-        let offset = self.cfs.data_blocks_offset()
-            + (inode.blkaddr[0] as u64 * self.cfs.super_block.blocksize as u64);
+        self.read_block_uncached(block_idx, buffer)?;
+        self.cache_block(block_idx, buffer.to_vec(), false)
+    }
+
+    pub fn write_block(
+        &mut self,
+        block_idx: usize,
+        buffer: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let block_size = self.cfs.super_block.blocksize as usize;
+        if !self.journals.is_empty() {
+            // held back until the transaction commits, anything short of a whole
+            // block goes over what's there
+            let mut data = vec![0; block_size];
+            if buffer.len() < block_size {
+                self.read_block(block_idx, &mut data)?;
+            }
+            data[..buffer.len()].copy_from_slice(buffer);
+            if let Some(journal) = self.journals.last_mut() {
+                journal.blocks.insert(block_idx, data);
+            }
+            return Ok(());
+        }
+
+        let policy = match &mut self.cache {
+            Some(cache) if buffer.len() == block_size => cache.config().policy,
+            // anything but a whole block can't be cached, drop the stale copy
+            Some(cache) => {
+                if let Some(data) = cache.remove(block_idx) {
+                    self.write_block_uncached(block_idx, &data)?;
+                }
+                return self.write_block_uncached(block_idx, buffer);
+            }
+            None => return self.write_block_uncached(block_idx, buffer),
+        };
+
+        if policy == cache::WritePolicy::WriteThrough {
+            self.write_block_uncached(block_idx, buffer)?;
+        }
+        self.cache_block(
+            block_idx,
+            buffer.to_vec(),
+            policy == cache::WritePolicy::WriteBack,
+        )
+    }
+
+    fn read_block_uncached(
+        &mut self,
+        block_idx: usize,
+        buffer: &mut [u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let offset = self.cfs.block_offset(block_idx);
+        self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
+        self.blk_dev.read_exact(buffer)?;
+        Ok(())
+    }
+
+    fn write_block_uncached(
+        &mut self,
+        block_idx: usize,
+        buffer: &[u8],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let offset = self.cfs.block_offset(block_idx);
         self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
+        self.blk_dev.write_all(buffer)?;
+        Ok(())
+    }
+
+    fn cache_block(
+        &mut self,
+        block_idx: usize,
+        data: Vec<u8>,
+        dirty: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let evicted = match &mut self.cache {
+            Some(cache) => cache.insert(block_idx, data, dirty),
+            None => return Ok(()),
+        };
+        for (block_idx, data) in evicted {
+            self.write_block_uncached(block_idx, &data)?;
+        }
+        Ok(())
+    }
+
+    // Put a cache of `config.capacity` data blocks between the partition and the
+    // device, or with `None` go without one (the default). Whatever the previous
+    // cache still held back is written first.
+    pub fn set_block_cache(
+        &mut self,
+        config: Option<cache::CacheConfig>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        if config.is_some() && self.shared {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "A SharedPartition can't have a block cache",
+            )));
+        }
+        if config.is_some_and(|config| config.capacity == 0) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Cache capacity must be at least one block",
+            )));
+        }
+        self.flush_cache()?;
+        self.cache = config.map(cache::BlockCache::new);
+        Ok(())
+    }
+
+    pub fn cache_stats(&self) -> Option<cache::CacheStats> {
+        self.cache.as_ref().map(cache::BlockCache::stats)
+    }
+
+    // Write every block a write-back cache is holding back, the device itself
+    // isn't synced
+    pub fn flush_cache(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let dirty = match &mut self.cache {
+            Some(cache) => cache.take_dirty(),
+            None => return Ok(()),
+        };
+        for (block_idx, data) in dirty {
+            self.write_block_uncached(block_idx, &data)?;
+        }
+        Ok(())
+    }
+
+    // Load up to `read_ahead` blocks of a file starting at its block `first` into
+    // the cache, reading physically contiguous ones in one go
+    pub(crate) fn read_ahead(
+        &mut self,
+        inode: &inode::Inode,
+        first: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(cache) = &self.cache else {
+            return Ok(());
+        };
+        let block_size = self.cfs.super_block.blocksize as usize;
+        let nblocks = inode.file_size().div_ceil(block_size as u64) as usize;
+        let last = (first + cache.config().read_ahead).min(nblocks);
+        let wanted: Vec<usize> = (first..last)
+            .filter_map(|n| inode.data_block(n))
+            .filter(|block_idx| !cache.contains(*block_idx))
+            .collect();
+
+        let mut runs: Vec<Vec<usize>> = Vec::new();
+        for block_idx in wanted {
+            match runs.last_mut() {
+                Some(run)
+                    if self.cfs.block_offset(*run.last().unwrap_or(&0)) + block_size as u64
+                        == self.cfs.block_offset(block_idx) =>
+                {
+                    run.push(block_idx)
+                }
+                _ => runs.push(vec![block_idx]),
+            }
+        }
+
+        for run in runs {
+            let mut buffer = vec![0; run.len() * block_size];
+            self.blk_dev
+                .seek(std::io::SeekFrom::Start(self.cfs.block_offset(run[0])))?;
+            self.blk_dev.read_exact(&mut buffer)?;
+            if let Some(cache) = &mut self.cache {
+                cache.stats.read_ahead += run.len() as u64;
+            }
+            for (block_idx, data) in run.iter().zip(buffer.chunks_exact(block_size)) {
+                self.cache_block(*block_idx, data.to_vec(), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Run `f` as a single change: the blocks it writes and the metadata it touches
+    // reach the device together once it returns Ok. When it fails, the bitmaps, the
+    // inode table and the blocks are left as they were, so nothing is leaked.
+    // Transactions nest, an inner one that succeeds is part of the outer one.
+    pub fn transaction<T>(
+        &mut self,
+        f: impl FnOnce(&mut Transaction) -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let mut tx = Transaction::begin(self);
+        let value = f(&mut tx)?;
+        tx.commit()?;
+        Ok(value)
+    }
+
+    pub fn in_transaction(&self) -> bool {
+        !self.journals.is_empty()
+    }
+
+    pub(crate) fn begin_journal(&mut self) {
+        self.journals.push(Journal::new(self.cfs.clone()));
+    }
+
+    pub(crate) fn commit_journal(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(journal) = self.journals.pop() else {
+            return Ok(());
+        };
+        if let Some(outer) = self.journals.last_mut() {
+            outer.blocks.extend(journal.blocks);
+            return Ok(());
+        }
+
+        log::debug!("commit: {} blocks", journal.blocks.len());
+        let result = journal
+            .blocks
+            .iter()
+            .try_for_each(|(block_idx, data)| self.write_block(*block_idx, data))
+            .and_then(|_| self.write_cfs());
+        if let Err(e) = &result {
+            // whatever made it to the device can't be taken back
+            log::error!("Failed to commit a transaction: {e}");
+            self.cfs = journal.cfs;
+            self.cfs.super_block.state = superblock::STATE_ERRORS;
+            if let Err(e) = self.write_cfs() {
+                log::error!("Failed to restore the metadata: {e}");
+            }
+        }
+        result
+    }
+
+    pub(crate) fn rollback_journal(&mut self) {
+        if let Some(journal) = self.journals.pop() {
+            log::debug!("rollback: {} blocks dropped", journal.blocks.len());
+            self.cfs = journal.cfs;
+        }
+    }
+
+    // the latest copy of a block written in a transaction that's still open
+    fn journaled_block(&self, block_idx: usize) -> Option<&[u8]> {
+        self.journals
+            .iter()
+            .rev()
+            .find_map(|journal| journal.blocks.get(&block_idx))
+            .map(Vec::as_slice)
+    }
+
+    // Copy-on-write: a block shared with a snapshot or a clone is copied before
+    // it's changed, the caller points its inode at the returned block
+    fn unshare_block(&mut self, block_idx: usize) -> Result<usize, Box<dyn std::error::Error>> {
+        if !self.cfs.is_shared(block_idx) {
+            return Ok(block_idx);
+        }
+
+        let copy = self
+            .cfs
+            .alloc_blocks(1, Some(block_idx))
+            .ok_or_else(|| std::io::Error::other("No free blocks"))?;
+        log::debug!("unshare: block {block_idx} copied to {copy}");
         let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
-        self.blk_dev.read_exact(&mut buffer)?;
+        self.read_block(block_idx, &mut buffer)?;
+        self.write_block(copy, &buffer)?;
+        self.cfs.free_block(block_idx);
+        Ok(copy)
+    }
 
-        // inode.nchildren is the number of dentries in the first block, so we need to
-        // write to the next available dentry
-        let dentry_offset = nchildren as usize * std::mem::size_of::<dir_entry::DirEntry>();
-        let dentry_data = dentry.to_bytes()?;
+    // Take a read-only snapshot of the whole filesystem as it is now, see
+    // `snapshot`. Only the inode table is copied, the blocks are shared.
+    pub fn snapshot_create(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        snapshot::check_name(name)?;
+        // running out of blocks halfway must not leave the blocks shared
+        self.transaction(|tx| tx.add_snapshot(name))
+    }
 
-        // write the dentry to the buffer
-        buffer[dentry_offset..dentry_offset + dentry_data.len()].copy_from_slice(&dentry_data);
+    fn add_snapshot(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let mut snapshots = self.snapshot_list()?;
+        if snapshots.iter().any(|snapshot| snapshot.name() == name) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("Snapshot {name} already exists"),
+            )));
+        }
 
-        // write the buffer back to the file
-        self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
-        self.blk_dev.write_all(&buffer)?;
+        let ninodes = self.cfs.super_block.ninodes as usize;
+        let mut inodes = self.cfs.inode_list.inodes()[..ninodes].to_vec();
+        for (inode_idx, inode) in inodes.iter().enumerate() {
+            if !self.cfs.iam.get(inode_idx) {
+                continue;
+            }
+            for addr in inode.block_addrs() {
+                self.cfs.share_block(*addr as usize);
+            }
+        }
 
-        log::debug!("data_blocks_offset * self.cfs.super_block.blocksize: {offset}");
-        log::debug!("dentry_offset: {dentry_offset}");
-        log::debug!("dentry_data.len(): {}\n", dentry_data.len());
+        // data block 0 can't be shared, it would look like no block at all, so the
+        // snapshot gets its own copy of the root directory's entries
+        let root = &mut inodes[crate::ROOT_INODE];
+        if root.blkaddr[0] == 0 {
+            let copy = self
+                .cfs
+                .alloc_blocks(1, None)
+                .ok_or_else(|| std::io::Error::other("No free blocks"))?;
+            let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+            self.read_block(0, &mut buffer)?;
+            self.write_block(copy, &buffer)?;
+            root.blkaddr[0] = copy as u32;
+        }
 
-        // update the inode
-        inode.nchildren += 1;
-        self.cfs.inode_list.set(parent_inode_idx, inode);
+        let table = snapshot::encode_table(
+            &self.cfs.iam.data,
+            &inodes,
+            self.cfs.super_block.inode_size(),
+        )?;
+        let table_block = self.write_chain(&table)?;
+        log::debug!("snapshot {name}: table at {table_block}");
+        snapshots.push(snapshot::Snapshot::new(
+            name,
+            utils::unix_time(),
+            table_block,
+        ));
+        self.write_snapshot_list(&snapshots)
+    }
 
-        // debug all related values
+    pub fn snapshot_list(&mut self) -> Result<Vec<snapshot::Snapshot>, Box<dyn std::error::Error>> {
+        let list = self.read_chain(self.cfs.super_block.snapshot_block)?;
+        Ok(snapshot::decode_list(&list)?)
+    }
 
-        self.write_cfs()?;
+    // Drop a snapshot, the blocks only it pointed to are freed
+    pub fn snapshot_delete(&mut self, name: &str) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let mut snapshots = self.snapshot_list()?;
+        let position = snapshots
+            .iter()
+            .position(|snapshot| snapshot.name() == name)
+            .ok_or_else(|| no_such_snapshot(name))?;
+        let removed = snapshots.remove(position);
+
+        let (mut iam, inodes) = self.read_snapshot_table(&removed)?;
+        for (inode_idx, inode) in inodes.iter().enumerate() {
+            if !iam.get(inode_idx) {
+                continue;
+            }
+            for addr in inode.block_addrs() {
+                self.cfs.free_block(*addr as usize);
+            }
+        }
+        self.free_chain(removed.table_block)?;
+        self.write_snapshot_list(&snapshots)
+    }
+
+    // Open a read-only view of an image as it was when snapshot `name` was taken
+    pub fn open_snapshot(
+        blk_dev: std::fs::File,
+        name: &str,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut partition = Self::open_read_only(blk_dev)?;
+        let snapshot = partition
+            .snapshot_list()?
+            .into_iter()
+            .find(|snapshot| snapshot.name() == name)
+            .ok_or_else(|| no_such_snapshot(name))?;
+
+        let (mut iam, inodes) = partition.read_snapshot_table(&snapshot)?;
+        // every block the snapshot points to is in use as far as the view is
+        // concerned, whatever the live tree did with it since
+        for (inode_idx, inode) in inodes.iter().enumerate() {
+            if !iam.get(inode_idx) {
+                continue;
+            }
+            for addr in inode.block_addrs() {
+                partition.cfs.bam.set(*addr as usize);
+            }
+        }
+        partition.cfs.iam.data = iam.data;
+        partition.cfs.inode_list = inode::InodeList::from_inodes(inodes);
+        partition.cfs.update_free_counts();
+        Ok(partition)
+    }
+
+    fn read_snapshot_table(
+        &mut self,
+        snapshot: &snapshot::Snapshot,
+    ) -> Result<(bitmap::Iam, Vec<inode::Inode>), Box<dyn std::error::Error>> {
+        let table = self.read_chain(snapshot.table_block)?;
+        let (iam_data, inodes) = snapshot::decode_table(&table)?;
+        let mut iam = bitmap::Iam::new(0);
+        iam.data = iam_data;
+        Ok((iam, inodes))
+    }
+
+    fn write_snapshot_list(
+        &mut self,
+        snapshots: &[snapshot::Snapshot],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let old = self.cfs.super_block.snapshot_block;
+        self.free_chain(old)?;
+        self.cfs.super_block.snapshot_block =
+            self.write_chain(&snapshot::encode_list(snapshots)?)?;
+        self.write_cfs()
+    }
+
+    fn write_refcounts(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let old = self.cfs.super_block.refcount_block;
+        self.free_chain(old)?;
+        let refcounts = snapshot::encode_refcounts(&self.cfs.refcounts)?;
+        self.cfs.super_block.refcount_block = self.write_chain(&refcounts)?;
+        self.cfs.stored_refcounts = self.cfs.refcounts.clone();
+        Ok(())
+    }
+
+    // Store `data` in a new chain of blocks, returning the first one (0 for no data)
+    fn write_chain(&mut self, data: &[u8]) -> Result<u32, Box<dyn std::error::Error>> {
+        let block_size = self.cfs.super_block.blocksize as usize;
+        let chunks: Vec<&[u8]> = data
+            .chunks(block_size - snapshot::CHAIN_HEADER_SIZE)
+            .collect();
+        let mut blocks = Vec::with_capacity(chunks.len());
+        for _ in &chunks {
+            match self.cfs.alloc_blocks(1, None) {
+                Some(block_idx) => blocks.push(block_idx),
+                None => {
+                    for block_idx in blocks {
+                        self.cfs.free_block(block_idx);
+                    }
+                    return Err(Box::new(std::io::Error::other("No free blocks")));
+                }
+            }
+        }
+
+        for (n, chunk) in chunks.iter().enumerate() {
+            let header = snapshot::ChainHeader {
+                next: blocks.get(n + 1).map_or(0, |next| *next as u32),
+                len: chunk.len() as u32,
+            };
+            let mut buffer = header.to_bytes()?;
+            buffer.extend_from_slice(chunk);
+            buffer.resize(block_size, 0);
+            self.write_block(blocks[n], &buffer)?;
+        }
+        Ok(blocks.first().map_or(0, |first| *first as u32))
+    }
+
+    fn read_chain(&mut self, first: u32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut data = Vec::new();
+        for block_idx in self.chain_blocks(first)? {
+            let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+            self.read_block(block_idx, &mut buffer)?;
+            let ((rest, _), header) = snapshot::ChainHeader::from_bytes((&buffer, 0))?;
+            data.extend_from_slice(&rest[..(header.len as usize).min(rest.len())]);
+        }
+        Ok(data)
+    }
+
+    fn free_chain(&mut self, first: u32) -> Result<(), Box<dyn std::error::Error>> {
+        for block_idx in self.chain_blocks(first)? {
+            self.cfs.free_block(block_idx);
+        }
+        Ok(())
+    }
+
+    // the blocks of a chain in order, a corrupted one that loops is an error
+    fn chain_blocks(&mut self, first: u32) -> Result<Vec<usize>, Box<dyn std::error::Error>> {
+        let data_blocks = self.cfs.data_blocks() as usize;
+        let mut blocks = Vec::new();
+        let mut next = first as usize;
+        let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+        while next != 0 {
+            if next >= data_blocks || blocks.len() >= data_blocks {
+                return Err(Box::new(std::io::Error::other("Corrupted block chain")));
+            }
+            blocks.push(next);
+            self.read_block(next, &mut buffer)?;
+            next = snapshot::ChainHeader::from_bytes((&buffer, 0))?.1.next as usize;
+        }
+        Ok(blocks)
+    }
+
+    // Grow or shrink the filesystem to `new_size` bytes. Growing extends the bitmaps
+    // (adding groups when there are any), shrinking first moves every block living
+    // past the new end somewhere below it. The inode table of the flat layout has a
+    // fixed size, and shrinking a block group layout only drops groups whose inodes
+    // are all free. Image files are resized along with the filesystem, block
+    // devices must already be big enough. Resizing is meant for images nobody else
+    // is using and isn't crash-safe, see relayout.
+    pub fn resize(&mut self, new_size: u64) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let block_size = self.cfs.super_block.blocksize as u64;
+        let old_nblocks = self.cfs.super_block.nblocks as u64;
+        let new_nblocks = new_size / block_size;
+        if new_nblocks == old_nblocks {
+            return Ok(());
+        }
+        log::debug!("resize: {old_nblocks} -> {new_nblocks} blocks");
+
+        // work out the new geometry, relayout takes care of the rest
+        let mut super_block = self.cfs.super_block.clone();
+        super_block.nblocks = new_nblocks as u32;
+        if self.cfs.has_block_groups() {
+            let groups = block_group_count(
+                new_nblocks,
+                super_block.gdt_blocks as u64,
+                self.cfs.group_span(),
+                super_block.blocks_per_group as u64,
+            );
+            if groups == 0 {
+                return Err(Box::new(std::io::Error::other("New size too small")));
+            }
+            if groups * group::GROUP_DESC_SIZE > super_block.gdt_blocks as u64 * block_size {
+                return Err(Box::new(std::io::Error::other(
+                    "Not enough room left in the group descriptor table",
+                )));
+            }
+            super_block.groups = groups as u32;
+            super_block.ninodes = groups as u32 * super_block.inodes_per_group;
+        } else {
+            let bam_blocks = new_nblocks.div_ceil(bits_per_block(block_size));
+            super_block.bam_blocks = super_block.bam_blocks.max(bam_blocks as u32);
+        }
+        self.relayout(super_block, new_size)
+    }
+
+    // Convert an image from before FEATURE_LARGE_INODE: legacy inodes are grown to
+    // INODE_SIZE, pushing the data area (or every group) further into the device
+    // like growing the BAM does in `resize`, then the feature is turned on
+    pub fn enable_large_inodes(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        if self
+            .cfs
+            .super_block
+            .has_feature(superblock::FEATURE_LARGE_INODE)
+        {
+            return Ok(());
+        }
+
+        let mut super_block = self.cfs.super_block.clone();
+        if super_block.inode_size() < inode::INODE_SIZE {
+            let block_size = super_block.blocksize as u64;
+            let inode_size = inode::INODE_SIZE as u64;
+            super_block.inode_size = inode::INODE_SIZE;
+            if self.cfs.has_block_groups() {
+                let inodes_per_group = super_block.inodes_per_group as u64;
+                let inode_table_blocks = (inodes_per_group * inode_size).div_ceil(block_size);
+                let group_span = 2 + inode_table_blocks + super_block.blocks_per_group as u64;
+                let groups = block_group_count(
+                    super_block.nblocks as u64,
+                    super_block.gdt_blocks as u64,
+                    group_span,
+                    super_block.blocks_per_group as u64,
+                );
+                if groups == 0 {
+                    return Err(Box::new(std::io::Error::other("Device too small")));
+                }
+                super_block.inode_blocks = inode_table_blocks as u32;
+                super_block.groups = groups as u32;
+                super_block.ninodes = groups as u32 * super_block.inodes_per_group;
+            } else {
+                let inode_blocks = (super_block.ninodes as u64 * inode_size).div_ceil(block_size);
+                super_block.inode_blocks = inode_blocks as u32;
+            }
+        }
+        super_block.features |= superblock::FEATURE_LARGE_INODE;
+
+        let size = self.device_size()?;
+        self.relayout(super_block, size)
+    }
+
+    // Move the filesystem over to the layout described by `super_block`: blocks
+    // that have no place in it are moved elsewhere first, then the data blocks are
+    // shifted to where the new layout puts them. Block and inode numbers stay the
+    // same, so only inodes past the new end are required to be free. Nothing is
+    // journaled: a crash halfway leaves the image marked STATE_ERRORS with blocks
+    // the old metadata still points to already overwritten.
+    fn relayout(
+        &mut self,
+        super_block: superblock::SuperBlock,
+        new_size: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // blocks are moved on the device directly, there's no holding that back
+        if self.in_transaction() {
+            return Err(Box::new(std::io::Error::other(
+                "Cannot change the layout inside a transaction",
+            )));
+        }
+        // snapshots point to blocks that would have to be moved along
+        if self.cfs.super_block.snapshot_block != 0 {
+            return Err(Box::new(std::io::Error::other(
+                "Cannot change the layout of an image with snapshots",
+            )));
+        }
+        let block_size = self.cfs.super_block.blocksize as u64;
+        let old_nblocks = self.cfs.super_block.nblocks as u64;
+        let new_nblocks = super_block.nblocks as u64;
+        let layout = Cfs::new(
+            super_block,
+            bitmap::Bam::new(0),
+            bitmap::Iam::new(0),
+            inode::InodeList::default(),
+        );
+        let old_data_blocks = self.cfs.data_blocks() as usize;
+        let new_data_blocks = layout.data_blocks() as usize;
+        let ninodes = layout.super_block.ninodes as usize;
+        if new_data_blocks == 0 {
+            return Err(Box::new(std::io::Error::other("New size too small")));
+        }
+
+        // inodes can't be moved around, so the ones past the new end must be free
+        let old_ninodes = self.cfs.super_block.ninodes as usize;
+        if (ninodes..old_ninodes).any(|inode_idx| self.cfs.iam.get(inode_idx)) {
+            return Err(Box::new(std::io::Error::other(
+                "Inodes in use past the new end",
+            )));
+        }
+
+        // the refcounts are kept in blocks no inode points to, they're written
+        // again in the new layout
+        let refcount_block = self.cfs.super_block.refcount_block;
+        self.free_chain(refcount_block)?;
+        self.cfs.super_block.refcount_block = 0;
+        self.cfs.stored_refcounts.clear();
+
+        // Build the new BAM: the old super block backups and the bits that were only
+        // reserved because the device ended there become free, the new backups are
+        // reserved (they may land on blocks in use, which will be moved as well)
+        let mut bam = self.cfs.bam.clone();
+        for block_idx in self.cfs.backup_blocks() {
+            bam.clear(block_idx);
+        }
+        for block_idx in old_data_blocks..bam.data.len() * 8 {
+            bam.clear(block_idx);
+        }
+        let bam_bytes = match layout.has_block_groups() {
+            true => {
+                new_data_blocks.div_ceil(layout.super_block.blocks_per_group as usize)
+                    * layout.super_block.blocks_per_group as usize
+                    / 8
+            }
+            false => layout.super_block.bam_blocks as usize * block_size as usize,
+        };
+        bam.data.resize(bam_bytes.max(bam.data.len()), 0);
+        check_backup_blocks(&layout)?;
+        let backups = layout.backup_blocks();
+        for block_idx in &backups {
+            bam.set(*block_idx);
+        }
+
+        // and make sure everything that has to move has somewhere to go
+        let must_move =
+            |block_idx: usize| block_idx >= new_data_blocks || backups.contains(&block_idx);
+        let moving = (0..old_ninodes)
+            .filter(|inode_idx| self.cfs.iam.get(*inode_idx))
+            .flat_map(|inode_idx| {
+                let inode = self.cfs.inode_list.get(inode_idx);
+                inode.block_addrs().copied().collect::<Vec<_>>()
+            })
+            .filter(|addr| must_move(*addr as usize))
+            .count();
+        if (0..old_data_blocks)
+            .any(|block_idx| self.cfs.is_shared(block_idx) && must_move(block_idx))
+        {
+            return Err(Box::new(std::io::Error::other(
+                "Shared blocks in the way of the new layout",
+            )));
+        }
+        if moving > bam.count_free(new_data_blocks) {
+            return Err(Box::new(std::io::Error::other(
+                "Not enough free space to move blocks",
+            )));
+        }
+
+        // image files are resized along with the filesystem, a block device has to
+        // be big enough already
+        let is_file = self.blk_dev.metadata()?.is_file();
+        if !is_file && new_size > self.device_size()? {
+            return Err(Box::new(std::io::Error::other(
+                "New size doesn't fit the device",
+            )));
+        }
+
+        // Blocks are shifted in place, so an image whose relayout was cut short is
+        // described by neither layout. It's flagged as having errors until the new
+        // one is written.
+        let state = self.cfs.super_block.state;
+        self.cfs.super_block.state = superblock::STATE_ERRORS;
+        self.write_super_block()?;
+
+        if new_nblocks > old_nblocks && is_file {
+            self.blk_dev.set_len(new_size)?;
+        }
+
+        // move the blocks out of the way, still in the old layout
+        let mut buffer = vec![0; block_size as usize];
+        let mut goal = 0;
+        for inode_idx in 0..ninodes.min(old_ninodes) {
+            if !self.cfs.iam.get(inode_idx) {
+                continue;
+            }
+
+            let mut inode = self.cfs.inode_list.get(inode_idx);
+            let mut moved = false;
+            for addr in inode.block_addrs_mut() {
+                if !must_move(*addr as usize) {
+                    continue;
+                }
+                let block_idx = bam
+                    .find_free_run(1, goal, new_data_blocks)
+                    .or_else(|| bam.find_free_run(1, 0, new_data_blocks))
+                    .ok_or_else(|| std::io::Error::other("No free blocks"))?;
+                log::debug!("relayout: moving block {addr} to {block_idx}");
+
+                self.read_block(*addr as usize, &mut buffer)?;
+                self.write_block(block_idx, &buffer)?;
+
+                bam.set(block_idx);
+                *addr = block_idx as u32;
+                goal = block_idx + 1;
+                moved = true;
+            }
+            if moved {
+                self.cfs.inode_list.set(inode_idx, inode);
+            }
+        }
+
+        // Growing the BAM or the inode table pushes the data further into the
+        // device, block numbers don't change but their contents have to be moved.
+        // Cached blocks stay valid, but the device has to hold the latest copy.
+        // Blocks only ever move forward, so going backwards never overwrites a block
+        // that is still to be copied.
+        self.flush_cache()?;
+        for block_idx in (0..new_data_blocks).rev() {
+            let from = self.cfs.block_offset(block_idx);
+            let to = layout.block_offset(block_idx);
+            if from != to && bam.get(block_idx) {
+                self.blk_dev.seek(std::io::SeekFrom::Start(from))?;
+                self.blk_dev.read_exact(&mut buffer)?;
+                self.blk_dev.seek(std::io::SeekFrom::Start(to))?;
+                self.blk_dev.write_all(&buffer)?;
+            }
+        }
+
+        // From here on the new layout is in place
+        self.cfs.super_block = layout.super_block;
+        self.cfs.super_block.state = state;
+        // `super_block` was taken before the refcount chain was freed
+        self.cfs.super_block.refcount_block = 0;
+        self.cfs.bam = bam;
+        if self.cfs.has_block_groups() {
+            self.cfs.iam.data.resize(ninodes / 8, 0);
+            self.cfs.inode_list.resize(ninodes);
+        }
+
+        self.cfs.bam.data.truncate(bam_bytes);
+        self.cfs.reserve_unusable();
+        self.write_cfs()?;
+
+        if new_nblocks < old_nblocks && is_file {
+            self.blk_dev.set_len(new_size)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn add_dentry_to_inode(
+        &mut self,
+        parent_inode_idx: usize,
+        dentry_name: &str,
+        inode_idx: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        log::debug!(
+            "add_dentry_to_inode(parent_inode_idx: {}, dentry_name: {}, inode_idx: {})",
+            parent_inode_idx,
+            dentry_name,
+            inode_idx
+        );
+        // a dentry_name must be at most [u8; 60], or 59 with the file type after it,
+        // longer names are refused rather than cut short
+        let typed = self.has_typed_dentries();
+        let max_name_len = match typed {
+            true => dir_entry::MAX_TYPED_NAME_LEN,
+            false => dir_entry::MAX_NAME_LEN,
+        };
+        if dentry_name.len() > max_name_len {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("File name longer than {max_name_len} bytes"),
+            )));
+        }
+        let dentry_name = utils::str_to_u8_60(dentry_name);
+        let file_type = self.cfs.inode_list.get(inode_idx).file_type();
+        let dentry =
+            dir_entry::DirEntry::new(dentry_name, inode_idx as u32).with_file_type(file_type);
+        let mut inode = self.cfs.inode_list.get(parent_inode_idx);
+        let nchildren = inode.nchildren;
+
+        // now that we have the inode, we can write the dentries to the inode.blkaddr, we must
+        // follow two rules:
+        // 1. The first block in inode.blkaddr is reserved for the dentries
+        // 2. The rest of the blocks are for the data
+
+        // read inode.blkaddr[0] into a buffer
+        let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+        self.read_block(inode.blkaddr[0] as usize, &mut buffer)?;
+
+        // inode.nchildren is the number of dentry slots in the first block, removed
+        // entries leave a free slot behind (see remove_dir_from_inode) which is reused
+        // before appending a new one
+        let slot = (0..nchildren as usize)
+            .find(|slot| dentry_slot_inode(&buffer, *slot) == inode::BAD_INODE)
+            .unwrap_or(nchildren as usize);
+        let dentry_offset = slot * DIR_ENTRY_SIZE;
+        if dentry_offset + DIR_ENTRY_SIZE > buffer.len() {
+            return Err(Box::new(std::io::Error::other("Directory is full")));
+        }
+        let dentry_data = dentry.to_bytes_typed(typed)?;
+
+        // write the dentry to the buffer
+        buffer[dentry_offset..dentry_offset + dentry_data.len()].copy_from_slice(&dentry_data);
+
+        // write the buffer back to the file, a block shared with a snapshot is
+        // copied first
+        inode.blkaddr[0] = self.unshare_block(inode.blkaddr[0] as usize)? as u32;
+        self.write_block(inode.blkaddr[0] as usize, &buffer)?;
+
+        log::debug!("dentry block: {}", inode.blkaddr[0]);
+        log::debug!("dentry_offset: {dentry_offset}");
+        log::debug!("dentry_data.len(): {}\n", dentry_data.len());
+
+        // update the inode
+        inode.nchildren = inode.nchildren.max(slot as u16 + 1);
+        inode.set_mtime(utils::now());
+        inode.set_ctime(inode.mtime());
+        self.cfs.inode_list.set(parent_inode_idx, inode);
+
+        // debug all related values
+
+        self.write_cfs()?;
+
+        Ok(())
+    }
+
+    pub fn add_file_to_inode(
+        &mut self,
+        parent_inode_idx: usize,
+        name: &str,
+        file: &mut std::fs::File,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        log::debug!("File {name} added in parent inode {parent_inode_idx}");
+        let host_file = read_host_file(
+            file,
+            self.cfs.super_block.blocksize as usize,
+            self.cfs.super_block.supports_inline_data(),
+        )?;
+        let inode_idx = self.alloc_file(parent_inode_idx, &host_file)?;
+
+        // now we need to write the file data to the blocks
+        let inode = self.cfs.inode_list.get(inode_idx);
+        for (n, buffer) in host_file.contents.iter().enumerate() {
+            if let Some(block_idx) = inode.data_block(n) {
+                log::debug!("Writing {name} block {n} @ {block_idx}");
+                self.write_block(block_idx, buffer)
+                    .map_err(|e| self.discard_inode(inode_idx, e))?;
+            }
+        }
+
+        // add dentry to parent inode
+        log::debug!("parent_inode_idx: {}", parent_inode_idx);
+        log::debug!("name: {}", name);
+        log::debug!("inode_idx: {}", inode_idx);
+        self.link_inode(parent_inode_idx, name, inode_idx)
+            .map_err(|e| self.discard_inode(inode_idx, e))
+    }
+
+    // Allocate the inode and data blocks of a file read with read_host_file, the
+    // caller writes the contents and links it with link_inode. They are separate
+    // steps so that SharedPartition can write the data without holding the lock.
+    pub(crate) fn alloc_file(
+        &mut self,
+        parent_inode_idx: usize,
+        host_file: &HostFile,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let mut inode = host_file.inode;
+        self.check_inode_limits(inode.uid(), inode.gid(), inode.file_size())?;
+
+        // we need to allocate a new inode for the file
+        let inode_idx = match self.cfs.alloc_inode_near(parent_inode_idx) {
+            Some(inode_idx) => inode_idx,
+            None => {
+                return Err(Box::new(std::io::Error::other("No free inodes")));
+            }
+        };
+
+        // blocks made only of zeros are left as holes
+        let is_hole = |buffer: &Vec<u8>| buffer.iter().all(|byte| *byte == 0);
+        let contents = &host_file.contents;
+        let nholes = contents.iter().filter(|buffer| is_hole(buffer)).count();
+
+        // try to keep the whole file in one run right after the parent directory,
+        // and only fall back to scattered blocks when there is no such run
+        let goal = self.cfs.block_goal(parent_inode_idx, inode_idx);
+        let count = contents.len() - nholes;
+        let run = self.cfs.alloc_blocks(count, Some(goal));
+
+        // blkaddr[0] is only used by directories, file data starts at blkaddr[1]
+        let slots = (0..contents.len())
+            .filter(|n| !is_hole(&contents[*n]))
+            .map(|n| n + 1);
+        for (i, slot) in slots.enumerate() {
+            let block_idx = match run {
+                Some(start) => Some(start + i),
+                None => self.cfs.alloc_blocks(1, Some(goal)),
+            };
+            if let Some(block_idx) = block_idx {
+                inode.blkaddr[slot] = block_idx as u32;
+                log::debug!("blkaddr[{}]: {}", slot, inode.blkaddr[slot]);
+            } else {
+                // give back the blocks taken so far along with the inode
+                self.cfs.inode_list.set(inode_idx, inode);
+                let e = Box::new(std::io::Error::other("No free blocks"));
+                return Err(self.discard_inode(inode_idx, e));
+            }
+        }
+
+        self.cfs.inode_list.set(inode_idx, inode);
+        Ok(inode_idx)
+    }
+
+    // Give a new inode its inherited ACL and its entry in the parent directory
+    pub(crate) fn link_inode(
+        &mut self,
+        parent_inode_idx: usize,
+        name: &str,
+        inode_idx: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let is_dir = self.cfs.inode_list.get(inode_idx).is_dir();
+        self.inherit_acl(parent_inode_idx, inode_idx, is_dir)?;
+        self.add_dentry_to_inode(parent_inode_idx, name, inode_idx)
+    }
+
+    // This function is used ub the same way as add_file_to_inode
+    // but it just add a directory instead of a file
+    pub fn add_dir_to_inode(
+        &mut self,
+        parent_inode_idx: usize,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let size = 0;
+        let fmode = inode::S_IFDIR | 0o755;
+        let uid = std::process::id();
+        let gid = std::process::id();
+        let atime = utils::now();
+        let mtime = atime;
+        let ctime = atime;
+        self.check_inode_limits(uid, gid, size)?;
+
+        // we need to allocate a new inode for the uppcomming directory
+        let inode_idx = match self.cfs.alloc_inode_near(parent_inode_idx) {
+            Some(inode_idx) => inode_idx,
+            None => {
+                return Err(Box::new(std::io::Error::other("No free inodes")));
+            }
+        };
+
+        // The dir does not have any data blocks, so we just set the blkaddr to 0
+        let mut blkaddr = [0; 10];
+        let goal = self.cfs.block_goal(parent_inode_idx, inode_idx);
+        if let Some(block_idx) = self.cfs.alloc_blocks(1, Some(goal)) {
+            blkaddr[0] = block_idx as u32;
+            log::debug!("blkaddr[0]: {}", blkaddr[0]);
+        } else {
+            let e = Box::new(std::io::Error::other("No free blocks"));
+            return Err(self.discard_inode(inode_idx, e));
+        }
+
+        // now we need to create the inode
+        let inode = inode::Inode::new(fmode, 0, uid, gid, size, atime, mtime, ctime, blkaddr);
+        self.cfs.inode_list.set(inode_idx, inode);
+
+        // add dentry to parent inode
+        log::debug!("parent_inode_idx: {}", parent_inode_idx);
+        log::debug!("name: {}", name);
+        log::debug!("inode_idx: {}", inode_idx);
+        self.link_inode(parent_inode_idx, name, inode_idx)
+            .map_err(|e| self.discard_inode(inode_idx, e))
+    }
+
+    // Create a special file like mknod(2): `mode` holds the file type, one of
+    // S_IFCHR, S_IFBLK, S_IFIFO, S_IFSOCK or S_IFREG for an empty file, and the
+    // permissions. `rdev` is the device number of device files, see inode::makedev.
+    pub fn mknod(
+        &mut self,
+        parent_inode_idx: usize,
+        name: &str,
+        mode: u16,
+        rdev: u32,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let file_type = inode::FileType::from_mode(mode);
+        let is_device = matches!(
+            file_type,
+            Some(inode::FileType::CharDevice | inode::FileType::BlockDevice)
+        );
+        if !matches!(
+            file_type,
+            Some(
+                inode::FileType::Regular
+                    | inode::FileType::CharDevice
+                    | inode::FileType::BlockDevice
+                    | inode::FileType::Fifo
+                    | inode::FileType::Socket
+            )
+        ) {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid file type for mknod",
+            )));
+        }
+        // legacy inodes have nowhere to keep the device number
+        if is_device && rdev != 0 && self.cfs.super_block.inode_size() < inode::INODE_SIZE {
+            return Err(Box::new(std::io::Error::other(
+                "Device files need large inodes, see enable_large_inodes",
+            )));
+        }
+
+        let uid = std::process::id();
+        let gid = std::process::id();
+        let now = utils::now();
+        self.check_inode_limits(uid, gid, 0)?;
+
+        let inode_idx = match self.cfs.alloc_inode_near(parent_inode_idx) {
+            Some(inode_idx) => inode_idx,
+            None => {
+                return Err(Box::new(std::io::Error::other("No free inodes")));
+            }
+        };
+
+        // special files have no data, so no blocks either
+        let mut inode = inode::Inode::new(mode, 0, uid, gid, 0, now, now, now, [0; 10]);
+        if is_device {
+            inode.rdev = rdev;
+        }
+        self.cfs.inode_list.set(inode_idx, inode);
+
+        log::debug!("mknod {name} ({mode:o}) as inode {inode_idx} in {parent_inode_idx}");
+        self.link_inode(parent_inode_idx, name, inode_idx)
+            .map_err(|e| self.discard_inode(inode_idx, e))?;
+        Ok(inode_idx)
+    }
+
+    // Make a copy of a regular file that shares its data blocks instead of copying
+    // them (a reflink), either side gets its own copy of a block when it changes
+    // it. Permissions and ownership come along, xattrs don't.
+    pub fn clone_file(
+        &mut self,
+        src_inode_idx: usize,
+        dst_parent_inode_idx: usize,
+        name: &str,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let src = self.cfs.inode_list.get(src_inode_idx);
+        check_regular(&src)?;
+        self.check_inode_limits(src.uid(), src.gid(), src.file_size())?;
+
+        let inode_idx = match self.cfs.alloc_inode_near(dst_parent_inode_idx) {
+            Some(inode_idx) => inode_idx,
+            None => {
+                return Err(Box::new(std::io::Error::other("No free inodes")));
+            }
+        };
+
+        let now = utils::now();
+        let mut inode = inode::Inode::new(
+            src.mode,
+            0,
+            src.uid(),
+            src.gid(),
+            src.file_size(),
+            now,
+            now,
+            now,
+            [0; 10],
+        );
+        inode.flags = src.flags;
+        inode.blkaddr = src.blkaddr;
+        for n in 0..inode::MAX_FILE_BLOCKS {
+            if let Some(block_idx) = src.data_block(n) {
+                self.cfs.share_block(block_idx);
+            }
+        }
+        self.cfs.inode_list.set(inode_idx, inode);
+
+        log::debug!("clone {src_inode_idx} as {name} ({inode_idx}) in {dst_parent_inode_idx}");
+        self.link_inode(dst_parent_inode_idx, name, inode_idx)
+            .map_err(|e| self.discard_inode(inode_idx, e))?;
+        Ok(inode_idx)
+    }
+
+    // Make [dst_offset, dst_offset + len) of a file share the blocks of
+    // [src_offset, src_offset + len) of another (or the same) one, like
+    // FICLONERANGE. Offsets are in whole blocks, and so is `len` unless the range
+    // runs up to the end of the source, 0 meaning just that. The blocks the
+    // destination had there are dropped, it grows when the range goes past its end.
+    pub fn clone_range(
+        &mut self,
+        src_inode_idx: usize,
+        src_offset: u64,
+        dst_inode_idx: usize,
+        dst_offset: u64,
+        len: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let block_size = self.cfs.super_block.blocksize as u64;
+        let src = self.cfs.inode_list.get(src_inode_idx);
+        let mut dst = self.cfs.inode_list.get(dst_inode_idx);
+        check_regular(&src)?;
+        check_regular(&dst)?;
+        if src.is_inline() {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "Inline files have no blocks to share",
+            )));
+        }
+
+        let src_size = src.file_size();
+        let len = match len {
+            0 => src_size.saturating_sub(src_offset),
+            len => len,
+        };
+        let src_end = src_offset.saturating_add(len);
+        let dst_end = dst_offset.saturating_add(len);
+        let invalid = |reason: &str| -> Result<(), Box<dyn std::error::Error>> {
+            Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("Invalid clone range: {reason}"),
+            )))
+        };
+        if !src_offset.is_multiple_of(block_size) || !dst_offset.is_multiple_of(block_size) {
+            return invalid("offsets must be block aligned");
+        }
+        if src_end > src_size {
+            return invalid("past the end of the source");
+        }
+        // a partial last block is only fine when it's the last one on both sides,
+        // what follows it would show up in the destination otherwise
+        if !len.is_multiple_of(block_size) && (src_end != src_size || dst_end < dst.file_size()) {
+            return invalid("length must be block aligned");
+        }
+        if src_inode_idx == dst_inode_idx && src_offset < dst_end && dst_offset < src_end {
+            return invalid("overlapping ranges");
+        }
+        if dst_end > inode::MAX_FILE_BLOCKS as u64 * block_size {
+            return Err(Box::new(std::io::Error::other("File too large")));
+        }
+        self.check_inode_limits(dst.uid(), dst.gid(), dst_end.max(dst.file_size()))?;
+        if len == 0 {
+            return Ok(());
+        }
+        if dst.is_inline() {
+            self.promote_inline_data(&mut dst)?;
+        }
+
+        let src_first = (src_offset / block_size) as usize;
+        let dst_first = (dst_offset / block_size) as usize;
+        for n in 0..len.div_ceil(block_size) as usize {
+            let shared = src.data_block(src_first + n);
+            if let Some(block_idx) = shared {
+                self.cfs.share_block(block_idx);
+            }
+            if let Some(block_idx) = dst.data_block(dst_first + n) {
+                self.cfs.free_block(block_idx);
+            }
+            dst.blkaddr[dst_first + n + 1] = shared.unwrap_or(0) as u32;
+        }
+
+        if dst_end > dst.file_size() {
+            dst.set_file_size(dst_end);
+        }
+        dst.set_mtime(utils::now());
+        dst.set_ctime(dst.mtime());
+        self.cfs.inode_list.set(dst_inode_idx, dst);
+        self.write_cfs()?;
+        Ok(())
+    }
+
+    // Without FEATURE_LARGE_INODE the upper halves of uid, gid and size have
+    // nowhere to go, refuse them instead of silently truncating them
+    fn check_inode_limits(
+        &self,
+        uid: u32,
+        gid: u32,
+        size: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let large = self
+            .cfs
+            .super_block
+            .has_feature(superblock::FEATURE_LARGE_INODE);
+        if !large && (uid > u16::MAX as u32 || gid > u16::MAX as u32 || size > u32::MAX as u64) {
+            return Err(Box::new(std::io::Error::other(
+                "uid, gid or size needs large inodes, see enable_large_inodes",
+            )));
+        }
+        Ok(())
+    }
+
+    pub fn open_file(&mut self, inode_idx: usize) -> file::FileHandle<'_> {
+        file::FileHandle::new(self, inode_idx)
+    }
+
+    // This function is used to get the file data from the inode data blocks
+    pub fn get_data_from_inode(
+        &mut self,
+        inode_idx: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut ret = Vec::new();
+        self.open_file(inode_idx).read_to_end(&mut ret)?;
+        Ok(ret)
+    }
+
+    // Write to a file at `offset` like pwrite(2), growing it as needed. Only ever
+    // one block per call, like read_at, the number of bytes written is returned.
+    pub fn write_at(
+        &mut self,
+        inode_idx: usize,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let (len, block_idx) = self.prepare_write(inode_idx, offset, buf)?;
+        if let Some(block_idx) = block_idx {
+            let block_size = self.cfs.super_block.blocksize as u64;
+            let in_block = (offset % block_size) as usize;
+            let mut block = vec![0; block_size as usize];
+            self.read_block(block_idx, &mut block)?;
+            block[in_block..in_block + len].copy_from_slice(&buf[..len]);
+            self.write_block(block_idx, &block)?;
+        }
+        Ok(len)
+    }
+
+    // Everything write_at does but writing the data: inline contents are changed
+    // right away, otherwise the block `buf[..len]` goes to is allocated (or copied
+    // when it's shared) and the file is grown. Returns `len` and that block, which
+    // SharedPartition then writes to without holding the partition.
+    pub(crate) fn prepare_write(
+        &mut self,
+        inode_idx: usize,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(usize, Option<usize>), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        check_contents(&inode)?;
+        let block_size = self.cfs.super_block.blocksize as u64;
+        if buf.is_empty() {
+            return Ok((0, None));
+        }
+        if offset >= inode::MAX_FILE_BLOCKS as u64 * block_size {
+            return Err(Box::new(std::io::Error::other("File too large")));
+        }
+
+        // never write across a block boundary, callers will just come back for more
+        let len = (buf.len() as u64).min(block_size - offset % block_size) as usize;
+        let end = offset + len as u64;
+        self.check_inode_limits(inode.uid(), inode.gid(), end.max(inode.file_size()))?;
+
+        let mut target = None;
+        if inode.is_inline() && end as usize <= inode::MAX_INLINE_DATA {
+            let mut data = inode.inline_data();
+            data.resize(data.len().max(end as usize), 0);
+            data[offset as usize..end as usize].copy_from_slice(&buf[..len]);
+            inode.set_inline_data(&data);
+        } else {
+            let promoted = inode.is_inline();
+            if promoted {
+                self.promote_inline_data(&mut inode)?;
+            }
+            let n = (offset / block_size) as usize;
+            let block_idx = match inode.data_block(n) {
+                Some(block_idx) => self.unshare_block(block_idx)?,
+                None => {
+                    let goal = inode.block_addrs().max().copied().unwrap_or(0) as usize;
+                    let Some(block_idx) = self.cfs.alloc_blocks(1, Some(goal)) else {
+                        // the inode isn't written back, neither is the block its
+                        // inline contents just moved to
+                        if let Some(block_idx) = inode.data_block(0).filter(|_| promoted) {
+                            self.cfs.free_block(block_idx);
+                        }
+                        return Err(Box::new(std::io::Error::other("No free blocks")));
+                    };
+                    self.write_block(block_idx, &vec![0; block_size as usize])?;
+                    block_idx
+                }
+            };
+            inode.blkaddr[n + 1] = block_idx as u32;
+            if end > inode.file_size() {
+                inode.set_file_size(end);
+            }
+            target = Some(block_idx);
+        }
+
+        inode.set_mtime(utils::now());
+        inode.set_ctime(inode.mtime());
+        self.cfs.inode_list.set(inode_idx, inode);
+        self.write_cfs()?;
+        Ok((len, target))
+    }
+
+    // Change the size of a file. Shrinking frees every block past the new end and
+    // zeroes what's left of the last one, growing just leaves a hole behind.
+    pub fn truncate(
+        &mut self,
+        inode_idx: usize,
+        new_len: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let block_size = self.cfs.super_block.blocksize as u64;
+        if new_len > inode::MAX_FILE_BLOCKS as u64 * block_size {
+            return Err(Box::new(std::io::Error::other("File too large")));
+        }
+
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        check_contents(&inode)?;
+        self.check_inode_limits(inode.uid(), inode.gid(), new_len)?;
+        if inode.is_inline() {
+            if new_len as usize <= inode::MAX_INLINE_DATA {
+                let mut data = inode.inline_data();
+                data.resize(new_len as usize, 0);
+                inode.set_inline_data(&data);
+            } else {
+                self.promote_inline_data(&mut inode)?;
+            }
+        } else if new_len < inode.file_size() {
+            let first_freed = new_len.div_ceil(block_size) as usize;
+            for n in first_freed..inode::MAX_FILE_BLOCKS {
+                if let Some(block_idx) = inode.data_block(n) {
+                    self.cfs.free_block(block_idx);
+                    inode.blkaddr[n + 1] = 0;
+                }
+            }
+
+            // bytes past the end of a file must read as zeros if it ever grows again
+            let tail = (new_len % block_size) as usize;
+            let n = (new_len / block_size) as usize;
+            if let Some(block_idx) = inode.data_block(n) {
+                if tail != 0 {
+                    let block_idx = self.unshare_block(block_idx)?;
+                    inode.blkaddr[n + 1] = block_idx as u32;
+                    self.zero_block_range(block_idx, tail, block_size as usize)?;
+                }
+            }
+        }
+
+        inode.set_file_size(new_len);
+        inode.set_mtime(utils::now());
+        inode.set_ctime(inode.mtime());
+        self.cfs.inode_list.set(inode_idx, inode);
+        self.write_cfs()?;
+        Ok(())
+    }
+
+    // Allocate (or with FALLOC_FL_PUNCH_HOLE, deallocate) the blocks backing
+    // [offset, offset + len) of a file, see fallocate(2)
+    pub fn fallocate(
+        &mut self,
+        inode_idx: usize,
+        offset: u64,
+        len: u64,
+        mode: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let block_size = self.cfs.super_block.blocksize as u64;
+        let keep_size = mode & file::FALLOC_FL_KEEP_SIZE != 0;
+        let punch_hole = mode & file::FALLOC_FL_PUNCH_HOLE != 0;
+        if mode & !(file::FALLOC_FL_KEEP_SIZE | file::FALLOC_FL_PUNCH_HOLE) != 0
+            || (punch_hole && !keep_size)
+        {
+            return Err(Box::new(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Unsupported fallocate mode",
+            )));
+        }
+        let end = offset
+            .checked_add(len)
+            .filter(|end| len != 0 && *end <= inode::MAX_FILE_BLOCKS as u64 * block_size)
+            .ok_or_else(|| std::io::Error::other("Invalid fallocate range"))?;
+
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        check_contents(&inode)?;
+        if !keep_size {
+            self.check_inode_limits(inode.uid(), inode.gid(), end.max(inode.file_size()))?;
+        }
+        // inline files already have the space for anything that stays inline,
+        // anything bigger gets moved out to blocks first
+        if inode.is_inline() && (punch_hole || end as usize <= inode::MAX_INLINE_DATA) {
+            let mut data = inode.inline_data();
+            if punch_hole {
+                let size = data.len() as u64;
+                data[offset.min(size) as usize..end.min(size) as usize].fill(0);
+            } else if !keep_size && end > inode.file_size() {
+                data.resize(end as usize, 0);
+                inode.set_mtime(utils::now());
+            }
+            inode.set_inline_data(&data);
+            inode.set_ctime(utils::now());
+            self.cfs.inode_list.set(inode_idx, inode);
+            self.write_cfs()?;
+            return Ok(());
+        }
+        // the block inline contents move to, given back along with the new ones
+        // should the allocation fail
+        let promoted = match inode.is_inline() {
+            true => {
+                self.promote_inline_data(&mut inode)?;
+                inode.data_block(0)
+            }
+            false => None,
+        };
+        let first = (offset / block_size) as usize;
+        let last = end.div_ceil(block_size) as usize;
+
+        if punch_hole {
+            for n in first..last {
+                let block_idx = match inode.data_block(n) {
+                    Some(block_idx) => block_idx,
+                    None => continue,
+                };
+
+                // whole blocks go away, the edges of the range are zeroed in place
+                let start = offset.saturating_sub(n as u64 * block_size).min(block_size);
+                let stop = (end - n as u64 * block_size).min(block_size);
+                if start == 0 && stop == block_size {
+                    self.cfs.free_block(block_idx);
+                    inode.blkaddr[n + 1] = 0;
+                } else {
+                    let block_idx = self.unshare_block(block_idx)?;
+                    inode.blkaddr[n + 1] = block_idx as u32;
+                    self.zero_block_range(block_idx, start as usize, stop as usize)?;
+                }
+            }
+        } else {
+            let zeroes = vec![0; block_size as usize];
+            let mut goal = inode.block_addrs().max().copied().unwrap_or(0) as usize;
+            let mut taken: Vec<usize> = promoted.into_iter().collect();
+            for n in first..last {
+                if inode.data_block(n).is_some() {
+                    continue;
+                }
+                let result = match self.cfs.alloc_blocks(1, Some(goal)) {
+                    Some(block_idx) => {
+                        taken.push(block_idx);
+                        self.write_block(block_idx, &zeroes).map(|_| block_idx)
+                    }
+                    None => Err(std::io::Error::other("No free blocks").into()),
+                };
+                // the inode isn't written back, so nothing would point to them
+                let block_idx = match result {
+                    Ok(block_idx) => block_idx,
+                    Err(e) => {
+                        for block_idx in taken {
+                            self.cfs.free_block(block_idx);
+                        }
+                        return Err(e);
+                    }
+                };
+                inode.blkaddr[n + 1] = block_idx as u32;
+                goal = block_idx + 1;
+            }
+
+            if !keep_size && end > inode.file_size() {
+                inode.set_file_size(end);
+                inode.set_mtime(utils::now());
+            }
+        }
+
+        inode.set_ctime(utils::now());
+        self.cfs.inode_list.set(inode_idx, inode);
+        self.write_cfs()?;
+        Ok(())
+    }
 
+    // Move inline file contents out to a data block so the file can grow past
+    // MAX_INLINE_DATA, the caller writes the inode back
+    fn promote_inline_data(
+        &mut self,
+        inode: &mut inode::Inode,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let data = inode.inline_data();
+        inode.flags &= !inode::INODE_INLINE_DATA;
+        inode.blkaddr = [0; 10];
+
+        // nothing but zeros is just a hole
+        if data.iter().any(|byte| *byte != 0) {
+            let block_idx = self
+                .cfs
+                .alloc_blocks(1, None)
+                .ok_or_else(|| std::io::Error::other("No free blocks"))?;
+            let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+            buffer[..data.len()].copy_from_slice(&data);
+            self.write_block(block_idx, &buffer)?;
+            inode.blkaddr[1] = block_idx as u32;
+        }
         Ok(())
     }
 
-    pub fn add_file_to_inode(
+    fn zero_block_range(
         &mut self,
-        parent_inode_idx: usize,
+        block_idx: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+        self.read_block(block_idx, &mut buffer)?;
+        buffer[start..end].fill(0);
+        self.write_block(block_idx, &buffer)
+    }
+
+    pub fn getxattr(
+        &mut self,
+        inode_idx: usize,
         name: &str,
-        file: &mut std::fs::File,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.find_xattr(inode_idx, name)?
+            .ok_or_else(|| no_such_xattr().into())
+    }
+
+    // Create or replace an extended attribute, `flags` takes XATTR_CREATE or
+    // XATTR_REPLACE to require the attribute to be missing or present
+    pub fn setxattr(
+        &mut self,
+        inode_idx: usize,
+        name: &str,
+        value: &[u8],
+        flags: u32,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        log::debug!("File {name} added in parent inode {parent_inode_idx}");
-        let metadata: std::fs::Metadata = file.metadata()?;
-        let size = metadata.len();
-        let fmode = metadata.permissions().mode();
-        let uid = metadata.uid();
-        let gid = metadata.gid();
-        let atime = metadata.accessed()?;
-        let mtime = metadata.modified()?;
-        let ctime = metadata.created()?;
+        self.check_writable()?;
+        if name == acl::ACL_ACCESS_XATTR || name == acl::ACL_DEFAULT_XATTR {
+            acl::Acl::decode(value)?;
+        }
 
-        // we need to allocate a new inode for the file
-        let inode_idx = match self.cfs.iam.first_free() {
-            Some(inode_idx) => {
-                self.cfs.iam.set(inode_idx);
-                inode_idx
-            }
-            None => {
+        let (name_index, name) = xattr::split_name(name)?;
+        let mut entries = self.read_xattrs(inode_idx)?;
+        let entry = xattr::XattrEntry::new(name_index, name.as_bytes(), value);
+        match entries
+            .iter()
+            .position(|entry| entry.name_index == name_index && entry.name == name.as_bytes())
+        {
+            Some(_) if flags & xattr::XATTR_CREATE != 0 => {
                 return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No free inodes",
+                    std::io::ErrorKind::AlreadyExists,
+                    "Attribute already exists",
                 )));
             }
-        };
+            Some(position) => entries[position] = entry,
+            None if flags & xattr::XATTR_REPLACE != 0 => return Err(Box::new(no_such_xattr())),
+            None => entries.push(entry),
+        }
+        self.write_xattrs(inode_idx, entries)
+    }
 
-        // we need to allocate the file data blocks
-        // NOTE: No time for indirect blocks
-        let nblocks = (size as f64 / self.cfs.super_block.blocksize as f64).ceil() as usize;
-        let mut blkaddr = [0; 10];
+    pub fn listxattr(
+        &mut self,
+        inode_idx: usize,
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        Ok(self
+            .read_xattrs(inode_idx)?
+            .iter()
+            .map(xattr::XattrEntry::full_name)
+            .collect())
+    }
 
-        for (i, addr) in blkaddr.iter_mut().take(nblocks + 1).enumerate() {
-            if let Some(block_idx) = self.cfs.bam.first_free() {
-                self.cfs.bam.set(block_idx);
-                *addr = block_idx as u32;
-                log::debug!("blkaddr[{}]: {}", i, *addr);
-            } else {
-                return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No free blocks",
-                )));
-            }
+    pub fn removexattr(
+        &mut self,
+        inode_idx: usize,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        let (name_index, name) = xattr::split_name(name)?;
+        let mut entries = self.read_xattrs(inode_idx)?;
+        let count = entries.len();
+        entries.retain(|entry| !(entry.name_index == name_index && entry.name == name.as_bytes()));
+        if entries.len() == count {
+            return Err(Box::new(no_such_xattr()));
         }
+        self.write_xattrs(inode_idx, entries)
+    }
 
-        // now we need to write the file data to the blocks
-        // Remember that the first block in inode.blkaddr is reserved for the dentries
-        let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
-        for addr in blkaddr.iter().take(nblocks + 1).skip(1) {
-            // read the block into the buffer
-            let offset = self.cfs.data_blocks_offset()
-                + (*addr as u64 * self.cfs.super_block.blocksize as u64);
-            self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
-            self.blk_dev.read_exact(&mut buffer)?;
+    fn find_xattr(
+        &mut self,
+        inode_idx: usize,
+        name: &str,
+    ) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+        let (name_index, name) = xattr::split_name(name)?;
+        Ok(self
+            .read_xattrs(inode_idx)?
+            .into_iter()
+            .find(|entry| entry.name_index == name_index && entry.name == name.as_bytes())
+            .map(|entry| entry.value))
+    }
 
-            log::debug!("Writing {name} @ {offset}");
+    // every xattr of an inode, the in-inode ones first
+    fn read_xattrs(
+        &mut self,
+        inode_idx: usize,
+    ) -> Result<Vec<xattr::XattrEntry>, Box<dyn std::error::Error>> {
+        let inode = self.cfs.inode_list.get(inode_idx);
+        let mut entries = xattr::decode(&inode.xattr_space[..self.xattr_space_size()])?;
+        if inode.xattr_block != 0 {
+            let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+            self.read_block(inode.xattr_block as usize, &mut buffer)?;
+            entries.extend(xattr::decode(&buffer)?);
+        }
+        Ok(entries)
+    }
 
-            // write the file data to the buffer
-            let n = file.read(&mut buffer)?;
-            if n == 0 {
-                break;
-            }
+    // Store `entries` in the inode's xattr space, and whatever doesn't fit there in
+    // its xattr block, which is allocated or freed as needed
+    fn write_xattrs(
+        &mut self,
+        inode_idx: usize,
+        mut entries: Vec<xattr::XattrEntry>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        // legacy inodes would lose track of the xattr block on the way to the disk
+        if !entries.is_empty() && !self.cfs.super_block.supports_xattrs() {
+            return Err(Box::new(std::io::Error::other(
+                "Extended attributes need large inodes, see enable_large_inodes",
+            )));
+        }
+        let space_size = self.xattr_space_size();
+        let block_size = self.cfs.super_block.blocksize as usize;
+        let mut in_inode = 0;
+        while in_inode < entries.len() && xattr::area_size(&entries[..=in_inode]) <= space_size {
+            in_inode += 1;
+        }
+        let spilled = entries.split_off(in_inode);
+        if !spilled.is_empty() && xattr::area_size(&spilled) > block_size {
+            return Err(Box::new(std::io::Error::other(
+                "No space left for extended attributes",
+            )));
+        }
 
-            // write the buffer back to the file
-            self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
-            self.blk_dev.write_all(&buffer)?;
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        let space = xattr::encode(&entries, space_size)?;
+        inode.xattr_space[..space_size].copy_from_slice(&space);
+
+        if spilled.is_empty() {
+            if inode.xattr_block != 0 {
+                self.cfs.free_block(inode.xattr_block as usize);
+                inode.xattr_block = 0;
+            }
+        } else {
+            if inode.xattr_block == 0 {
+                let goal = inode.block_addrs().max().map(|addr| *addr as usize);
+                inode.xattr_block = self
+                    .cfs
+                    .alloc_blocks(1, goal)
+                    .ok_or_else(|| std::io::Error::other("No free blocks"))?
+                    as u32;
+            } else {
+                inode.xattr_block = self.unshare_block(inode.xattr_block as usize)? as u32;
+            }
+            let buffer = xattr::encode(&spilled, block_size)?;
+            self.write_block(inode.xattr_block as usize, &buffer)?;
         }
 
-        // now we need to create the inode
-        let inode = inode::Inode::new(
-            fmode as u16,
-            0,
-            uid as u16,
-            gid as u16,
-            size as u32,
-            atime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            mtime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            ctime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            blkaddr,
-        );
+        inode.set_ctime(utils::now());
         self.cfs.inode_list.set(inode_idx, inode);
+        self.write_cfs()?;
+        Ok(())
+    }
 
-        // add dentry to parent inode
-        log::debug!("parent_inode_idx: {}", parent_inode_idx);
-        log::debug!("name: {}", name);
-        log::debug!("inode_idx: {}", inode_idx);
-        self.add_dentry_to_inode(parent_inode_idx, name, inode_idx)?;
+    // how much of an inode record is left for xattrs
+    fn xattr_space_size(&self) -> usize {
+        self.cfs
+            .super_block
+            .inode_size()
+            .clamp(inode::INODE_SIZE, inode::MAX_INODE_SIZE) as usize
+            - inode::INODE_SIZE as usize
+    }
 
-        Ok(())
+    pub fn get_acl(
+        &mut self,
+        inode_idx: usize,
+        acl_type: acl::AclType,
+    ) -> Result<Option<acl::Acl>, Box<dyn std::error::Error>> {
+        match self.find_xattr(inode_idx, acl_type.xattr_name())? {
+            Some(value) => Ok(Some(acl::Acl::decode(&value)?)),
+            None => Ok(None),
+        }
     }
 
-    // This function is used ub the same way as add_file_to_inode
-    // but it just add a directory instead of a file
-    pub fn add_dir_to_inode(
+    // Set or with `None` remove an ACL. The access ACL and the permission bits of the
+    // mode are kept in sync, an ACL the mode alone can express is not stored at all.
+    pub fn set_acl(
         &mut self,
-        parent_inode_idx: usize,
-        name: &str,
+        inode_idx: usize,
+        acl_type: acl::AclType,
+        acl: Option<&acl::Acl>,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let size = 0;
-        let fmode = 0o755;
-        let uid = std::process::id();
-        let gid = std::process::id();
-        let atime = std::time::SystemTime::now();
-        let mtime = atime;
-        let ctime = atime;
+        self.check_writable()?;
+        let name = acl_type.xattr_name();
+        if let (acl::AclType::Access, Some(acl)) = (acl_type, acl) {
+            let mut inode = self.cfs.inode_list.get(inode_idx);
+            inode.mode = (inode.mode & !0o777) | acl.mode_bits();
+            inode.set_ctime(utils::now());
+            self.cfs.inode_list.set(inode_idx, inode);
+        }
 
-        // we need to allocate a new inode for the uppcomming directory
-        let inode_idx = match self.cfs.iam.first_free() {
-            Some(inode_idx) => {
-                self.cfs.iam.set(inode_idx);
-                inode_idx
+        match acl {
+            Some(acl) if acl_type == acl::AclType::Default || !acl.is_minimal() => {
+                self.setxattr(inode_idx, name, &acl.encode()?, 0)
             }
-            None => {
+            _ if self.find_xattr(inode_idx, name)?.is_some() => self.removexattr(inode_idx, name),
+            _ => self.write_cfs(),
+        }
+    }
+
+    // Set the access and modification times like utimensat(2), `None` leaves one as
+    // it is (UTIME_OMIT). The change time is always bumped to now.
+    pub fn utimens(
+        &mut self,
+        inode_idx: usize,
+        atime: Option<utils::Timespec>,
+        mtime: Option<utils::Timespec>,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        check_nsec(&[atime, mtime])?;
+
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        if let Some(atime) = atime {
+            inode.set_atime(atime);
+        }
+        if let Some(mtime) = mtime {
+            inode.set_mtime(mtime);
+        }
+        inode.set_ctime(utils::now());
+        self.cfs.inode_list.set(inode_idx, inode);
+        self.write_cfs()
+    }
+
+    // chmod, chown, truncate and utimes in one go. The new owner and times are
+    // checked before anything changes, and the change time is bumped to now.
+    pub fn setattr(
+        &mut self,
+        inode_idx: usize,
+        attr: &inode::SetAttr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
+        check_nsec(&[attr.atime, attr.mtime])?;
+        let inode = self.cfs.inode_list.get(inode_idx);
+        self.check_inode_limits(
+            attr.uid.unwrap_or(inode.uid()),
+            attr.gid.unwrap_or(inode.gid()),
+            attr.size.unwrap_or(inode.file_size()),
+        )?;
+
+        if let Some(size) = attr.size {
+            if inode.file_type() != Some(inode::FileType::Regular) {
                 return Err(Box::new(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No free inodes",
+                    std::io::ErrorKind::InvalidInput,
+                    "Only regular files can be truncated",
                 )));
             }
-        };
-
-        // The dir does not have any data blocks, so we just set the blkaddr to 0
-        let mut blkaddr = [0; 10];
-        if let Some(block_idx) = self.cfs.bam.first_free() {
-            self.cfs.bam.set(block_idx);
-            blkaddr[0] = block_idx as u32;
-            log::debug!("blkaddr[0]: {}", blkaddr[0]);
-        } else {
-            return Err(Box::new(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "No free blocks",
-            )));
+            self.truncate(inode_idx, size)?;
         }
 
-        // now we need to create the inode
-        let inode = inode::Inode::new(
-            fmode as u16,
-            0,
-            uid as u16,
-            gid as u16,
-            size as u32,
-            atime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            mtime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            ctime.duration_since(UNIX_EPOCH)?.as_secs() as u32,
-            blkaddr,
-        );
+        let mut inode = self.cfs.inode_list.get(inode_idx);
+        if let Some(mode) = attr.mode {
+            inode.mode = (inode.mode & inode::S_IFMT) | (mode & !inode::S_IFMT);
+        }
+        let chown = attr.uid.is_some_and(|uid| uid != inode.uid())
+            || attr.gid.is_some_and(|gid| gid != inode.gid());
+        if let Some(uid) = attr.uid {
+            inode.set_uid(uid);
+        }
+        if let Some(gid) = attr.gid {
+            inode.set_gid(gid);
+        }
+        // like Linux, a new owner drops setuid, and setgid unless it only marks
+        // mandatory locking (no group execute)
+        if chown && !inode.is_dir() {
+            inode.mode &= !inode::S_ISUID;
+            if inode.mode & 0o010 != 0 {
+                inode.mode &= !inode::S_ISGID;
+            }
+        }
+        if let Some(atime) = attr.atime {
+            inode.set_atime(atime);
+        }
+        if let Some(mtime) = attr.mtime {
+            inode.set_mtime(mtime);
+        }
+        inode.set_ctime(utils::now());
+        let mode = inode.mode;
         self.cfs.inode_list.set(inode_idx, inode);
 
-        // add dentry to parent inode
-        log::debug!("parent_inode_idx: {}", parent_inode_idx);
-        log::debug!("name: {}", name);
-        log::debug!("inode_idx: {}", inode_idx);
-        self.add_dentry_to_inode(parent_inode_idx, name, inode_idx)?;
+        // the access ACL follows the new permission bits
+        if attr.mode.is_some() {
+            if let Some(mut acl) = self.get_acl(inode_idx, acl::AclType::Access)? {
+                acl.apply_mode(mode);
+                return self.set_acl(inode_idx, acl::AclType::Access, Some(&acl));
+            }
+        }
+        self.write_cfs()
+    }
 
-        Ok(())
+    pub fn stat(&mut self, inode_idx: usize) -> Result<inode::Stat, Box<dyn std::error::Error>> {
+        let inode = self.cfs.inode_list.get(inode_idx);
+
+        // a directory is linked from its parent, its own "." and the ".." of every
+        // subdirectory, everything else has a single entry
+        let nlink = if inode.is_dir() {
+            let mut subdirs = 0;
+            for item in self.read_dir(inode_idx) {
+                let item = item?;
+                if item.file_type == Some(inode::FileType::Directory)
+                    && item.name != "."
+                    && item.name != ".."
+                {
+                    subdirs += 1;
+                }
+            }
+            2 + subdirs
+        } else {
+            1
+        };
+
+        // the root directory's entries are in data block 0, which block_addrs
+        // can't tell apart from no block at all (snapshots keep a copy elsewhere)
+        let mut blocks = inode.block_addrs().count();
+        if inode_idx == crate::ROOT_INODE && inode.blkaddr[0] == 0 {
+            blocks += 1;
+        }
+
+        Ok(inode::Stat {
+            inode: inode_idx as u32,
+            file_type: inode.file_type(),
+            perm: inode.mode & !inode::S_IFMT,
+            nlink,
+            uid: inode.uid(),
+            gid: inode.gid(),
+            size: inode.file_size(),
+            blocks: blocks as u64,
+            block_size: self.cfs.super_block.blocksize as u64,
+            rdev: inode.rdev,
+            atime: inode.atime(),
+            mtime: inode.mtime(),
+            ctime: inode.ctime(),
+            crtime: inode.crtime(),
+        })
     }
 
-    // This function is used to get the file data from the inode data blocks
-    pub fn get_data_from_inode(
+    // Whether `uid`, member of `gids`, may access the inode with the ACL_READ,
+    // ACL_WRITE and ACL_EXECUTE bits of `mask`, like access(2) does. Root can do
+    // anything but execute files nobody can execute, directories can always be
+    // searched.
+    pub fn access(
         &mut self,
         inode_idx: usize,
-    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        // get the inode from the inode list
+        uid: u32,
+        gids: &[u32],
+        mask: u16,
+    ) -> Result<bool, Box<dyn std::error::Error>> {
         let inode = self.cfs.inode_list.get(inode_idx);
+        if uid == 0 {
+            return Ok(mask & acl::ACL_EXECUTE == 0 || inode.is_dir() || inode.mode & 0o111 != 0);
+        }
 
-        log::debug!("inode: {:?}", inode);
-
-        // the number of blocks that the file is using (ceil(size / blocksize)
-        let size = (inode.size as f64 / self.cfs.super_block.blocksize as f64).ceil() as u32;
-        log::debug!("size: {}", size);
-
-        // the buffer that we will read the file data into
-        let mut buffer = vec![0; inode.size as usize];
+        let acl = match self.get_acl(inode_idx, acl::AclType::Access)? {
+            Some(acl) => acl,
+            None => acl::Acl::from_mode(inode.mode),
+        };
+        Ok(acl.permits(inode.uid(), inode.gid(), uid, gids, mask))
+    }
 
-        let mut ret = Vec::new();
+    // A new inode takes its access ACL from its parent's default ACL, new
+    // directories also pass the default ACL on
+    fn inherit_acl(
+        &mut self,
+        parent_inode_idx: usize,
+        inode_idx: usize,
+        is_dir: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let default_acl = match self.get_acl(parent_inode_idx, acl::AclType::Default)? {
+            Some(acl) => acl,
+            None => return Ok(()),
+        };
 
-        // read the data blocks into the buffer
-        for i in 1..=size {
-            // read the block into the buffer
-            let offset = self.cfs.data_blocks_offset()
-                + (inode.blkaddr[i as usize] as u64 * self.cfs.super_block.blocksize as u64);
-            self.blk_dev.seek(std::io::SeekFrom::Start(offset))?;
-            self.blk_dev.read_exact(&mut buffer)?;
-            ret.extend_from_slice(&buffer);
+        let mode = self.cfs.inode_list.get(inode_idx).mode;
+        let access_acl = default_acl.inherit(mode);
+        self.set_acl(inode_idx, acl::AclType::Access, Some(&access_acl))?;
+        if is_dir {
+            self.set_acl(inode_idx, acl::AclType::Default, Some(&default_acl))?;
         }
-
-        Ok(ret)
+        Ok(())
     }
 
     pub fn remove_inode(&mut self, inode_idx: usize) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
         // get the inode from the inode list
         let inode = self.cfs.inode_list.get(inode_idx);
 
         // free the inode
-        self.cfs.iam.clear(inode_idx);
-        self.cfs.inode_list.clear(inode_idx);
+        self.cfs.free_inode(inode_idx);
 
         // free the data blocks that the inode points to
-        let nblocks = inode.size / self.cfs.super_block.blocksize;
-        for i in 0..nblocks {
-            self.cfs.bam.clear(inode.blkaddr[i as usize] as usize);
+        for addr in inode.block_addrs() {
+            self.cfs.free_block(*addr as usize);
         }
 
         self.write_cfs()?;
@@ -378,6 +2389,20 @@ impl CfsPartition {
         Ok(())
     }
 
+    // Undo a create that failed halfway: the new inode never made it into a
+    // directory, so it's freed along with whatever blocks it got. Hands `e` back.
+    pub(crate) fn discard_inode(
+        &mut self,
+        inode_idx: usize,
+        e: Box<dyn std::error::Error>,
+    ) -> Box<dyn std::error::Error> {
+        log::debug!("Discarding inode {inode_idx}: {e}");
+        if let Err(undo) = self.remove_inode(inode_idx) {
+            log::error!("Failed to free inode {inode_idx}: {undo}");
+        }
+        e
+    }
+
     // This function will delete a dentry from the inode,
     // and also delete the inode
     pub fn remove_dir_from_inode(
@@ -385,33 +2410,32 @@ impl CfsPartition {
         parent_inode_idx: usize,
         inode_idx: u32,
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let dentries = self.list_dentries_from_inode(parent_inode_idx)?;
-
-        // find the dentry that we want to remove, filter it, and map it to a new vector, and map it to a new vector of u8s,
-        // and then write it to the data block
-
-        let mut buffer: Vec<u8> = dentries
-            .into_iter()
-            .filter(|x| x.inode != inode_idx)
-            .map(|x| x.to_bytes())
-            .collect::<Result<Vec<_>, _>>()?
-            .into_iter()
-            .flatten()
-            .collect();
-
-        // pad the buffer with 0s
-        buffer.resize(self.cfs.super_block.blocksize as usize, 0);
-
-        // write the dentries to the data block
+        self.check_writable()?;
         let mut inode = self.cfs.inode_list.get(parent_inode_idx);
         let data_block_idx = inode.blkaddr[0] as usize;
-        let index = self.cfs.data_blocks_offset()
-            + data_block_idx as u64 * self.cfs.super_block.blocksize as u64;
-        std::io::Seek::seek(&mut self.blk_dev, std::io::SeekFrom::Start(index))?;
-        std::io::Write::write_all(&mut self.blk_dev, &buffer)?;
+        let mut buffer = vec![0; self.cfs.super_block.blocksize as usize];
+        self.read_block(data_block_idx, &mut buffer)?;
+
+        // The dentry's slot is cleared rather than the others moved down, so the
+        // positions handed out by read_dir stay valid. Free slots at the end are
+        // dropped altogether.
+        let nchildren = inode.nchildren as usize;
+        let slot = (0..nchildren)
+            .find(|slot| dentry_slot_inode(&buffer, *slot) == inode_idx)
+            .ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, "No such directory entry")
+            })?;
+        buffer[slot * DIR_ENTRY_SIZE..(slot + 1) * DIR_ENTRY_SIZE].fill(0);
+        inode.blkaddr[0] = self.unshare_block(data_block_idx)? as u32;
+        self.write_block(inode.blkaddr[0] as usize, &buffer)?;
 
         // update the parent inode
-        inode.nchildren -= 1;
+        inode.nchildren = (0..nchildren)
+            .rev()
+            .find(|slot| dentry_slot_inode(&buffer, *slot) != inode::BAD_INODE)
+            .map_or(0, |slot| slot as u16 + 1);
+        inode.set_mtime(utils::now());
+        inode.set_ctime(inode.mtime());
         self.cfs.inode_list.set(parent_inode_idx, inode);
 
         // remove the inode
@@ -432,32 +2456,53 @@ impl CfsPartition {
         // the dentrty is stored in the inode data block 0
         let data_block_idx = inode.blkaddr[0] as usize;
         log::debug!("data_block_idx: {}", data_block_idx);
-        let index = self.cfs.data_blocks_offset()
-            + data_block_idx as u64 * self.cfs.super_block.blocksize as u64;
         let mut buf = vec![0; self.cfs.super_block.blocksize as usize];
-
-        // seek and read the data block
-        std::io::Seek::seek(&mut self.blk_dev, std::io::SeekFrom::Start(index))?;
-        std::io::Read::read_exact(&mut self.blk_dev, &mut buf)?;
+        self.read_block(data_block_idx, &mut buf)?;
 
         // the nunmber of dentries in the data block is in inode.nchildren
         let nchildren = inode.nchildren as usize;
 
-        // convert the buffer to a vector of dentries
-        let dentries = buf
-            .chunks_exact(std::mem::size_of::<dir_entry::DirEntry>())
+        // convert the buffer to a vector of dentries, skipping the free slots
+        let typed = self.has_typed_dentries();
+        let mut dentries = buf
+            .chunks_exact(DIR_ENTRY_SIZE)
             .to_owned()
             .take(nchildren)
             .map(|chunk| {
-                dir_entry::DirEntry::try_from(chunk)
-                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+                dir_entry::DirEntry::from_bytes_typed(chunk, typed).map_err(std::io::Error::other)
             })
+            .filter(|dentry| !matches!(dentry, Ok(dentry) if dentry.inode == inode::BAD_INODE))
             .collect::<Result<Vec<dir_entry::DirEntry>, _>>()?;
 
+        // older images don't keep the type in the dentries, look at the inodes then
+        if !typed {
+            for dentry in dentries.iter_mut() {
+                let file_type = self.cfs.inode_list.get(dentry.inode as usize).file_type();
+                dentry.file_type = dir_entry::file_type_code(file_type);
+            }
+        }
+
         Ok(dentries)
     }
 
+    // Iterate over a directory, see dir::ReadDir
+    pub fn read_dir(&mut self, inode_idx: usize) -> dir::ReadDir<'_> {
+        dir::ReadDir::new(self, inode_idx, 0)
+    }
+
+    // Same as read_dir, but resuming from a cookie of a previous dir::DirItem
+    pub fn read_dir_from(&mut self, inode_idx: usize, cookie: u64) -> dir::ReadDir<'_> {
+        dir::ReadDir::new(self, inode_idx, cookie)
+    }
+
+    pub(crate) fn has_typed_dentries(&self) -> bool {
+        self.cfs
+            .super_block
+            .has_feature(superblock::FEATURE_DIR_FILE_TYPE)
+    }
+
     pub fn setup_root_dir(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.check_writable()?;
         self.add_dentry_to_inode(crate::ROOT_INODE, ".", 1)?;
         self.add_dentry_to_inode(crate::ROOT_INODE, "..", 1)?;
         Ok(())
@@ -467,21 +2512,354 @@ impl CfsPartition {
 // 💨
 impl Drop for CfsPartition {
     fn drop(&mut self) {
-        self.blk_dev.sync_all().unwrap();
+        if self.read_only {
+            return;
+        }
+        if let Err(e) = self.unmount() {
+            log::error!("Failed to mark the image clean: {e}");
+        }
+        if let Err(e) = self.blk_dev.sync_all() {
+            log::error!("Failed to sync the device: {e}");
+        }
     }
 }
 
 impl TryFrom<std::fs::File> for CfsPartition {
     type Error = Box<dyn std::error::Error>;
 
-    fn try_from(mut blk_dev: std::fs::File) -> Result<Self, Self::Error> {
-        let mut buffer = vec![0; DEFAULT_BLOCK_SIZE as usize];
-        blk_dev.seek(std::io::SeekFrom::Start(0))?;
+    fn try_from(blk_dev: std::fs::File) -> Result<Self, Self::Error> {
+        Self::open_with_policy(blk_dev, |_| Ok(()))
+    }
+}
+
+// A host file about to be copied in: its inode, not allocated yet, and its
+// contents split in blocks (none when they fit inline in the inode)
+pub(crate) struct HostFile {
+    pub inode: inode::Inode,
+    pub contents: Vec<Vec<u8>>,
+}
+
+// Read a host file up front, taking its mode, owner and times along
+pub(crate) fn read_host_file(
+    file: &mut std::fs::File,
+    block_size: usize,
+    inline: bool,
+) -> Result<HostFile, Box<dyn std::error::Error>> {
+    let metadata: std::fs::Metadata = file.metadata()?;
+    let size = metadata.len();
+    // NOTE: No time for indirect blocks
+    if size > (inode::MAX_FILE_BLOCKS * block_size) as u64 {
+        return Err(Box::new(std::io::Error::other("File too large")));
+    }
+    let fmode = metadata.permissions().mode();
+    let atime = utils::Timespec::new(metadata.atime(), metadata.atime_nsec() as u32);
+    let mtime = utils::Timespec::new(metadata.mtime(), metadata.mtime_nsec() as u32);
+    let ctime = utils::Timespec::new(metadata.ctime(), metadata.ctime_nsec() as u32);
+    let mut inode = inode::Inode::new(
+        fmode as u16,
+        0,
+        metadata.uid(),
+        metadata.gid(),
+        size,
+        atime,
+        mtime,
+        ctime,
+        [0; 10],
+    );
+    // the times come from the host file, but the inode is born here
+    inode.set_crtime(utils::now());
+
+    // tiny files don't need any data block, their contents go in blkaddr
+    if size as usize <= inode::MAX_INLINE_DATA && inline {
+        let mut buffer = Vec::with_capacity(size as usize);
+        file.read_to_end(&mut buffer)?;
+        buffer.truncate(size as usize);
+        inode.set_inline_data(&buffer);
+        return Ok(HostFile {
+            inode,
+            contents: Vec::new(),
+        });
+    }
+
+    let nblocks = (size as usize).div_ceil(block_size);
+    let mut contents = Vec::with_capacity(nblocks);
+    for _ in 0..nblocks {
+        let mut buffer = Vec::with_capacity(block_size);
+        (&mut *file)
+            .take(block_size as u64)
+            .read_to_end(&mut buffer)?;
+        buffer.resize(block_size, 0);
+        contents.push(buffer);
+    }
+    Ok(HostFile { inode, contents })
+}
+
+// Read the metadata of an existing image without writing anything
+fn load(mut blk_dev: std::fs::File) -> Result<CfsPartition, Box<dyn std::error::Error>> {
+    let super_block = match read_super_block(&mut blk_dev, 0) {
+        Ok(super_block) => super_block,
+        Err(e) => {
+            log::warn!("Primary super block is damaged ({e}), looking for a backup");
+            find_backup_super_block(&mut blk_dev)?
+        }
+    };
+
+    let mut cfs = match super_block.has_feature(superblock::FEATURE_BLOCK_GROUPS) {
+        true => read_block_groups(&mut blk_dev, super_block)?,
+        false => read_flat(&mut blk_dev, super_block)?,
+    };
+    blk_dev.seek(std::io::SeekFrom::Start(0))?;
+
+    // The bitmaps are what counts: images from before the free counts have them
+    // zeroed, and a crash between writing the two leaves them off
+    if cfs.update_free_counts() {
+        log::warn!("Free counts didn't match the bitmaps, recounted them");
+    }
+
+    let mut partition = CfsPartition {
+        blk_dev,
+        cfs,
+        read_only: false,
+        mounted: false,
+        cache: None,
+        shared: false,
+        journals: Vec::new(),
+    };
+    let refcounts = partition.read_chain(partition.cfs.super_block.refcount_block)?;
+    partition.cfs.refcounts = snapshot::decode_refcounts(&refcounts)?;
+    partition.cfs.stored_refcounts = partition.cfs.refcounts.clone();
+    Ok(partition)
+}
+
+// a layout must have room for at least one super block backup, see backup_blocks
+fn check_backup_blocks(cfs: &Cfs) -> Result<(), std::io::Error> {
+    if cfs.backup_blocks().is_empty() {
+        return Err(std::io::Error::other(
+            "No room for a super block backup in this layout",
+        ));
+    }
+    Ok(())
+}
+
+// only regular files have contents to write, truncate or allocate
+fn check_contents(inode: &inode::Inode) -> Result<(), std::io::Error> {
+    match inode.file_type() {
+        Some(inode::FileType::Regular) => Ok(()),
+        Some(inode::FileType::Directory) => Err(std::io::Error::new(
+            std::io::ErrorKind::IsADirectory,
+            "Is a directory",
+        )),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Not a regular file",
+        )),
+    }
+}
+
+// only regular files have blocks that can be shared with clone_file and clone_range
+fn check_regular(inode: &inode::Inode) -> Result<(), std::io::Error> {
+    if inode.file_type() != Some(inode::FileType::Regular) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Only regular files can be cloned",
+        ));
+    }
+    Ok(())
+}
+
+fn no_such_snapshot(name: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No such snapshot: {name}"),
+    )
+}
+
+// on-disk size of a dentry, the file type doesn't take any room of its own
+pub(crate) const DIR_ENTRY_SIZE: usize = dir_entry::MAX_NAME_LEN + 4;
+
+fn check_nsec(times: &[Option<utils::Timespec>]) -> Result<(), std::io::Error> {
+    if times
+        .iter()
+        .flatten()
+        .any(|time| time.nsec >= 1_000_000_000)
+    {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "Invalid nanoseconds",
+        ));
+    }
+    Ok(())
+}
+
+// the inode a dentry slot of a directory block points to, BAD_INODE when free
+pub(crate) fn dentry_slot_inode(block: &[u8], slot: usize) -> u32 {
+    let offset = slot * DIR_ENTRY_SIZE + dir_entry::MAX_NAME_LEN;
+    u32::from_le_bytes([
+        block[offset],
+        block[offset + 1],
+        block[offset + 2],
+        block[offset + 3],
+    ])
+}
+
+// what getxattr reports as ENODATA
+fn no_such_xattr() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "No such attribute")
+}
+
+// The metadata goes from the super block up to the first data block, the super
+// block itself is taken from whichever copy turned out to be valid
+fn read_flat(
+    blk_dev: &mut std::fs::File,
+    super_block: superblock::SuperBlock,
+) -> Result<Cfs, Box<dyn std::error::Error>> {
+    let block_size = super_block.blocksize as u64;
+    let metadata_blocks = RESERVED_BLOCKS
+        + super_block.bam_blocks as u64
+        + super_block.iam_blocks as u64
+        + super_block.inode_blocks as u64;
+    let mut buffer = super_block.to_bytes()?;
+    buffer.resize((metadata_blocks * block_size) as usize, 0);
+    blk_dev.seek(std::io::SeekFrom::Start(block_size))?;
+    blk_dev.read_exact(&mut buffer[block_size as usize..])?;
+
+    let (_, cfs) = Cfs::from_bytes((buffer.as_ref(), 0))?;
+    Ok(cfs)
+}
+
+// Gather every group's slice of the bitmaps and inode table back into whole ones
+fn read_block_groups(
+    blk_dev: &mut std::fs::File,
+    super_block: superblock::SuperBlock,
+) -> Result<Cfs, Box<dyn std::error::Error>> {
+    let block_size = super_block.blocksize as usize;
+    let groups = super_block.groups as usize;
+    let blocks_per_group = super_block.blocks_per_group as usize;
+    let inodes_per_group = super_block.inodes_per_group as usize;
+    let inode_size = super_block.inode_size() as usize;
+
+    let mut cfs = Cfs::new(
+        super_block,
+        bitmap::Bam::new(groups * blocks_per_group / 8),
+        bitmap::Iam::new(groups * inodes_per_group / 8),
+        inode::InodeList::default(),
+    );
+
+    let mut buffer = vec![0; groups * group::GROUP_DESC_SIZE as usize];
+    blk_dev.seek(std::io::SeekFrom::Start(
+        RESERVED_BLOCKS * block_size as u64,
+    ))?;
+    blk_dev.read_exact(&mut buffer)?;
+    let group_descs = buffer
+        .chunks_exact(group::GROUP_DESC_SIZE as usize)
+        .map(group::GroupDesc::try_from)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let mut inodes = Vec::with_capacity(groups * inodes_per_group);
+    let mut buffer = vec![0; cfs.group_metadata_blocks() as usize * block_size];
+    for (group, group_desc) in group_descs.iter().enumerate() {
+        let start = cfs.group_start_block(group);
+        if group_desc.block_bitmap as u64 != start {
+            return Err(Box::new(std::io::Error::other(format!(
+                "Group {group} descriptor doesn't match the layout"
+            ))));
+        }
+
+        blk_dev.seek(std::io::SeekFrom::Start(start * block_size as u64))?;
         blk_dev.read_exact(&mut buffer)?;
-        blk_dev.seek(std::io::SeekFrom::Start(0))?;
 
-        let (_, cfs) = Cfs::from_bytes((buffer.as_ref(), 0))?;
+        let bam_bytes = group * blocks_per_group / 8..(group + 1) * blocks_per_group / 8;
+        let iam_bytes = group * inodes_per_group / 8..(group + 1) * inodes_per_group / 8;
+        cfs.bam.data[bam_bytes.clone()].copy_from_slice(&buffer[..bam_bytes.len()]);
+        cfs.iam.data[iam_bytes.clone()]
+            .copy_from_slice(&buffer[block_size..block_size + iam_bytes.len()]);
+        for chunk in buffer[2 * block_size..]
+            .chunks_exact(inode_size)
+            .take(inodes_per_group)
+        {
+            inodes.push(inode::Inode::from_bytes_sized(chunk)?);
+        }
+    }
+    cfs.inode_list = inode::InodeList::from_inodes(inodes);
+    cfs.group_descs = group_descs;
+
+    Ok(cfs)
+}
+
+// How many groups fit in `nblocks`, the last one needs room for its metadata and at
+// least one data block
+fn block_group_count(nblocks: u64, gdt_blocks: u64, group_span: u64, blocks_per_group: u64) -> u64 {
+    let available = nblocks.saturating_sub(RESERVED_BLOCKS + gdt_blocks);
+    let groups = available.div_ceil(group_span);
+    if groups > 0 && available - (groups - 1) * group_span <= group_span - blocks_per_group {
+        return groups - 1;
+    }
+    groups
+}
+
+fn read_super_block(
+    blk_dev: &mut std::fs::File,
+    offset: u64,
+) -> Result<superblock::SuperBlock, Box<dyn std::error::Error>> {
+    // magic and blocksize come first, we need the latter to know how much to read
+    let mut header = [0; 8];
+    blk_dev.seek(std::io::SeekFrom::Start(offset))?;
+    blk_dev.read_exact(&mut header)?;
+    let block_size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    if !block_size.is_power_of_two() || !(512..=65536).contains(&block_size) {
+        return Err(Box::new(std::io::Error::other("Invalid block size")));
+    }
+
+    let mut buffer = vec![0; block_size as usize];
+    blk_dev.seek(std::io::SeekFrom::Start(offset))?;
+    blk_dev.read_exact(&mut buffer)?;
+
+    let (_, super_block) = superblock::SuperBlock::from_bytes((buffer.as_ref(), 0))?;
+    if !super_block.is_valid() {
+        return Err(Box::new(std::io::Error::other("Bad super block")));
+    }
+
+    Ok(super_block)
+}
+
+// The backups are placed relative to the number of blocks in the device, which we
+// can only guess from its size, so try every sensible block size
+// in bytes, the metadata of a block device says 0
+fn device_size(blk_dev: &mut std::fs::File) -> Result<u64, Box<dyn std::error::Error>> {
+    let metadata = blk_dev.metadata()?;
+    if metadata.is_file() {
+        return Ok(metadata.len());
+    }
+    let size = blk_dev.seek(std::io::SeekFrom::End(0))?;
+    blk_dev.seek(std::io::SeekFrom::Start(0))?;
+    Ok(size)
+}
 
-        Ok(CfsPartition { blk_dev, cfs })
+fn find_backup_super_block(
+    blk_dev: &mut std::fs::File,
+) -> Result<superblock::SuperBlock, Box<dyn std::error::Error>> {
+    let size = device_size(blk_dev)?;
+    let block_sizes = std::iter::once(DEFAULT_BLOCK_SIZE as u64)
+        .chain((9..=16).map(|shift| 1 << shift))
+        .filter(|block_size| *block_size <= size);
+
+    for block_size in block_sizes {
+        for location in superblock::backup_locations(size / block_size) {
+            let super_block = match read_super_block(blk_dev, location * block_size) {
+                Ok(super_block) => super_block,
+                Err(_) => continue,
+            };
+
+            if super_block.blocksize as u64 == block_size
+                && superblock::backup_locations(super_block.nblocks as u64).contains(&location)
+            {
+                log::warn!("Using backup super block @ block {location}");
+                return Ok(super_block);
+            }
+        }
     }
+
+    Err(Box::new(std::io::Error::other(
+        "No valid super block found",
+    )))
 }
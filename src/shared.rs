@@ -0,0 +1,440 @@
+use std::{
+    os::unix::fs::FileExt,
+    sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError, RwLock},
+};
+
+use crate::{
+    dir::DirItem,
+    inode,
+    partition::{self, CfsPartition},
+};
+
+// how many locks the inodes are spread over
+const INODE_LOCK_STRIPES: usize = 64;
+
+// A CfsPartition that can be shared between threads, clones are handles to the
+// same partition.
+//
+// The partition itself, which owns the bitmaps, the inode table and the super
+// block, sits behind one mutex that every operation holds only as long as it
+// touches metadata. File contents are read and written outside of it through a
+// second handle on the device with positioned I/O, so reads of different files
+// (or the same one) run in parallel, and creates only contend for the short
+// allocation and linking steps.
+//
+// Blocks can only be freed by whoever holds the write lock of their inode, which
+// readers hold shared while they read, so a file can't be truncated or removed
+// under a reader's feet. Inodes are spread over INODE_LOCK_STRIPES locks and an
+// operation takes at most one of them before the mutex, except `exclusive` which
+// takes them all in order, so they can't deadlock. A file being created has no
+// lock of its own yet, its blocks are kept in place by holding `creates` shared
+// from the allocation until the contents are written, `exclusive` takes it last.
+#[derive(Clone)]
+pub struct SharedPartition {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    partition: Mutex<CfsPartition>,
+    data_dev: std::fs::File,
+    inode_locks: Vec<InodeLock>,
+    // see add_file_to_inode
+    creates: RwLock<()>,
+}
+
+// What a read of a file comes down to, see SharedPartition::plan_read
+pub(crate) enum Extent {
+    // bytes known without going to the device: inline contents, a hole, or
+    // nothing at all past the end
+    Data(Vec<u8>),
+    // `len` bytes of the device at `offset`
+    Device { offset: u64, len: usize },
+}
+
+// A reader/writer lock over a stripe of inodes. Unlike an RwLock's, its guards
+// own a handle on the partition instead of borrowing it, so the async API can
+// hold one across an await. Waiting writers go first.
+#[derive(Default)]
+struct InodeLock {
+    state: Mutex<LockState>,
+    released: Condvar,
+}
+
+#[derive(Default)]
+struct LockState {
+    readers: usize,
+    writer: bool,
+    writers_waiting: usize,
+}
+
+// Held while reading (or writing) the contents of an inode, see SharedPartition
+pub(crate) struct InodeGuard {
+    inner: Arc<Inner>,
+    stripe: usize,
+    write: bool,
+}
+
+impl InodeLock {
+    fn lock(&self, write: bool) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if write {
+            state.writers_waiting += 1;
+            while state.writer || state.readers > 0 {
+                state = self
+                    .released
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            state.writers_waiting -= 1;
+            state.writer = true;
+        } else {
+            while state.writer || state.writers_waiting > 0 {
+                state = self
+                    .released
+                    .wait(state)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+            state.readers += 1;
+        }
+    }
+
+    fn unlock(&self, write: bool) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        if write {
+            state.writer = false;
+        } else {
+            state.readers -= 1;
+        }
+        self.released.notify_all();
+    }
+}
+
+impl Drop for InodeGuard {
+    fn drop(&mut self) {
+        self.inner.inode_locks[self.stripe].unlock(self.write);
+    }
+}
+
+impl SharedPartition {
+    pub fn new(mut partition: CfsPartition) -> Result<Self, Box<dyn std::error::Error>> {
+        // file contents go around the partition, a block cache would go stale
+        partition.set_block_cache(None)?;
+        partition.shared = true;
+        let data_dev = partition.blk_dev.try_clone()?;
+        Ok(Self {
+            inner: Arc::new(Inner {
+                partition: Mutex::new(partition),
+                data_dev,
+                inode_locks: (0..INODE_LOCK_STRIPES)
+                    .map(|_| InodeLock::default())
+                    .collect(),
+                creates: RwLock::new(()),
+            }),
+        })
+    }
+
+    // Run `f` with the partition all to itself, for anything not covered here like
+    // resize. Every inode lock is held, so it may move or free blocks. It can't
+    // turn the block cache back on.
+    pub fn exclusive<R>(&self, f: impl FnOnce(&mut CfsPartition) -> R) -> R {
+        let _locks: Vec<_> = (0..INODE_LOCK_STRIPES)
+            .map(|inode_idx| self.write_inode(inode_idx))
+            .collect();
+        let _creates = self
+            .inner
+            .creates
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        f(&mut self.partition())
+    }
+
+    // Read from a file at `offset` like pread(2), holes read back as zeros
+    pub fn read_at(&self, inode_idx: usize, offset: u64, buf: &mut [u8]) -> std::io::Result<usize> {
+        let _lock = self.read_inode(inode_idx);
+        self.read_at_locked(inode_idx, offset, buf)
+    }
+
+    pub fn get_data_from_inode(
+        &self,
+        inode_idx: usize,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let _lock = self.read_inode(inode_idx);
+        let mut data = Vec::new();
+        let mut buf = vec![0; self.partition().cfs.super_block.blocksize as usize];
+        loop {
+            let len = self.read_at_locked(inode_idx, data.len() as u64, &mut buf)?;
+            if len == 0 {
+                return Ok(data);
+            }
+            data.extend_from_slice(&buf[..len]);
+        }
+    }
+
+    pub fn read_dir(&self, inode_idx: usize) -> std::io::Result<Vec<DirItem>> {
+        self.partition().read_dir(inode_idx).collect()
+    }
+
+    pub fn stat(&self, inode_idx: usize) -> Result<inode::Stat, Box<dyn std::error::Error>> {
+        self.partition().stat(inode_idx)
+    }
+
+    // Same as CfsPartition::add_file_to_inode, the host file is read and the data
+    // blocks are written without holding the partition
+    pub fn add_file_to_inode(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+        file: &mut std::fs::File,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let (block_size, inline) = {
+            let partition = self.partition();
+            let super_block = &partition.cfs.super_block;
+            (
+                super_block.blocksize as usize,
+                super_block.supports_inline_data(),
+            )
+        };
+        let host_file = partition::read_host_file(file, block_size, inline)?;
+
+        // `exclusive` can't move the blocks until they're written
+        let creating = self
+            .inner
+            .creates
+            .read()
+            .unwrap_or_else(PoisonError::into_inner);
+        let (inode_idx, writes) = {
+            let mut partition = self.partition();
+            let inode_idx = partition.alloc_file(parent_inode_idx, &host_file)?;
+            let inode = partition.cfs.inode_list.get(inode_idx);
+            let writes: Vec<_> = host_file
+                .contents
+                .iter()
+                .enumerate()
+                .filter_map(|(n, buffer)| {
+                    Some((partition.cfs.block_offset(inode.data_block(n)?), buffer))
+                })
+                .collect();
+            (inode_idx, writes)
+        };
+
+        // nobody else knows about the inode until it's linked
+        let written = writes
+            .into_iter()
+            .try_for_each(|(offset, buffer)| self.inner.data_dev.write_all_at(buffer, offset));
+        drop(creating);
+        let mut partition = self.partition();
+        if let Err(e) = written {
+            return Err(partition.discard_inode(inode_idx, Box::new(e)));
+        }
+        partition
+            .link_inode(parent_inode_idx, name, inode_idx)
+            .map_err(|e| partition.discard_inode(inode_idx, e))
+    }
+
+    pub fn add_dir_to_inode(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.partition().add_dir_to_inode(parent_inode_idx, name)
+    }
+
+    pub fn mknod(
+        &self,
+        parent_inode_idx: usize,
+        name: &str,
+        mode: u16,
+        rdev: u32,
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        self.partition().mknod(parent_inode_idx, name, mode, rdev)
+    }
+
+    pub fn remove_dir_from_inode(
+        &self,
+        parent_inode_idx: usize,
+        inode_idx: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = self.write_inode(inode_idx as usize);
+        self.partition()
+            .remove_dir_from_inode(parent_inode_idx, inode_idx)
+    }
+
+    pub fn truncate(
+        &self,
+        inode_idx: usize,
+        new_len: u64,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = self.write_inode(inode_idx);
+        self.partition().truncate(inode_idx, new_len)
+    }
+
+    pub fn fallocate(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        len: u64,
+        mode: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = self.write_inode(inode_idx);
+        self.partition().fallocate(inode_idx, offset, len, mode)
+    }
+
+    // Write to a file at `offset` like pwrite(2), only ever one block per call. The
+    // block is allocated under the partition and written without holding it.
+    pub fn write_at(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<usize, Box<dyn std::error::Error>> {
+        let _lock = self.write_inode(inode_idx);
+        let (len, device_offset) = self.prepare_write(inode_idx, offset, buf)?;
+        if let Some(device_offset) = device_offset {
+            self.inner
+                .data_dev
+                .write_all_at(&buf[..len], device_offset)?;
+        }
+        Ok(len)
+    }
+
+    pub fn setattr(
+        &self,
+        inode_idx: usize,
+        attr: &inode::SetAttr,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let _lock = self.write_inode(inode_idx);
+        self.partition().setattr(inode_idx, attr)
+    }
+
+    pub fn getxattr(
+        &self,
+        inode_idx: usize,
+        name: &str,
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        self.partition().getxattr(inode_idx, name)
+    }
+
+    pub fn setxattr(
+        &self,
+        inode_idx: usize,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.partition().setxattr(inode_idx, name, value, flags)
+    }
+
+    pub fn listxattr(&self, inode_idx: usize) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        self.partition().listxattr(inode_idx)
+    }
+
+    pub fn removexattr(
+        &self,
+        inode_idx: usize,
+        name: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        self.partition().removexattr(inode_idx, name)
+    }
+
+    // Only ever one block per call, like CfsPartition's FileHandle
+    fn read_at_locked(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        buf: &mut [u8],
+    ) -> std::io::Result<usize> {
+        match self.plan_read(inode_idx, offset, buf.len()) {
+            Extent::Data(data) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            }
+            Extent::Device { offset, len } => {
+                self.inner.data_dev.read_exact_at(&mut buf[..len], offset)?;
+                Ok(len)
+            }
+        }
+    }
+
+    // Where the bytes of a read of up to `len` bytes at `offset` come from, the
+    // caller holds the inode's lock until it has read them
+    pub(crate) fn plan_read(&self, inode_idx: usize, offset: u64, len: usize) -> Extent {
+        let (inode, block_size, block_offset) = {
+            let partition = self.partition();
+            let inode = partition.cfs.inode_list.get(inode_idx);
+            let block_size = partition.cfs.super_block.blocksize as u64;
+            let block_offset = inode
+                .data_block((offset / block_size) as usize)
+                .map(|block_idx| partition.cfs.block_offset(block_idx));
+            (inode, block_size, block_offset)
+        };
+
+        let size = inode.file_size();
+        if offset >= size || len == 0 {
+            return Extent::Data(Vec::new());
+        }
+
+        if inode.is_inline() {
+            let data = inode.inline_data();
+            let len = len.min(data.len() - offset as usize);
+            return Extent::Data(data[offset as usize..offset as usize + len].to_vec());
+        }
+
+        let in_block = offset % block_size;
+        let len = (len as u64).min(block_size - in_block).min(size - offset) as usize;
+        match block_offset {
+            Some(block_offset) => Extent::Device {
+                offset: block_offset + in_block,
+                len,
+            },
+            None => Extent::Data(vec![0; len]),
+        }
+    }
+
+    // CfsPartition::prepare_write, with the block turned into where `buf[..len]`
+    // goes on the device
+    pub(crate) fn prepare_write(
+        &self,
+        inode_idx: usize,
+        offset: u64,
+        buf: &[u8],
+    ) -> Result<(usize, Option<u64>), Box<dyn std::error::Error>> {
+        let mut partition = self.partition();
+        let (len, block_idx) = partition.prepare_write(inode_idx, offset, buf)?;
+        let block_size = partition.cfs.super_block.blocksize as u64;
+        let device_offset =
+            block_idx.map(|block_idx| partition.cfs.block_offset(block_idx) + offset % block_size);
+        Ok((len, device_offset))
+    }
+
+    #[cfg(feature = "tokio")]
+    pub(crate) fn data_dev(&self) -> &std::fs::File {
+        &self.inner.data_dev
+    }
+
+    // a panic while holding a lock leaves the partition as it was at that point,
+    // which is no worse than for a CfsPartition that isn't shared
+    fn partition(&self) -> MutexGuard<'_, CfsPartition> {
+        self.inner
+            .partition
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn read_inode(&self, inode_idx: usize) -> InodeGuard {
+        self.lock_inode(inode_idx, false)
+    }
+
+    pub(crate) fn write_inode(&self, inode_idx: usize) -> InodeGuard {
+        self.lock_inode(inode_idx, true)
+    }
+
+    fn lock_inode(&self, inode_idx: usize, write: bool) -> InodeGuard {
+        let stripe = inode_idx % INODE_LOCK_STRIPES;
+        self.inner.inode_locks[stripe].lock(write);
+        InodeGuard {
+            inner: self.inner.clone(),
+            stripe,
+            write,
+        }
+    }
+}
@@ -0,0 +1,156 @@
+#![allow(clippy::manual_div_ceil)]
+
+use std::collections::BTreeMap;
+
+use deku::prelude::*;
+
+use crate::inode;
+
+// A snapshot is a read-only copy of the inode bitmap and table taken at some
+// point. No data is copied: every block the inodes point to gets one more
+// reference (see Cfs::share_block) and is copied by whichever side changes it
+// first. The snapshot list, the inode tables and the reference counts are kept in
+// chains of data blocks:
+// ┌────────────┬────────┬─────────┐
+// │ Next block │ Length │ Payload │
+// └────────────┴────────┴─────────┘
+// A next block of 0 ends the chain, block 0 belongs to the root directory.
+pub const CHAIN_HEADER_SIZE: usize = 8;
+
+pub const SNAPSHOT_NAME_MAX: usize = 255;
+
+#[derive(Debug, Copy, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct ChainHeader {
+    pub next: u32,
+    pub len: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct Snapshot {
+    name_len: u8,
+    #[deku(count = "name_len")]
+    name: Vec<u8>,
+    // seconds since the epoch
+    pub created: u64,
+    // first block of the chain holding its inode bitmap and table
+    pub table_block: u32,
+}
+
+impl Snapshot {
+    pub fn new(name: &str, created: u64, table_block: u32) -> Self {
+        Self {
+            name_len: name.len() as u8,
+            name: name.as_bytes().to_vec(),
+            created,
+            table_block,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name).into_owned()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+struct RefcountEntry {
+    block: u32,
+    // references on top of the first one
+    extra: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+struct TableHeader {
+    inode_size: u32,
+    ninodes: u32,
+    iam_len: u32,
+}
+
+pub fn check_name(name: &str) -> Result<(), std::io::Error> {
+    if name.is_empty() || name.len() > SNAPSHOT_NAME_MAX {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid snapshot name: {name}"),
+        ));
+    }
+    Ok(())
+}
+
+pub fn decode_list(mut bytes: &[u8]) -> Result<Vec<Snapshot>, DekuError> {
+    let mut snapshots = Vec::new();
+    while !bytes.is_empty() {
+        let ((rest, _), snapshot) = Snapshot::from_bytes((bytes, 0))?;
+        bytes = rest;
+        snapshots.push(snapshot);
+    }
+    Ok(snapshots)
+}
+
+pub fn encode_list(snapshots: &[Snapshot]) -> Result<Vec<u8>, DekuError> {
+    let mut buffer = Vec::new();
+    for snapshot in snapshots {
+        buffer.extend_from_slice(&snapshot.to_bytes()?);
+    }
+    Ok(buffer)
+}
+
+pub fn decode_refcounts(mut bytes: &[u8]) -> Result<BTreeMap<usize, u32>, DekuError> {
+    let mut refcounts = BTreeMap::new();
+    while !bytes.is_empty() {
+        let ((rest, _), entry) = RefcountEntry::from_bytes((bytes, 0))?;
+        bytes = rest;
+        refcounts.insert(entry.block as usize, entry.extra);
+    }
+    Ok(refcounts)
+}
+
+pub fn encode_refcounts(refcounts: &BTreeMap<usize, u32>) -> Result<Vec<u8>, DekuError> {
+    let mut buffer = Vec::new();
+    for (block_idx, extra) in refcounts {
+        let entry = RefcountEntry {
+            block: *block_idx as u32,
+            extra: *extra,
+        };
+        buffer.extend_from_slice(&entry.to_bytes()?);
+    }
+    Ok(buffer)
+}
+
+// A snapshot's inode bitmap followed by its inodes, `inode_size` bytes each
+pub fn encode_table(
+    iam: &[u8],
+    inodes: &[inode::Inode],
+    inode_size: u32,
+) -> Result<Vec<u8>, DekuError> {
+    let header = TableHeader {
+        inode_size,
+        ninodes: inodes.len() as u32,
+        iam_len: iam.len() as u32,
+    };
+    let mut buffer = header.to_bytes()?;
+    buffer.extend_from_slice(iam);
+    for inode in inodes {
+        buffer.extend_from_slice(&inode.to_bytes_sized(inode_size)?);
+    }
+    Ok(buffer)
+}
+
+pub fn decode_table(bytes: &[u8]) -> Result<(Vec<u8>, Vec<inode::Inode>), DekuError> {
+    let ((rest, _), header) = TableHeader::from_bytes((bytes, 0))?;
+    let iam_len = header.iam_len as usize;
+    let inode_size = header.inode_size as usize;
+    if inode_size == 0 || rest.len() < iam_len + header.ninodes as usize * inode_size {
+        return Err(DekuError::Parse("Truncated snapshot table".to_string()));
+    }
+
+    let (iam, rest) = rest.split_at(iam_len);
+    let inodes = rest
+        .chunks_exact(inode_size)
+        .take(header.ninodes as usize)
+        .map(inode::Inode::from_bytes_sized)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok((iam.to_vec(), inodes))
+}
@@ -1,7 +1,37 @@
+#![allow(clippy::manual_div_ceil)]
+
+use crate::{inode, utils, MAGIC};
 use deku::prelude::*;
 
+// Size of the fields that precede the padding
+const HEADER_SIZE: u32 = 104;
+
+// data blocks are split in block groups, see `group`
+pub const FEATURE_BLOCK_GROUPS: u32 = 1 << 0;
+// inodes store the upper halves of uid, gid and size
+pub const FEATURE_LARGE_INODE: u32 = 1 << 1;
+// directory entries record the file type, see dir_entry::MAX_TYPED_NAME_LEN
+pub const FEATURE_DIR_FILE_TYPE: u32 = 1 << 2;
+// the super block carries a checksum, set by update_checksum
+pub const FEATURE_CHECKSUM: u32 = 1 << 3;
+
+// Values of `state`. Images that predate it have 0, which counts as clean.
+pub const STATE_CLEAN: u32 = 1;
+// opened for writing and not closed yet
+pub const STATE_DIRTY: u32 = 2;
+// something went wrong, stays until the image is checked
+pub const STATE_ERRORS: u32 = 3;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum FsState {
+    Clean,
+    Dirty,
+    Errors,
+}
+
 // I've broken my rules of no Clones... 🕺
 #[derive(Debug, PartialEq, DekuRead, DekuWrite, Clone)]
+#[deku(endian = "little")]
 pub struct SuperBlock {
     pub magic: u32,
     pub blocksize: u32,
@@ -10,7 +40,31 @@ pub struct SuperBlock {
     pub inode_blocks: u32,
     pub nblocks: u32,
     pub ninodes: u32,
-    #[deku(count = "*blocksize - 28")]
+    // CRC-32 of the whole block with this field set to 0, only meaningful with
+    // FEATURE_CHECKSUM. Images created before the checksum existed have neither.
+    pub checksum: u32,
+    pub free_blocks: u32,
+    pub free_inodes: u32,
+    pub features: u32,
+    // Block group geometry, all zero for the flat layout
+    pub groups: u32,
+    pub blocks_per_group: u32,
+    pub inodes_per_group: u32,
+    pub gdt_blocks: u32,
+    // size of an on-disk inode, zero on images that predate it (see inode_size())
+    pub inode_size: u32,
+    // See FsState, along with how many times the image was opened for writing
+    // since it was last checked. Times are in seconds since the epoch, 0 is never.
+    pub state: u32,
+    pub mount_count: u32,
+    pub mount_time: u64,
+    pub write_time: u64,
+    pub check_time: u64,
+    // first blocks of the reference counts of shared blocks and of the snapshot
+    // list, 0 when there are none (see `snapshot`)
+    pub refcount_block: u32,
+    pub snapshot_block: u32,
+    #[deku(count = "*blocksize - HEADER_SIZE")]
     pub padding: Vec<u8>,
 }
 
@@ -32,7 +86,138 @@ impl SuperBlock {
             inode_blocks,
             nblocks,
             ninodes,
-            padding: vec![0; (blocksize - 28) as usize],
+            checksum: 0,
+            free_blocks: 0,
+            free_inodes: 0,
+            features: 0,
+            groups: 0,
+            blocks_per_group: 0,
+            inodes_per_group: 0,
+            gdt_blocks: 0,
+            // legacy until the partition turns on FEATURE_LARGE_INODE
+            inode_size: 0,
+            // a new image counts as freshly checked
+            state: STATE_CLEAN,
+            mount_count: 0,
+            mount_time: 0,
+            write_time: 0,
+            check_time: utils::unix_time(),
+            refcount_block: 0,
+            snapshot_block: 0,
+            padding: vec![0; (blocksize - HEADER_SIZE) as usize],
         }
     }
+
+    pub fn inode_size(&self) -> u32 {
+        match self.inode_size {
+            0 => inode::LEGACY_INODE_SIZE,
+            inode_size => inode_size,
+        }
+    }
+
+    // inline data needs the inode flags, which only large inodes have
+    pub fn supports_inline_data(&self) -> bool {
+        self.has_feature(FEATURE_LARGE_INODE) && self.inode_size() >= inode::INODE_SIZE
+    }
+
+    // the xattr block is kept next to the inode flags
+    pub fn supports_xattrs(&self) -> bool {
+        self.inode_size() >= inode::INODE_SIZE
+    }
+
+    pub fn fs_state(&self) -> FsState {
+        match self.state {
+            STATE_DIRTY => FsState::Dirty,
+            STATE_ERRORS => FsState::Errors,
+            _ => FsState::Clean,
+        }
+    }
+
+    pub fn has_feature(&self, feature: u32) -> bool {
+        self.features & feature != 0
+    }
+
+    pub fn compute_checksum(&self) -> Result<u32, DekuError> {
+        let mut super_block = self.clone();
+        super_block.checksum = 0;
+        Ok(utils::crc32(&super_block.to_bytes()?))
+    }
+
+    // Images from before the checksum get it along with the feature the first
+    // time they are written
+    pub fn update_checksum(&mut self) -> Result<(), DekuError> {
+        self.features |= FEATURE_CHECKSUM;
+        self.checksum = self.compute_checksum()?;
+        Ok(())
+    }
+
+    pub fn is_valid(&self) -> bool {
+        if self.magic != MAGIC || self.blocksize < HEADER_SIZE {
+            return false;
+        }
+
+        // the field was padding before the feature, so zero is all it can be missing
+        // from an image that predates it
+        if self.checksum == 0 && !self.has_feature(FEATURE_CHECKSUM) {
+            return true;
+        }
+        self.compute_checksum().ok() == Some(self.checksum)
+    }
+}
+
+// When an image should be checked before being opened for writing, see
+// CfsPartition::open_with_policy
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CheckPolicy {
+    // refuse images that weren't closed cleanly or recorded errors
+    pub require_clean: bool,
+    // refuse after this many opens since the last check
+    pub max_mount_count: Option<u32>,
+    // refuse when the last check is older than this many seconds
+    pub check_interval: Option<u64>,
+}
+
+impl Default for CheckPolicy {
+    fn default() -> Self {
+        Self {
+            require_clean: true,
+            max_mount_count: None,
+            check_interval: None,
+        }
+    }
+}
+
+impl CheckPolicy {
+    pub fn check(&self, super_block: &SuperBlock) -> Result<(), std::io::Error> {
+        let reason = if self.require_clean && super_block.fs_state() != FsState::Clean {
+            Some(format!("state is {:?}", super_block.fs_state()))
+        } else if self
+            .max_mount_count
+            .is_some_and(|max| super_block.mount_count >= max)
+        {
+            Some(format!("opened {} times", super_block.mount_count))
+        } else if self.check_interval.is_some_and(|interval| {
+            super_block.check_time.saturating_add(interval) < utils::unix_time()
+        }) {
+            Some("last check is too old".to_string())
+        } else {
+            None
+        };
+
+        match reason {
+            Some(reason) => Err(std::io::Error::other(format!(
+                "Filesystem needs checking: {reason}"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+// Backup copies of the super block live in the middle and at the very end of the
+// device, both can be found again from the device size alone when block 0 is gone
+pub fn backup_locations(nblocks: u64) -> Vec<u64> {
+    let mut locations = vec![nblocks / 2, nblocks.saturating_sub(1)];
+    locations.retain(|location| *location > 0);
+    locations.dedup();
+    locations
 }
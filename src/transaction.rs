@@ -0,0 +1,68 @@
+use std::{
+    collections::BTreeMap,
+    ops::{Deref, DerefMut},
+};
+
+use crate::{partition::CfsPartition, Cfs};
+
+// What a transaction needs to go back to where it started: the metadata as it
+// was, and the blocks written since, which only reach the device on commit
+pub(crate) struct Journal {
+    pub cfs: Cfs,
+    pub blocks: BTreeMap<usize, Vec<u8>>,
+}
+
+impl Journal {
+    pub fn new(cfs: Cfs) -> Self {
+        Self {
+            cfs,
+            blocks: BTreeMap::new(),
+        }
+    }
+}
+
+// A group of changes to a partition, see CfsPartition::transaction. It derefs to
+// the partition so every operation can be part of it. Dropping it without
+// committing, when unwinding from a panic as well, puts the bitmaps, the inode
+// table and the blocks back as they were.
+pub struct Transaction<'a> {
+    partition: &'a mut CfsPartition,
+    committed: bool,
+}
+
+impl<'a> Transaction<'a> {
+    pub(crate) fn begin(partition: &'a mut CfsPartition) -> Self {
+        partition.begin_journal();
+        Self {
+            partition,
+            committed: false,
+        }
+    }
+
+    pub(crate) fn commit(mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.committed = true;
+        self.partition.commit_journal()
+    }
+}
+
+impl Deref for Transaction<'_> {
+    type Target = CfsPartition;
+
+    fn deref(&self) -> &CfsPartition {
+        self.partition
+    }
+}
+
+impl DerefMut for Transaction<'_> {
+    fn deref_mut(&mut self) -> &mut CfsPartition {
+        self.partition
+    }
+}
+
+impl Drop for Transaction<'_> {
+    fn drop(&mut self) {
+        if !self.committed {
+            self.partition.rollback_journal();
+        }
+    }
+}
@@ -11,3 +11,55 @@ pub fn str_to_u8_60(s: &str) -> [u8; 60] {
         .for_each(|(a_byte, s_byte)| *a_byte = s_byte);
     res
 }
+
+// Plain bitwise CRC-32 (IEEE), we only checksum a handful of blocks so there is
+// no need for a lookup table
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xedb8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+// A point in time as stored in the inode timestamps, seconds since the epoch
+// (negative before it) and nanoseconds into that second
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timespec {
+    pub sec: i64,
+    pub nsec: u32,
+}
+
+impl Timespec {
+    pub fn new(sec: i64, nsec: u32) -> Self {
+        Self { sec, nsec }
+    }
+}
+
+impl From<std::time::SystemTime> for Timespec {
+    fn from(time: std::time::SystemTime) -> Self {
+        match time.duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => Self::new(since.as_secs() as i64, since.subsec_nanos()),
+            Err(before) => {
+                let before = before.duration();
+                match before.subsec_nanos() {
+                    0 => Self::new(-(before.as_secs() as i64), 0),
+                    nsec => Self::new(-(before.as_secs() as i64) - 1, 1_000_000_000 - nsec),
+                }
+            }
+        }
+    }
+}
+
+pub fn now() -> Timespec {
+    std::time::SystemTime::now().into()
+}
+
+// whole seconds since the epoch, what the super block keeps
+pub fn unix_time() -> u64 {
+    now().sec.max(0) as u64
+}
@@ -0,0 +1,136 @@
+#![allow(clippy::manual_div_ceil)]
+
+use deku::prelude::*;
+
+// Extended attributes live in the inode's xattr space (when the inode is bigger
+// than INODE_SIZE) and spill over to a single xattr block. Both areas share the
+// same layout:
+// ┌───────┬─────────┬─────┬─────────┬──────┐
+// │ Magic │ Entry 0 │ ... │ Entry N │ 0000 │
+// └───────┴─────────┴─────┴─────────┴──────┘
+// every entry is padded to 4 bytes and a zeroed entry header ends the list
+pub const XATTR_MAGIC: u32 = 0xEA02_0000;
+const XATTR_HEADER_SIZE: usize = 4;
+const XATTR_ENTRY_HEADER_SIZE: usize = 4;
+
+// setxattr flags, same values as in Linux
+pub const XATTR_CREATE: u32 = 0x01;
+pub const XATTR_REPLACE: u32 = 0x02;
+
+pub const XATTR_NAME_MAX: usize = 255;
+
+// Namespaces are stored as an index instead of the full prefix
+pub const XATTR_INDEX_USER: u8 = 1;
+pub const XATTR_INDEX_TRUSTED: u8 = 2;
+pub const XATTR_INDEX_SECURITY: u8 = 3;
+pub const XATTR_INDEX_SYSTEM: u8 = 4;
+
+const XATTR_PREFIXES: [(u8, &str); 4] = [
+    (XATTR_INDEX_USER, "user."),
+    (XATTR_INDEX_TRUSTED, "trusted."),
+    (XATTR_INDEX_SECURITY, "security."),
+    (XATTR_INDEX_SYSTEM, "system."),
+];
+
+#[derive(Debug, Clone, PartialEq, DekuRead, DekuWrite)]
+#[deku(endian = "little")]
+pub struct XattrEntry {
+    pub name_index: u8,
+    pub name_len: u8,
+    pub value_len: u16,
+    #[deku(count = "name_len")]
+    pub name: Vec<u8>,
+    #[deku(count = "value_len")]
+    pub value: Vec<u8>,
+    #[deku(count = "padding_len(*name_len as usize + *value_len as usize)")]
+    padding: Vec<u8>,
+}
+
+impl XattrEntry {
+    pub fn new(name_index: u8, name: &[u8], value: &[u8]) -> Self {
+        Self {
+            name_index,
+            name_len: name.len() as u8,
+            value_len: value.len() as u16,
+            name: name.to_vec(),
+            value: value.to_vec(),
+            padding: vec![0; padding_len(name.len() + value.len())],
+        }
+    }
+
+    // the name with its namespace prefix put back
+    pub fn full_name(&self) -> String {
+        let prefix = XATTR_PREFIXES
+            .iter()
+            .find(|(index, _)| *index == self.name_index)
+            .map_or("", |(_, prefix)| prefix);
+        format!("{prefix}{}", String::from_utf8_lossy(&self.name))
+    }
+
+    pub fn disk_size(&self) -> usize {
+        XATTR_ENTRY_HEADER_SIZE + self.name.len() + self.value.len() + self.padding.len()
+    }
+}
+
+fn padding_len(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+// Split "user.foo" into its namespace index and "foo"
+pub fn split_name(name: &str) -> Result<(u8, &str), std::io::Error> {
+    let (index, suffix) = XATTR_PREFIXES
+        .iter()
+        .find_map(|(index, prefix)| Some((*index, name.strip_prefix(prefix)?)))
+        .ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                format!("Unsupported xattr namespace: {name}"),
+            )
+        })?;
+
+    if suffix.is_empty() || suffix.len() > XATTR_NAME_MAX {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid xattr name: {name}"),
+        ));
+    }
+    Ok((index, suffix))
+}
+
+// Space taken by `entries` in an xattr area, header included
+pub fn area_size(entries: &[XattrEntry]) -> usize {
+    XATTR_HEADER_SIZE + entries.iter().map(XattrEntry::disk_size).sum::<usize>()
+}
+
+// Parse an xattr area, a zeroed one (like unused in-inode space) has no entries
+pub fn decode(bytes: &[u8]) -> Result<Vec<XattrEntry>, DekuError> {
+    if bytes.len() < XATTR_HEADER_SIZE || bytes[..XATTR_HEADER_SIZE] == [0; XATTR_HEADER_SIZE] {
+        return Ok(Vec::new());
+    }
+    if bytes[..XATTR_HEADER_SIZE] != XATTR_MAGIC.to_le_bytes() {
+        return Err(DekuError::Parse("Bad xattr magic".to_string()));
+    }
+
+    let mut entries = Vec::new();
+    let mut rest = &bytes[XATTR_HEADER_SIZE..];
+    while rest.len() >= XATTR_ENTRY_HEADER_SIZE && rest[0] != 0 {
+        let ((next, _), entry) = XattrEntry::from_bytes((rest, 0))?;
+        rest = next;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Serialize `entries` into an xattr area of `size` bytes, the caller makes sure
+// they fit with `area_size`
+pub fn encode(entries: &[XattrEntry], size: usize) -> Result<Vec<u8>, DekuError> {
+    let mut buffer = Vec::with_capacity(size);
+    if !entries.is_empty() {
+        buffer.extend_from_slice(&XATTR_MAGIC.to_le_bytes());
+        for entry in entries {
+            buffer.extend_from_slice(&entry.to_bytes()?);
+        }
+    }
+    buffer.resize(size, 0);
+    Ok(buffer)
+}
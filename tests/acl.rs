@@ -0,0 +1,104 @@
+mod common;
+
+use cfs::{
+    acl::{self, Acl, AclEntry, AclType},
+    inode::SetAttr,
+    partition::CfsPartition,
+};
+use common::{add_file, contents, find, host_file, Image};
+
+const OWNER: u32 = 1000;
+const GROUP: u32 = 1000;
+
+fn chmod(partition: &mut CfsPartition, inode_idx: usize, mode: u16) {
+    let attr = SetAttr {
+        mode: Some(mode),
+        uid: Some(OWNER),
+        gid: Some(GROUP),
+        ..Default::default()
+    };
+    partition.setattr(inode_idx, &attr).unwrap();
+}
+
+fn named_user_acl(uid: u32, perm: u16) -> Acl {
+    Acl::new(vec![
+        AclEntry::new(acl::ACL_USER_OBJ, 0o6, None),
+        AclEntry::new(acl::ACL_USER, perm, Some(uid)),
+        AclEntry::new(acl::ACL_GROUP_OBJ, 0o4, None),
+        AclEntry::new(acl::ACL_MASK, 0o6, None),
+        AclEntry::new(acl::ACL_OTHER, 0, None),
+    ])
+    .unwrap()
+}
+
+// Root searches any directory, but only executes files somebody could execute
+#[test]
+fn root_execute() {
+    let (_image, mut partition) = Image::new("acl-root", 16 << 20);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    let file = add_file(&mut partition, "file", &contents(1, 10));
+    let exec = acl::ACL_EXECUTE;
+
+    chmod(&mut partition, dir, 0o600);
+    assert!(partition.access(dir, 0, &[0], exec).unwrap());
+    assert!(!partition.access(dir, OWNER, &[GROUP], exec).unwrap());
+
+    chmod(&mut partition, file, 0o644);
+    assert!(!partition.access(file, 0, &[0], exec).unwrap());
+    assert!(partition
+        .access(file, 0, &[0], acl::ACL_READ | acl::ACL_WRITE)
+        .unwrap());
+    chmod(&mut partition, file, 0o605);
+    assert!(partition.access(file, 0, &[0], exec).unwrap());
+}
+
+// A named user entry survives reopening, shows in the mode through the mask and
+// is what access goes by
+#[test]
+fn access_acl_round_trip() {
+    let (image, mut partition) = Image::new("acl-access", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 10));
+    chmod(&mut partition, file, 0o640);
+    let acl = named_user_acl(2000, 0o6);
+    partition
+        .set_acl(file, AclType::Access, Some(&acl))
+        .unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_acl(file, AclType::Access).unwrap(), Some(acl));
+    assert_eq!(partition.stat(file).unwrap().perm, 0o660);
+    let write = acl::ACL_READ | acl::ACL_WRITE;
+    assert!(partition.access(file, 2000, &[], write).unwrap());
+    assert!(!partition.access(file, 3000, &[], acl::ACL_READ).unwrap());
+    assert!(partition
+        .access(file, 3000, &[GROUP], acl::ACL_READ)
+        .unwrap());
+    assert!(!partition.access(file, 3000, &[GROUP], write).unwrap());
+}
+
+// Files created in a directory with a default ACL get it as their access ACL
+#[test]
+fn default_acl_is_inherited() {
+    let (image, mut partition) = Image::new("acl-default", 16 << 20);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    let acl = named_user_acl(2000, 0o4);
+    partition
+        .set_acl(dir, AclType::Default, Some(&acl))
+        .unwrap();
+    partition
+        .add_file_to_inode(dir, "file", &mut host_file("acl-default", &contents(2, 10)))
+        .unwrap();
+    let file = find(&mut partition, dir, "file").unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let inherited = partition.get_acl(file, AclType::Access).unwrap().unwrap();
+    assert!(inherited
+        .entries()
+        .contains(&AclEntry::new(acl::ACL_USER, 0o4, Some(2000))));
+    assert!(partition.access(file, 2000, &[], acl::ACL_READ).unwrap());
+    assert!(!partition.access(file, 2001, &[], acl::ACL_READ).unwrap());
+}
@@ -0,0 +1,89 @@
+#![cfg(feature = "tokio")]
+
+mod common;
+
+use std::{future::poll_fn, io::SeekFrom, pin::Pin, task::Poll};
+
+use cfs::{aio::AsyncPartition, shared::SharedPartition};
+use common::{contents, Image};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const S_IFREG: u16 = 0o100000;
+
+async fn partition(name: &str) -> (Image, AsyncPartition) {
+    let (image, partition) = Image::new(name, 16 << 20);
+    let partition = AsyncPartition::new(SharedPartition::new(partition).unwrap()).unwrap();
+    (image, partition)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn write_seek_read_remove() {
+    let (image, partition) = partition("aio").await;
+    let data = contents(1, 3 * 4096 + 100);
+    let inode_idx = partition
+        .mknod(1, "file", S_IFREG | 0o644, 0)
+        .await
+        .unwrap();
+
+    let mut file = partition.open_file(inode_idx);
+    file.write_all(&data).await.unwrap();
+    assert_eq!(file.size().await.unwrap(), data.len() as u64);
+    assert_eq!(file.seek(SeekFrom::Start(5000)).await.unwrap(), 5000);
+    let mut read = Vec::new();
+    file.read_to_end(&mut read).await.unwrap();
+    assert_eq!(read, data[5000..]);
+    assert_eq!(
+        partition.get_data_from_inode(inode_idx).await.unwrap(),
+        data
+    );
+
+    partition
+        .remove_dir_from_inode(1, inode_idx as u32)
+        .await
+        .unwrap();
+    let items = partition.read_dir(1).await.unwrap();
+    assert!(items.iter().all(|item| item.name != *"file"));
+    drop((file, partition));
+    common::check_consistency(&image.reopen());
+}
+
+// A seek right after poll_write gave up waiting must not lose the write or
+// move the position out from under it
+#[tokio::test(flavor = "multi_thread")]
+async fn seek_while_writing() {
+    let (_image, partition) = partition("aio-seek").await;
+    let data = contents(2, 1000);
+    let inode_idx = partition
+        .mknod(1, "file", S_IFREG | 0o644, 0)
+        .await
+        .unwrap();
+    let mut file = partition.open_file(inode_idx);
+
+    // start the write and leave it in flight
+    poll_fn(|cx| {
+        let _ = Pin::new(&mut file).poll_write(cx, &data);
+        Poll::Ready(())
+    })
+    .await;
+    assert_eq!(
+        file.seek(SeekFrom::Current(0)).await.unwrap(),
+        data.len() as u64
+    );
+    assert_eq!(
+        partition.get_data_from_inode(inode_idx).await.unwrap(),
+        data
+    );
+
+    // polling again with other data can't be told how much of it was written
+    poll_fn(|cx| {
+        if Pin::new(&mut file).poll_write(cx, &data).is_pending() {
+            let other = contents(3, 1000);
+            assert!(matches!(
+                Pin::new(&mut file).poll_write(cx, &other),
+                Poll::Ready(Err(_))
+            ));
+        }
+        Poll::Ready(())
+    })
+    .await;
+}
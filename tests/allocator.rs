@@ -0,0 +1,75 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{add_file, bitmaps, check_consistency, contents, Image};
+
+fn data_blocks(partition: &CfsPartition, inode_idx: usize) -> Vec<usize> {
+    let inode = partition.cfs.inode_list().get(inode_idx);
+    (0..9).map_while(|n| inode.data_block(n)).collect()
+}
+
+fn contiguous(blocks: &[usize]) -> bool {
+    blocks.windows(2).all(|pair| pair[1] == pair[0] + 1)
+}
+
+// Files are laid out in one run, one after the other
+#[test]
+fn files_get_contiguous_blocks() {
+    let (image, mut partition) = Image::new("alloc-files", 16 << 20);
+    let files: Vec<_> = (0..4)
+        .map(|n| {
+            let data = contents(n, (n + 2) * 4096);
+            (add_file(&mut partition, &format!("file{n}"), &data), data)
+        })
+        .collect();
+    let runs: Vec<_> = files
+        .iter()
+        .map(|(inode_idx, _)| data_blocks(&partition, *inode_idx))
+        .collect();
+    for (run, n) in runs.iter().zip(2..) {
+        assert_eq!(run.len(), n);
+        assert!(contiguous(run));
+    }
+    assert!(runs.windows(2).all(|pair| pair[0].last() < pair[1].first()));
+    drop(partition);
+
+    let mut partition = image.reopen();
+    for ((inode_idx, data), run) in files.iter().zip(&runs) {
+        assert_eq!(data_blocks(&partition, *inode_idx), *run);
+        assert_eq!(partition.get_data_from_inode(*inode_idx).unwrap(), *data);
+    }
+    check_consistency(&partition);
+}
+
+// A goal is followed when there's room after it, the search wraps around to the
+// start otherwise, and a run that fits nowhere isn't allocated at all
+#[test]
+fn goals_and_wrapping() {
+    let (_image, mut partition) = Image::new("alloc-goals", 16 << 20);
+    let cfs = &mut partition.cfs;
+    let data_blocks = cfs.data_blocks() as usize;
+
+    let first = cfs.alloc_blocks(8, Some(100)).unwrap();
+    assert_eq!(first, 100);
+    let next = cfs.alloc_blocks(4, Some(100)).unwrap();
+    assert_eq!(next, 108);
+
+    // a hole of 3 in the middle of the first run
+    for block_idx in 102..105 {
+        cfs.free_block(block_idx);
+    }
+    assert_eq!(cfs.alloc_blocks(4, Some(100)), Some(112));
+    assert_eq!(cfs.alloc_blocks(3, Some(100)), Some(102));
+
+    // nothing after the goal, the search starts over at 0
+    assert_eq!(cfs.alloc_blocks(2, Some(data_blocks)), Some(1));
+
+    let free = cfs.super_block().free_blocks;
+    assert_eq!(cfs.alloc_blocks(data_blocks, None), None);
+    assert_eq!(cfs.super_block().free_blocks, free);
+    let (used_blocks, _) = bitmaps(&partition);
+    assert_eq!(
+        partition.statfs().free_blocks as usize,
+        data_blocks - used_blocks.len()
+    );
+}
@@ -0,0 +1,89 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{bitmaps, check_consistency, contents, find, host_file, Image};
+
+// Every group descriptor agrees with its slice of the bitmaps
+fn check_group_descs(partition: &CfsPartition) {
+    let cfs = &partition.cfs;
+    let super_block = cfs.super_block();
+    let blocks_per_group = super_block.blocks_per_group as usize;
+    let inodes_per_group = super_block.inodes_per_group as usize;
+    let (used_blocks, used_inodes) = bitmaps(partition);
+    let descs = cfs.group_descs();
+    assert_eq!(descs.len(), super_block.groups as usize);
+    for (group, desc) in descs.iter().enumerate() {
+        let blocks = used_blocks
+            .iter()
+            .filter(|block_idx| cfs.group_of_block(**block_idx) == group)
+            .count();
+        let inodes = used_inodes
+            .iter()
+            .filter(|inode_idx| cfs.group_of_inode(**inode_idx) == group)
+            .count();
+        let group_blocks =
+            blocks_per_group.min(cfs.data_blocks() as usize - group * blocks_per_group);
+        assert_eq!(
+            desc.free_blocks as usize,
+            group_blocks - blocks,
+            "group {group}"
+        );
+        assert_eq!(
+            desc.free_inodes as usize,
+            inodes_per_group - inodes,
+            "group {group}"
+        );
+    }
+    let free_blocks: u32 = descs.iter().map(|desc| desc.free_blocks).sum();
+    let free_inodes: u32 = descs.iter().map(|desc| desc.free_inodes).sum();
+    assert_eq!(free_blocks, super_block.free_blocks);
+    assert_eq!(free_inodes, super_block.free_inodes);
+}
+
+// Once the first group is out of inodes new files land in the next one, and
+// their blocks follow them there
+#[test]
+fn files_stay_in_their_group() {
+    let (image, mut partition) = Image::with_block_groups("groups", 16 << 20, 1024);
+    let inodes_per_group = partition.cfs.super_block().inodes_per_group as usize;
+    assert!(partition.cfs.super_block().groups > 2);
+
+    let mut files = Vec::new();
+    for dir in 0..2 {
+        let dir_name = format!("dir{dir}");
+        partition.add_dir_to_inode(1, &dir_name).unwrap();
+        let dir_idx = find(&mut partition, 1, &dir_name).unwrap();
+        for n in 0..inodes_per_group / 2 {
+            let name = format!("file{n}");
+            let data = contents(dir * 100 + n, 4096 + n);
+            let tag = format!("groups-{dir}-{n}");
+            partition
+                .add_file_to_inode(dir_idx, &name, &mut host_file(&tag, &data))
+                .unwrap();
+            files.push((find(&mut partition, dir_idx, &name).unwrap(), data));
+        }
+    }
+    let cfs = &partition.cfs;
+    assert!(files
+        .iter()
+        .any(|(inode_idx, _)| cfs.group_of_inode(*inode_idx) == 1));
+    for (inode_idx, _) in &files {
+        let inode = cfs.inode_list().get(*inode_idx);
+        for block_idx in inode.block_addrs() {
+            assert_eq!(
+                cfs.group_of_block(*block_idx as usize),
+                cfs.group_of_inode(*inode_idx),
+                "inode {inode_idx}"
+            );
+        }
+    }
+    check_group_descs(&partition);
+    drop(partition);
+
+    let mut partition = image.reopen();
+    for (inode_idx, data) in &files {
+        assert_eq!(partition.get_data_from_inode(*inode_idx).unwrap(), *data);
+    }
+    check_group_descs(&partition);
+    check_consistency(&partition);
+}
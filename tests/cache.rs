@@ -0,0 +1,89 @@
+mod common;
+
+use std::io::Read;
+
+use cfs::{
+    cache::{CacheConfig, WritePolicy},
+    partition::CfsPartition,
+};
+use common::{add_file, contents, Image};
+
+fn write_back(capacity: usize) -> Option<CacheConfig> {
+    Some(CacheConfig {
+        capacity,
+        policy: WritePolicy::WriteBack,
+        ..Default::default()
+    })
+}
+
+// write_at only writes a block at a time
+fn write_all(partition: &mut CfsPartition, file: usize, offset: usize, data: &[u8]) {
+    let mut done = 0;
+    while done < data.len() {
+        done += partition
+            .write_at(file, (offset + done) as u64, &data[done..])
+            .unwrap();
+    }
+}
+
+// A write-back cache holds writes until they're flushed, then they're on the
+// device like without a cache
+#[test]
+fn write_back_round_trip() {
+    let (image, mut partition) = Image::new("cache-write-back", 16 << 20);
+    let old = contents(1, 4 * 4096);
+    let file = add_file(&mut partition, "file", &old);
+    partition.set_block_cache(write_back(64)).unwrap();
+
+    let new = contents(2, 2 * 4096);
+    write_all(&mut partition, file, 4096, &new);
+    let mut expected = old.clone();
+    expected[4096..3 * 4096].copy_from_slice(&new);
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), expected);
+    assert_eq!(image.reopen().get_data_from_inode(file).unwrap(), old);
+
+    partition.flush_cache().unwrap();
+    assert_eq!(partition.cache_stats().unwrap().writebacks, 2);
+    assert_eq!(image.reopen().get_data_from_inode(file).unwrap(), expected);
+}
+
+// Evicted dirty blocks are written on the way out, and dropping the partition
+// flushes the rest
+#[test]
+fn eviction() {
+    let (image, mut partition) = Image::new("cache-eviction", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 8 * 4096));
+    partition.set_block_cache(write_back(2)).unwrap();
+
+    let data = contents(3, 8 * 4096);
+    write_all(&mut partition, file, 0, &data);
+    let stats = partition.cache_stats().unwrap();
+    assert!(stats.evictions >= 6);
+    assert!(stats.writebacks >= 6);
+    drop(partition);
+    assert_eq!(image.reopen().get_data_from_inode(file).unwrap(), data);
+}
+
+// Reading a file front to back loads the blocks ahead of the reader, which
+// then come from the cache
+#[test]
+fn read_ahead() {
+    let (_image, mut partition) = Image::new("cache-read-ahead", 16 << 20);
+    let data = contents(1, 9 * 4096);
+    let file = add_file(&mut partition, "file", &data);
+    partition
+        .set_block_cache(Some(CacheConfig::default()))
+        .unwrap();
+
+    let mut read = Vec::new();
+    partition.open_file(file).read_to_end(&mut read).unwrap();
+    assert_eq!(read, data);
+    let stats = partition.cache_stats().unwrap();
+    assert_eq!(stats.read_ahead, 7);
+    assert!(stats.hits >= 7);
+
+    // a second pass only hits
+    let misses = stats.misses;
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+    assert_eq!(partition.cache_stats().unwrap().misses, misses);
+}
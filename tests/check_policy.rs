@@ -0,0 +1,88 @@
+mod common;
+
+use cfs::{
+    partition::CfsPartition,
+    superblock::{CheckPolicy, FsState},
+};
+use common::{add_file, contents, Image};
+
+fn open(image: &Image, policy: CheckPolicy) -> Result<CfsPartition, String> {
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    CfsPartition::open_with_policy(blk_dev, |super_block| policy.check(super_block))
+        .map_err(|e| e.to_string())
+}
+
+fn state(image: &Image) -> FsState {
+    image.reopen().cfs.super_block().fs_state()
+}
+
+// Errors keep the image from being opened until it's checked, an open image is
+// dirty until it's closed
+#[test]
+fn errors_until_checked() {
+    let (image, mut partition) = Image::new("policy-errors", 16 << 20);
+    let data = contents(1, 5000);
+    let file = add_file(&mut partition, "file", &data);
+    assert_eq!(state(&image), FsState::Dirty);
+    partition.mark_errors().unwrap();
+    partition.close().unwrap();
+    assert_eq!(state(&image), FsState::Errors);
+
+    let error = open(&image, CheckPolicy::default()).err().unwrap();
+    assert_eq!(error, "Filesystem needs checking: state is Errors");
+    let policy = CheckPolicy {
+        require_clean: false,
+        ..Default::default()
+    };
+    let mut partition = open(&image, policy).unwrap();
+    partition.mark_checked().unwrap();
+    assert_eq!(state(&image), FsState::Dirty);
+    partition.close().unwrap();
+    assert_eq!(state(&image), FsState::Clean);
+
+    let mut partition = open(&image, CheckPolicy::default()).unwrap();
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+}
+
+// Checking an image nobody has open leaves it clean
+#[test]
+fn checked_before_it_is_written() {
+    let (image, partition) = Image::new("policy-new", 16 << 20);
+    drop(partition);
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    let mut partition = CfsPartition::new(blk_dev, 4096).unwrap();
+    partition.mark_checked().unwrap();
+    assert_eq!(state(&image), FsState::Clean);
+    drop(partition);
+    assert_eq!(state(&image), FsState::Clean);
+}
+
+// Every open counts, checking starts over
+#[test]
+fn max_mount_count() {
+    let (image, partition) = Image::new("policy-mounts", 16 << 20);
+    partition.close().unwrap();
+    let policy = CheckPolicy {
+        max_mount_count: Some(3),
+        ..Default::default()
+    };
+    for _ in 0..3 {
+        open(&image, policy).unwrap().close().unwrap();
+    }
+    let error = open(&image, policy).err().unwrap();
+    assert_eq!(error, "Filesystem needs checking: opened 3 times");
+
+    let mut partition = open(&image, CheckPolicy::default()).unwrap();
+    partition.mark_checked().unwrap();
+    partition.close().unwrap();
+    assert_eq!(image.reopen().cfs.super_block().mount_count, 0);
+    open(&image, policy).unwrap();
+}
@@ -0,0 +1,164 @@
+// Helpers shared by the integration tests, not every test uses all of them
+#![allow(dead_code)]
+
+use std::{io::Write, path::PathBuf};
+
+use cfs::partition::CfsPartition;
+
+// A scratch image in the temp dir, removed when dropped
+pub struct Image {
+    pub path: PathBuf,
+}
+
+impl Image {
+    // A fresh image of `size` bytes with a root directory
+    pub fn new(name: &str, size: u64) -> (Self, CfsPartition) {
+        let path = std::env::temp_dir().join(format!("cfs-{name}-{}.img", std::process::id()));
+        let blk_dev = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        blk_dev.set_len(size).unwrap();
+        let mut partition = CfsPartition::new(blk_dev, 4096).unwrap();
+        partition.write_cfs().unwrap();
+        partition.setup_root_dir().unwrap();
+        (Self { path }, partition)
+    }
+
+    // Same as `new` with the data blocks split in groups of `blocks_per_group`
+    pub fn with_block_groups(name: &str, size: u64, blocks_per_group: u64) -> (Self, CfsPartition) {
+        let (image, partition) = Self::new(name, size);
+        drop(partition);
+        let blk_dev = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&image.path)
+            .unwrap();
+        let mut partition =
+            CfsPartition::new_with_block_groups(blk_dev, 4096, blocks_per_group).unwrap();
+        partition.write_cfs().unwrap();
+        partition.setup_root_dir().unwrap();
+        (image, partition)
+    }
+
+    // What's on the device, read-only so it can be looked at while the image is
+    // still open
+    pub fn reopen(&self) -> CfsPartition {
+        CfsPartition::open_read_only(std::fs::File::open(&self.path).unwrap()).unwrap()
+    }
+
+    // Open the image for writing again
+    pub fn open(&self) -> CfsPartition {
+        let blk_dev = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&self.path)
+            .unwrap();
+        CfsPartition::try_from(blk_dev).unwrap()
+    }
+}
+
+impl Drop for Image {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+// A host file holding `data`, to feed add_file_to_inode
+pub fn host_file(name: &str, data: &[u8]) -> std::fs::File {
+    let path = std::env::temp_dir().join(format!("cfs-{name}-{}", std::process::id()));
+    let mut file = std::fs::File::create(&path).unwrap();
+    file.write_all(data).unwrap();
+    let file = std::fs::File::open(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    file
+}
+
+// Import `data` into the root directory and return the new inode
+pub fn add_file(partition: &mut CfsPartition, name: &str, data: &[u8]) -> usize {
+    partition
+        .add_file_to_inode(1, name, &mut host_file(name, data))
+        .unwrap();
+    find(partition, 1, name).unwrap()
+}
+
+// Contents that differ from file to file and never look like a hole
+pub fn contents(seed: usize, len: usize) -> Vec<u8> {
+    (0..len)
+        .map(|i| ((i * 31 + seed * 7) % 251) as u8 + 1)
+        .collect()
+}
+
+pub fn find(partition: &mut CfsPartition, dir: usize, name: &str) -> Option<usize> {
+    partition
+        .read_dir(dir)
+        .map(|item| item.unwrap())
+        .find(|item| item.name == name)
+        .map(|item| item.inode as usize)
+}
+
+// Take free blocks away until only `left` remain
+pub fn fill_blocks(partition: &mut CfsPartition, left: usize) {
+    while partition.cfs.super_block().free_blocks as usize > left {
+        partition.cfs.alloc_block().unwrap();
+    }
+    partition.write_cfs().unwrap();
+}
+
+pub fn fill_inodes(partition: &mut CfsPartition) {
+    while partition.cfs.alloc_inode().is_some() {}
+    partition.write_cfs().unwrap();
+}
+
+fn bit(data: &[u8], index: usize) -> bool {
+    data[index / 8] & (1 << (index % 8)) != 0
+}
+
+// The bits set in the BAM and IAM, only the ones that stand for blocks and inodes
+pub fn bitmaps(partition: &CfsPartition) -> (Vec<usize>, Vec<usize>) {
+    let cfs = &partition.cfs;
+    let blocks = (0..cfs.data_blocks() as usize)
+        .filter(|block_idx| bit(&cfs.bam().data, *block_idx))
+        .collect();
+    let inodes = (0..cfs.super_block().ninodes as usize)
+        .filter(|inode_idx| bit(&cfs.iam().data, *inode_idx))
+        .collect();
+    (blocks, inodes)
+}
+
+// The free counts agree with the bitmaps, and every block in use belongs to
+// exactly one inode or holds a super block backup. Only for images without
+// clones or snapshots, whose blocks have more than one owner.
+pub fn check_consistency(partition: &CfsPartition) {
+    let cfs = &partition.cfs;
+    let data_blocks = cfs.data_blocks() as usize;
+    let ninodes = cfs.super_block().ninodes as usize;
+    let (used_blocks, used_inodes) = bitmaps(partition);
+    assert_eq!(
+        cfs.super_block().free_blocks as usize,
+        data_blocks - used_blocks.len()
+    );
+    assert_eq!(
+        cfs.super_block().free_inodes as usize,
+        ninodes - used_inodes.len()
+    );
+
+    let mut owners = vec![0; data_blocks];
+    // a zero address is a hole, block 0 is the root directory's
+    owners[0] += 1;
+    for inode_idx in &used_inodes {
+        for block_idx in cfs.inode_list().get(*inode_idx).block_addrs() {
+            owners[*block_idx as usize] += 1;
+        }
+    }
+    for block_idx in cfs.backup_blocks() {
+        owners[block_idx] += 1;
+    }
+    for (block_idx, owners) in owners.iter().enumerate() {
+        let used = used_blocks.binary_search(&block_idx).is_ok();
+        assert_eq!(*owners, used as usize, "block {block_idx}");
+    }
+}
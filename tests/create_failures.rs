@@ -0,0 +1,140 @@
+mod common;
+
+use std::fmt::Debug;
+
+use cfs::{inode, partition::CfsPartition, shared::SharedPartition, StatFs};
+use common::{bitmaps, contents, fill_blocks, fill_inodes, find, host_file, Image};
+
+const NO_FREE_BLOCKS: &str = "No free blocks";
+const NO_FREE_INODES: &str = "No free inodes";
+
+// The free counts and the bitmaps
+fn state(partition: &CfsPartition) -> (StatFs, (Vec<usize>, Vec<usize>)) {
+    (partition.statfs(), bitmaps(partition))
+}
+
+// `create` fails with `message` and leaves the free counts and the bitmaps as
+// they were, in memory and on the device
+fn assert_fails_cleanly<T: Debug>(
+    image: &Image,
+    partition: &mut CfsPartition,
+    message: &str,
+    create: impl FnOnce(&mut CfsPartition) -> Result<T, Box<dyn std::error::Error>>,
+) {
+    let before = state(partition);
+    assert_eq!(create(partition).unwrap_err().to_string(), message);
+    assert_eq!(state(partition), before);
+    assert_eq!(state(&image.reopen()), before);
+}
+
+#[test]
+fn add_file_without_blocks() {
+    let (image, mut partition) = Image::new("file-blocks", 16 << 20);
+    // room for some of the file but not all of it
+    fill_blocks(&mut partition, 2);
+    let data = contents(1, 5 * 4096);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_BLOCKS, |partition| {
+        partition.add_file_to_inode(1, "file", &mut host_file("file-blocks", &data))
+    });
+}
+
+#[test]
+fn add_file_without_inodes() {
+    let (image, mut partition) = Image::new("file-inodes", 16 << 20);
+    fill_inodes(&mut partition);
+    let data = contents(2, 4096);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_INODES, |partition| {
+        partition.add_file_to_inode(1, "file", &mut host_file("file-inodes", &data))
+    });
+}
+
+#[test]
+fn add_dir_without_blocks() {
+    let (image, mut partition) = Image::new("dir-blocks", 16 << 20);
+    fill_blocks(&mut partition, 0);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_BLOCKS, |partition| {
+        partition.add_dir_to_inode(1, "dir")
+    });
+}
+
+#[test]
+fn add_dir_without_inodes() {
+    let (image, mut partition) = Image::new("dir-inodes", 16 << 20);
+    fill_inodes(&mut partition);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_INODES, |partition| {
+        partition.add_dir_to_inode(1, "dir")
+    });
+}
+
+#[test]
+fn mknod_without_inodes() {
+    let (image, mut partition) = Image::new("mknod-inodes", 16 << 20);
+    fill_inodes(&mut partition);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_INODES, |partition| {
+        partition.mknod(1, "null", inode::S_IFCHR | 0o666, inode::makedev(1, 3))
+    });
+}
+
+#[test]
+fn clone_file_without_blocks() {
+    let (image, mut partition) = Image::new("clone-blocks", 16 << 20);
+    let data = contents(3, 3 * 4096);
+    partition
+        .add_file_to_inode(1, "src", &mut host_file("clone-blocks", &data))
+        .unwrap();
+    let src = find(&mut partition, 1, "src").unwrap();
+    // the clone shares the data blocks, but keeping count of the references
+    // takes a block of its own
+    fill_blocks(&mut partition, 0);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_BLOCKS, |partition| {
+        partition.clone_file(src, 1, "clone")
+    });
+    assert_eq!(partition.get_data_from_inode(src).unwrap(), data);
+}
+
+#[test]
+fn clone_file_without_inodes() {
+    let (image, mut partition) = Image::new("clone-inodes", 16 << 20);
+    let data = contents(4, 4096);
+    partition
+        .add_file_to_inode(1, "src", &mut host_file("clone-inodes", &data))
+        .unwrap();
+    let src = find(&mut partition, 1, "src").unwrap();
+    fill_inodes(&mut partition);
+    assert_fails_cleanly(&image, &mut partition, NO_FREE_INODES, |partition| {
+        partition.clone_file(src, 1, "clone")
+    });
+}
+
+// SharedPartition allocates, writes the data without the partition and then
+// links the file, every step can fail
+#[test]
+fn shared_add_file_failures() {
+    let (image, mut partition) = Image::new("shared-add", 16 << 20);
+    fill_blocks(&mut partition, 2);
+    let shared = SharedPartition::new(partition).unwrap();
+    let data = contents(5, 5 * 4096);
+    let long_name = "x".repeat(64);
+    let cases = [
+        ("file", data.clone(), NO_FREE_BLOCKS.to_string()),
+        (
+            long_name.as_str(),
+            data[..4096].to_vec(),
+            "File name longer than 59 bytes".to_string(),
+        ),
+    ];
+    for (name, data, message) in cases {
+        let before = shared.exclusive(|partition| state(partition));
+        let result = shared.add_file_to_inode(1, name, &mut host_file("shared-add", &data));
+        assert_eq!(result.unwrap_err().to_string(), message);
+        assert_eq!(shared.exclusive(|partition| state(partition)), before);
+        assert_eq!(state(&image.reopen()), before);
+    }
+
+    shared.exclusive(fill_inodes);
+    let before = shared.exclusive(|partition| state(partition));
+    let result = shared.add_file_to_inode(1, "file", &mut host_file("shared-add", &data));
+    assert_eq!(result.unwrap_err().to_string(), NO_FREE_INODES);
+    assert_eq!(shared.exclusive(|partition| state(partition)), before);
+    assert_eq!(state(&image.reopen()), before);
+}
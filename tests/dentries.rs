@@ -0,0 +1,97 @@
+mod common;
+
+use std::os::unix::fs::FileExt;
+
+use cfs::{dir_entry, inode::FileType, superblock};
+use common::{add_file, contents, find, Image};
+
+// The file type of every entry is stored in the last byte of its name
+#[test]
+fn typed_dentries_round_trip() {
+    let (image, mut partition) = Image::new("dentries", 16 << 20);
+    add_file(&mut partition, "file", &contents(1, 10));
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let long = "n".repeat(dir_entry::MAX_TYPED_NAME_LEN);
+    add_file(&mut partition, &long, &contents(2, 10));
+    let error = partition
+        .add_dir_to_inode(1, &format!("{long}n"))
+        .unwrap_err();
+    assert_eq!(error.to_string(), "File name longer than 59 bytes");
+    assert_eq!(partition.statfs().max_name_len, 59);
+    let root_block = partition.cfs.block_offset(0);
+    drop(partition);
+
+    let bytes = std::fs::read(&image.path).unwrap();
+    let root = &bytes[root_block as usize..root_block as usize + 4096];
+    let type_of = |name: &str| {
+        root.chunks_exact(dir_entry::MAX_NAME_LEN + 4)
+            .find(|slot| slot.starts_with(name.as_bytes()))
+            .map(|slot| slot[dir_entry::MAX_TYPED_NAME_LEN])
+    };
+    assert_eq!(type_of("file\0"), Some(dir_entry::FT_REG_FILE));
+    assert_eq!(type_of("dir\0"), Some(dir_entry::FT_DIR));
+    assert_eq!(type_of(&long), Some(dir_entry::FT_REG_FILE));
+
+    let mut partition = image.reopen();
+    let items = partition
+        .read_dir(1)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let type_of = |name: &str| {
+        items
+            .iter()
+            .find(|item| item.name == name)
+            .unwrap()
+            .file_type
+    };
+    assert_eq!(type_of("file"), Some(FileType::Regular));
+    assert_eq!(type_of("dir"), Some(FileType::Directory));
+    assert_eq!(type_of(&long), Some(FileType::Regular));
+}
+
+// Images from before typed dentries take names of the whole 60 bytes, their
+// types are looked up in the inodes
+#[test]
+fn untyped_dentries() {
+    let (image, partition) = Image::new("dentries-untyped", 16 << 20);
+    drop(partition);
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    let mut header = [0; 44];
+    blk_dev.read_exact_at(&mut header, 0).unwrap();
+    // no checksum either, the image predates both
+    header[28..32].fill(0);
+    let features = u32::from_le_bytes(header[40..44].try_into().unwrap());
+    let features = features & !(superblock::FEATURE_DIR_FILE_TYPE | superblock::FEATURE_CHECKSUM);
+    header[40..44].copy_from_slice(&features.to_le_bytes());
+    blk_dev.write_all_at(&header, 0).unwrap();
+
+    let mut partition = image.open();
+    assert_eq!(partition.statfs().max_name_len, 60);
+    let long = "n".repeat(dir_entry::MAX_NAME_LEN);
+    partition.add_dir_to_inode(1, &long).unwrap();
+    add_file(&mut partition, "file", &contents(1, 10));
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert!(!partition
+        .cfs
+        .super_block()
+        .has_feature(superblock::FEATURE_DIR_FILE_TYPE));
+    let dir = find(&mut partition, 1, &long).unwrap();
+    let items = partition
+        .read_dir(1)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    let item = items
+        .iter()
+        .find(|item| item.inode as usize == dir)
+        .unwrap();
+    assert_eq!(item.name, *long);
+    assert_eq!(item.file_type, Some(FileType::Directory));
+    let item = items.iter().find(|item| item.name == "file").unwrap();
+    assert_eq!(item.file_type, Some(FileType::Regular));
+}
@@ -0,0 +1,151 @@
+mod common;
+
+use cfs::file;
+use common::{add_file, bitmaps, check_consistency, contents, fill_blocks, find, host_file, Image};
+
+#[test]
+fn truncate_and_fallocate() {
+    let (image, mut partition) = Image::new("truncate", 16 << 20);
+    let data = contents(1, 3 * 4096 + 100);
+    let file = add_file(&mut partition, "file", &data);
+
+    partition.truncate(file, 5000).unwrap();
+    partition.truncate(file, 9000).unwrap();
+    let read = partition.get_data_from_inode(file).unwrap();
+    assert_eq!(read[..5000], data[..5000]);
+    assert!(read[5000..].iter().all(|byte| *byte == 0));
+
+    partition.fallocate(file, 0, 6 * 4096, 0).unwrap();
+    assert_eq!(partition.stat(file).unwrap().size, 6 * 4096);
+    let mode = file::FALLOC_FL_PUNCH_HOLE | file::FALLOC_FL_KEEP_SIZE;
+    partition.fallocate(file, 4096, 4096, mode).unwrap();
+    let read = partition.get_data_from_inode(file).unwrap();
+    assert_eq!(read.len(), 6 * 4096);
+    assert_eq!(read[..4096], data[..4096]);
+    assert!(read[4096..].iter().all(|byte| *byte == 0));
+    check_consistency(&partition);
+    drop(partition);
+    check_consistency(&image.reopen());
+}
+
+// Running out of blocks halfway gives back the ones taken so far
+#[test]
+fn fallocate_without_enough_blocks() {
+    let (image, mut partition) = Image::new("fallocate-full", 16 << 20);
+    let file = add_file(&mut partition, "file", b"");
+    fill_blocks(&mut partition, 3);
+    let before = (partition.statfs(), bitmaps(&partition));
+    let inode = partition.cfs.inode_list().get(file);
+    let result = partition.fallocate(file, 0, 9 * 4096, 0);
+    assert_eq!(result.unwrap_err().to_string(), "No free blocks");
+    assert_eq!((partition.statfs(), bitmaps(&partition)), before);
+    assert_eq!(partition.cfs.inode_list().get(file).blkaddr, inode.blkaddr);
+    drop(partition);
+    let reopened = image.reopen();
+    assert_eq!((reopened.statfs(), bitmaps(&reopened)), before);
+}
+
+#[test]
+fn only_regular_files_have_contents() {
+    let (_image, mut partition) = Image::new("contents-type", 16 << 20);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    let fifo = partition
+        .mknod(1, "fifo", cfs::inode::S_IFIFO | 0o644, 0)
+        .unwrap();
+    let before = partition.cfs.inode_list().get(dir);
+
+    let kind = |result: Result<(), Box<dyn std::error::Error>>| {
+        result
+            .unwrap_err()
+            .downcast::<std::io::Error>()
+            .unwrap()
+            .kind()
+    };
+    assert_eq!(
+        kind(partition.fallocate(dir, 0, 3 * 4096, 0)),
+        std::io::ErrorKind::IsADirectory
+    );
+    assert_eq!(
+        kind(partition.truncate(dir, 100)),
+        std::io::ErrorKind::IsADirectory
+    );
+    assert_eq!(
+        kind(partition.truncate(fifo, 100)),
+        std::io::ErrorKind::InvalidInput
+    );
+    assert_eq!(partition.cfs.inode_list().get(dir), before);
+    check_consistency(&partition);
+}
+
+// Blocks of zeros become holes, which read back as zeros and are skipped by
+// SEEK_DATA and found by SEEK_HOLE
+#[test]
+fn sparse_files() {
+    let (image, mut partition) = Image::new("sparse", 16 << 20);
+    let mut data = vec![0; 5 * 4096];
+    data[4096..8192].copy_from_slice(&contents(2, 4096));
+    data[4 * 4096..4 * 4096 + 10].copy_from_slice(b"0123456789");
+    let file = add_file(&mut partition, "sparse", &data);
+    let inode = partition.cfs.inode_list().get(file);
+    assert_eq!(inode.data_block(0), None);
+    assert_eq!(inode.data_block(2), None);
+    assert!(inode.data_block(1).is_some());
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+    let mut handle = partition.open_file(file);
+    assert_eq!(handle.seek_data(0).unwrap(), 4096);
+    assert_eq!(handle.seek_hole(4096).unwrap(), 2 * 4096);
+    assert_eq!(handle.seek_data(2 * 4096).unwrap(), 4 * 4096);
+    assert_eq!(handle.seek_hole(4 * 4096).unwrap(), 5 * 4096);
+    assert!(handle.seek_data(5 * 4096).is_err());
+    check_consistency(&partition);
+}
+
+// Files only have MAX_FILE_BLOCKS blocks, anything bigger is refused before a
+// thing is allocated
+#[test]
+fn import_too_large() {
+    let (_image, mut partition) = Image::new("too-large", 16 << 20);
+    let data = contents(3, (cfs::inode::MAX_FILE_BLOCKS + 1) * 4096);
+    let before = (partition.statfs(), bitmaps(&partition));
+    let result = partition.add_file_to_inode(1, "big", &mut host_file("big", &data));
+    assert_eq!(result.unwrap_err().to_string(), "File too large");
+    assert_eq!((partition.statfs(), bitmaps(&partition)), before);
+    assert_eq!(find(&mut partition, 1, "big"), None);
+
+    let data = &data[..cfs::inode::MAX_FILE_BLOCKS * 4096];
+    let file = add_file(&mut partition, "fits", data);
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+}
+
+// Small files live in the inode until they outgrow it
+#[test]
+fn inline_data() {
+    let (image, mut partition) = Image::new("inline", 16 << 20);
+    let before = bitmaps(&partition).0;
+    let data = contents(1, 30);
+    let file = add_file(&mut partition, "file", &data);
+    assert!(partition.cfs.inode_list().get(file).is_inline());
+    assert_eq!(bitmaps(&partition).0, before);
+    assert_eq!(image.reopen().get_data_from_inode(file).unwrap(), data);
+
+    // up to MAX_INLINE_DATA bytes still fit
+    let mut data = data;
+    partition.write_at(file, 30, &[9; 10]).unwrap();
+    data.extend_from_slice(&[9; 10]);
+    assert!(partition.cfs.inode_list().get(file).is_inline());
+    assert_eq!(image.reopen().get_data_from_inode(file).unwrap(), data);
+
+    partition.write_at(file, 40, &[8]).unwrap();
+    data.push(8);
+    let inode = partition.cfs.inode_list().get(file);
+    assert!(!inode.is_inline());
+    assert_eq!(bitmaps(&partition).0.len(), before.len() + 1);
+    drop(partition);
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+    check_consistency(&partition);
+}
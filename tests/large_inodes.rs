@@ -0,0 +1,48 @@
+mod common;
+
+use cfs::inode::{Inode, SetAttr, INODE_SIZE, LEGACY_INODE_SIZE};
+use common::{add_file, contents, Image};
+
+// Owners past 16 bits come back from the device whole
+#[test]
+fn large_owners_round_trip() {
+    let (image, mut partition) = Image::new("large-owners", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 100));
+    let attr = SetAttr {
+        uid: Some(100_000),
+        gid: Some(u32::MAX - 1),
+        ..Default::default()
+    };
+    partition.setattr(file, &attr).unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let stat = partition.stat(file).unwrap();
+    assert_eq!((stat.uid, stat.gid), (100_000, u32::MAX - 1));
+    assert_eq!(stat.size, 100);
+}
+
+// The upper halves live in the extended fields, a legacy record has no room
+// for them
+#[test]
+fn inode_record_halves() {
+    let (_image, mut partition) = Image::new("large-record", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 100));
+    let mut inode = partition.cfs.inode_list().get(file);
+    inode.set_file_size(5 << 30);
+    inode.set_uid(0x1_0005);
+    inode.set_gid(0x2_0007);
+
+    let record = inode.to_bytes_sized(INODE_SIZE).unwrap();
+    assert_eq!(record.len(), INODE_SIZE as usize);
+    let read = Inode::from_bytes_sized(&record).unwrap();
+    assert_eq!(read, inode);
+    assert_eq!(read.file_size(), 5 << 30);
+    assert_eq!((read.uid(), read.gid()), (0x1_0005, 0x2_0007));
+
+    let legacy = inode.to_bytes_sized(LEGACY_INODE_SIZE).unwrap();
+    assert_eq!(legacy.len(), LEGACY_INODE_SIZE as usize);
+    let read = Inode::from_bytes_sized(&legacy).unwrap();
+    assert_eq!(read.file_size(), (5u64 << 30) & u32::MAX as u64);
+    assert_eq!((read.uid(), read.gid()), (5, 7));
+}
@@ -0,0 +1,78 @@
+mod common;
+
+use cfs::{dir::DirItem, partition::CfsPartition};
+use common::{add_file, contents, Image};
+
+fn list_from(partition: &mut CfsPartition, cookie: u64) -> Vec<DirItem> {
+    partition
+        .read_dir_from(1, cookie)
+        .collect::<Result<_, _>>()
+        .unwrap()
+}
+
+// Listing a few entries at a time from the cookie of the last one gives the
+// same entries as one go, on the device as well
+#[test]
+fn resume_from_cookies() {
+    let (image, mut partition) = Image::new("readdir", 16 << 20);
+    for n in 0..10 {
+        add_file(&mut partition, &format!("file{n}"), &contents(n, 10));
+    }
+    let all = list_from(&mut partition, 0);
+    assert_eq!(all.len(), 12);
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let mut resumed = Vec::new();
+    let mut cookie = 0;
+    loop {
+        let chunk: Vec<_> = partition
+            .read_dir_from(1, cookie)
+            .take(3)
+            .map(|item| item.unwrap())
+            .collect();
+        let Some(last) = chunk.last() else {
+            break;
+        };
+        cookie = last.cookie;
+        resumed.extend(chunk);
+    }
+    assert_eq!(resumed, all);
+    assert!(list_from(&mut partition, cookie + 100).is_empty());
+}
+
+// Removing entries doesn't move the others, a cookie taken before still points
+// at the same place
+#[test]
+fn cookies_survive_removals() {
+    let (image, mut partition) = Image::new("readdir-remove", 16 << 20);
+    let files: Vec<_> = (0..6)
+        .map(|n| add_file(&mut partition, &format!("file{n}"), &contents(n, 10)))
+        .collect();
+    let all = list_from(&mut partition, 0);
+    let middle = all.iter().position(|item| item.name == "file2").unwrap();
+    let cookie = all[middle].cookie;
+
+    partition.remove_dir_from_inode(1, files[1] as u32).unwrap();
+    partition.remove_dir_from_inode(1, files[4] as u32).unwrap();
+    drop(partition);
+    let mut partition = image.reopen();
+    let rest = list_from(&mut partition, cookie);
+    let expected: Vec<_> = all[middle + 1..]
+        .iter()
+        .filter(|item| item.inode as usize != files[4])
+        .cloned()
+        .collect();
+    assert_eq!(rest, expected);
+
+    // a new entry takes the first free slot, before the cookie
+    drop(partition);
+    let mut partition = image.open();
+    let new = add_file(&mut partition, "new", &contents(9, 10));
+    assert!(list_from(&mut partition, cookie)
+        .iter()
+        .all(|item| item.inode as usize != new));
+    assert!(list_from(&mut partition, 0)
+        .iter()
+        .any(|item| item.inode as usize == new));
+}
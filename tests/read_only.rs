@@ -0,0 +1,66 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{add_file, contents, find, host_file, Image};
+
+fn assert_read_only<T>(result: Result<T, Box<dyn std::error::Error>>) {
+    let error = result.err().unwrap();
+    let error = error.downcast_ref::<std::io::Error>().unwrap();
+    assert_eq!(error.kind(), std::io::ErrorKind::ReadOnlyFilesystem);
+}
+
+// Nothing gets written through a read-only partition, not even on drop
+#[test]
+fn writes_are_refused() {
+    let (image, mut partition) = Image::new("read-only", 16 << 20);
+    let data = contents(1, 2 * 4096 + 5);
+    let file = add_file(&mut partition, "file", &data);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    partition.close().unwrap();
+    let before = std::fs::read(&image.path).unwrap();
+
+    let mut partition = image.reopen();
+    assert!(partition.is_read_only());
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    assert_read_only(partition.add_file_to_inode(1, "new", &mut host_file("ro", b"new")));
+    assert_read_only(partition.add_dir_to_inode(1, "new"));
+    assert_read_only(partition.mknod(1, "fifo", 0o010644, 0));
+    assert_read_only(partition.write_at(file, 0, b"changed"));
+    assert_read_only(partition.truncate(file, 0));
+    assert_read_only(partition.utimens(file, None, None));
+    assert_read_only(partition.setxattr(file, "user.key", b"value", 0));
+    assert_read_only(partition.clone_file(file, 1, "clone"));
+    assert_read_only(partition.remove_dir_from_inode(1, dir as u32));
+    assert_read_only(partition.remove_inode(file));
+    assert_read_only(partition.snapshot_create("snap"));
+    assert_read_only(partition.resize(32 << 20));
+    assert_read_only(partition.mark_errors());
+
+    // reading still works
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data);
+    assert!(partition.stat(dir).unwrap().file_type.is_some());
+    drop(partition);
+    assert_eq!(std::fs::read(&image.path).unwrap(), before);
+
+    // and close is a no-op too
+    image.reopen().close().unwrap();
+    assert_eq!(std::fs::read(&image.path).unwrap(), before);
+}
+
+// A read-write open marks the image dirty, a read-only one leaves it be
+#[test]
+fn state_is_left_alone() {
+    let (image, partition) = Image::new("read-only-state", 16 << 20);
+    drop(partition);
+    let blk_dev = std::fs::File::open(&image.path).unwrap();
+    assert!(CfsPartition::try_from(blk_dev.try_clone().unwrap()).is_err());
+    let partition = CfsPartition::open_read_only(blk_dev).unwrap();
+    let mount_count = partition.cfs.super_block().mount_count;
+    drop(partition);
+
+    drop(image.open());
+    assert_eq!(
+        image.reopen().cfs.super_block().mount_count,
+        mount_count + 1
+    );
+}
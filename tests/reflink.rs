@@ -0,0 +1,118 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{add_file, bitmaps, check_consistency, contents, Image};
+
+fn data_blocks(partition: &CfsPartition, inode_idx: usize) -> Vec<usize> {
+    let inode = partition.cfs.inode_list().get(inode_idx);
+    (0..9).map_while(|n| inode.data_block(n)).collect()
+}
+
+// check_consistency without the one owner per block part, shared blocks have more
+fn check_counts(partition: &CfsPartition) {
+    let (used_blocks, _) = bitmaps(partition);
+    assert_eq!(
+        partition.statfs().free_blocks as usize,
+        partition.cfs.data_blocks() as usize - used_blocks.len()
+    );
+}
+
+fn refcounts(partition: &mut CfsPartition, blocks: &[usize]) -> Vec<u32> {
+    blocks
+        .iter()
+        .map(|block_idx| partition.cfs.block_refcount(*block_idx))
+        .collect()
+}
+
+// A clone shares the blocks until one side writes, which gets that side its own
+// copy of the block and leaves the other one alone
+#[test]
+fn clone_file_round_trip() {
+    let (image, mut partition) = Image::new("reflink", 16 << 20);
+    let free = partition.statfs().free_blocks;
+    let data = contents(1, 3 * 4096);
+    let src = add_file(&mut partition, "src", &data);
+    let clone = partition.clone_file(src, 1, "clone").unwrap();
+    let blocks = data_blocks(&partition, src);
+    assert_eq!(data_blocks(&partition, clone), blocks);
+    assert_eq!(refcounts(&mut partition, &blocks), [2, 2, 2]);
+
+    partition.write_at(clone, 4096 + 10, b"changed").unwrap();
+    let mut changed = data.clone();
+    changed[4096 + 10..4096 + 17].copy_from_slice(b"changed");
+    drop(partition);
+
+    let mut partition = image.open();
+    assert_eq!(partition.get_data_from_inode(src).unwrap(), data);
+    assert_eq!(partition.get_data_from_inode(clone).unwrap(), changed);
+    assert_eq!(data_blocks(&partition, src), blocks);
+    let copied = data_blocks(&partition, clone);
+    assert_eq!((copied[0], copied[2]), (blocks[0], blocks[2]));
+    assert!(!blocks.contains(&copied[1]));
+    assert_eq!(refcounts(&mut partition, &blocks), [2, 1, 2]);
+    check_counts(&partition);
+
+    // the blocks are only free once both are gone
+    partition.remove_dir_from_inode(1, clone as u32).unwrap();
+    assert_eq!(refcounts(&mut partition, &blocks), [1, 1, 1]);
+    partition.remove_dir_from_inode(1, src as u32).unwrap();
+    drop(partition);
+    let mut partition = image.reopen();
+    assert_eq!(refcounts(&mut partition, &blocks), [0, 0, 0]);
+    assert_eq!(refcounts(&mut partition, &copied), [0, 0, 0]);
+    assert_eq!(partition.statfs().free_blocks, free);
+    check_consistency(&partition);
+}
+
+// clone_range swaps the destination's blocks in the range for the source's,
+// freeing the ones it had there
+#[test]
+fn clone_range_round_trip() {
+    let (image, mut partition) = Image::new("reflink-range", 16 << 20);
+    let src_data = contents(1, 4 * 4096);
+    let dst_data = contents(2, 2 * 4096);
+    let src = add_file(&mut partition, "src", &src_data);
+    let dst = add_file(&mut partition, "dst", &dst_data);
+    let src_blocks = data_blocks(&partition, src);
+    let dst_blocks = data_blocks(&partition, dst);
+
+    // blocks 1 and 2 of src over block 1 of dst and past its end
+    partition
+        .clone_range(src, 4096, dst, 4096, 2 * 4096)
+        .unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let mut expected = dst_data[..4096].to_vec();
+    expected.extend_from_slice(&src_data[4096..3 * 4096]);
+    assert_eq!(partition.get_data_from_inode(dst).unwrap(), expected);
+    assert_eq!(partition.get_data_from_inode(src).unwrap(), src_data);
+    assert_eq!(
+        data_blocks(&partition, dst),
+        [dst_blocks[0], src_blocks[1], src_blocks[2]]
+    );
+    assert_eq!(refcounts(&mut partition, &src_blocks), [1, 2, 2, 1]);
+    assert_eq!(refcounts(&mut partition, &dst_blocks), [1, 0]);
+    check_counts(&partition);
+}
+
+// Only regular files have blocks to share, and only in whole blocks
+#[test]
+fn refused() {
+    let (_image, mut partition) = Image::new("reflink-refused", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 2 * 4096));
+    let before = (partition.statfs(), bitmaps(&partition));
+
+    let error = partition.clone_file(1, 1, "root").err().unwrap();
+    assert_eq!(error.to_string(), "Only regular files can be cloned");
+    let error = partition.clone_range(file, 0, 1, 0, 0).unwrap_err();
+    assert_eq!(error.to_string(), "Only regular files can be cloned");
+    let error = partition
+        .clone_range(file, 1, file, 4096, 4096)
+        .unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "Invalid clone range: offsets must be block aligned"
+    );
+    assert_eq!((partition.statfs(), bitmaps(&partition)), before);
+}
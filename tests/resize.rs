@@ -0,0 +1,87 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{add_file, bitmaps, check_consistency, contents, fill_inodes, Image};
+
+fn add_files(partition: &mut CfsPartition) -> Vec<(usize, Vec<u8>)> {
+    (0..6)
+        .map(|n| {
+            let data = contents(n, n * 7000 + 10);
+            (add_file(partition, &format!("file{n}"), &data), data)
+        })
+        .collect()
+}
+
+fn check_files(partition: &mut CfsPartition, files: &[(usize, Vec<u8>)]) {
+    for (inode_idx, data) in files {
+        assert_eq!(partition.get_data_from_inode(*inode_idx).unwrap(), *data);
+    }
+    check_consistency(partition);
+}
+
+fn grow_and_shrink(image: Image, mut partition: CfsPartition, sizes: &[u64]) {
+    let files = add_files(&mut partition);
+    for size in sizes {
+        partition.resize(*size).unwrap();
+        assert_eq!(std::fs::metadata(&image.path).unwrap().len(), *size);
+        check_files(&mut partition, &files);
+        assert_eq!(partition.statfs(), image.reopen().statfs());
+        check_files(&mut image.reopen(), &files);
+    }
+}
+
+// Growing past 128 MiB takes another BAM block, which moves every data block
+#[test]
+fn grow_and_shrink_flat() {
+    let (image, partition) = Image::new("resize-flat", 32 << 20);
+    grow_and_shrink(image, partition, &[160 << 20, 48 << 20, 16 << 20]);
+}
+
+#[test]
+fn grow_and_shrink_groups() {
+    let (image, partition) = Image::with_block_groups("resize-groups", 16 << 20, 1024);
+    grow_and_shrink(image, partition, &[40 << 20, 24 << 20, 16 << 20]);
+}
+
+fn assert_refused(image: &Image, partition: &mut CfsPartition, size: u64, error: &str) {
+    let before = bitmaps(partition);
+    let statfs = partition.statfs();
+    let len = std::fs::metadata(&image.path).unwrap().len();
+    assert_eq!(partition.resize(size).unwrap_err().to_string(), error);
+    assert_eq!(bitmaps(partition), before);
+    assert_eq!(partition.statfs(), statfs);
+    assert_eq!(std::fs::metadata(&image.path).unwrap().len(), len);
+    assert_eq!(bitmaps(&image.reopen()), before);
+}
+
+// The blocks the files use don't fit in the smaller image
+#[test]
+fn shrink_below_used_blocks() {
+    let (image, mut partition) = Image::new("resize-full", 32 << 20);
+    let files = add_files(&mut partition);
+    let (used_blocks, _) = bitmaps(&partition);
+    let metadata_blocks = partition.cfs.super_block().nblocks as u64 - partition.cfs.data_blocks();
+    let size = (metadata_blocks + used_blocks.len() as u64 / 2) * 4096;
+    assert_refused(
+        &image,
+        &mut partition,
+        size,
+        "Not enough free space to move blocks",
+    );
+    check_files(&mut partition, &files);
+}
+
+// Dropping a group takes its inodes along, they have to be free
+#[test]
+fn shrink_below_used_inodes() {
+    let (image, mut partition) = Image::with_block_groups("resize-inodes", 16 << 20, 1024);
+    let files = add_files(&mut partition);
+    fill_inodes(&mut partition);
+    assert_refused(
+        &image,
+        &mut partition,
+        8 << 20,
+        "Inodes in use past the new end",
+    );
+    check_files(&mut partition, &files);
+}
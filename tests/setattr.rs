@@ -0,0 +1,96 @@
+mod common;
+
+use cfs::{
+    inode::{FileType, SetAttr, S_ISGID, S_ISUID},
+    utils::Timespec,
+};
+use common::{add_file, contents, find, Image};
+
+// Everything setattr changes is what stat reads back from the device
+#[test]
+fn setattr_round_trip() {
+    let (image, mut partition) = Image::new("setattr", 16 << 20);
+    let data = contents(1, 3 * 4096);
+    let file = add_file(&mut partition, "file", &data);
+    let attr = SetAttr {
+        mode: Some(0o751),
+        uid: Some(1000),
+        gid: Some(2000),
+        atime: Some(Timespec::new(1_000, 1)),
+        mtime: Some(Timespec::new(2_000, 2)),
+        size: Some(5000),
+    };
+    partition.setattr(file, &attr).unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let stat = partition.stat(file).unwrap();
+    assert_eq!(stat.inode as usize, file);
+    assert_eq!(stat.file_type, Some(FileType::Regular));
+    assert_eq!(stat.perm, 0o751);
+    assert_eq!((stat.uid, stat.gid), (1000, 2000));
+    assert_eq!(stat.atime, Timespec::new(1_000, 1));
+    assert_eq!(stat.mtime, Timespec::new(2_000, 2));
+    assert_eq!((stat.size, stat.blocks, stat.block_size), (5000, 2, 4096));
+    assert_eq!(stat.nlink, 1);
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), data[..5000]);
+}
+
+// A new owner drops setuid, and setgid along with group execute
+#[test]
+fn chown_drops_set_ids() {
+    let (_image, mut partition) = Image::new("setattr-chown", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 10));
+    let mode = |mode| SetAttr {
+        mode: Some(mode),
+        ..Default::default()
+    };
+    let chown = SetAttr {
+        uid: Some(4242),
+        ..Default::default()
+    };
+
+    partition.setattr(file, &mode(0o6755)).unwrap();
+    partition.setattr(file, &chown).unwrap();
+    assert_eq!(partition.stat(file).unwrap().perm, 0o755);
+
+    // without group execute setgid marks mandatory locking and stays
+    partition.setattr(file, &mode(0o6745)).unwrap();
+    partition
+        .setattr(
+            file,
+            &SetAttr {
+                gid: Some(4242),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+    assert_eq!(partition.stat(file).unwrap().perm, S_ISGID | 0o745);
+
+    // the same owner again is no change
+    partition.setattr(file, &mode(S_ISUID | 0o755)).unwrap();
+    partition.setattr(file, &chown).unwrap();
+    assert_eq!(partition.stat(file).unwrap().perm, S_ISUID | 0o755);
+}
+
+// Directories count their subdirectories as links and can't be truncated
+#[test]
+fn directories() {
+    let (_image, mut partition) = Image::new("setattr-dirs", 16 << 20);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    partition.add_dir_to_inode(dir, "a").unwrap();
+    partition.add_dir_to_inode(dir, "b").unwrap();
+    let stat = partition.stat(dir).unwrap();
+    assert_eq!(stat.file_type, Some(FileType::Directory));
+    assert_eq!(stat.nlink, 4);
+
+    let attr = SetAttr {
+        mode: Some(0o700),
+        size: Some(0),
+        ..Default::default()
+    };
+    let error = partition.setattr(dir, &attr).unwrap_err();
+    assert_eq!(error.to_string(), "Only regular files can be truncated");
+    assert_eq!(partition.stat(dir).unwrap(), stat);
+}
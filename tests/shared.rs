@@ -0,0 +1,154 @@
+mod common;
+
+use cfs::{cache::CacheConfig, shared::SharedPartition};
+use common::{check_consistency, contents, host_file, Image};
+
+const WRITERS: usize = 4;
+const READERS: usize = 3;
+const ROUNDS: usize = 8;
+
+// Creates and removes in different directories while other threads read files
+// that are left alone, then check nothing was lost, leaked or shared
+#[test]
+fn concurrent_creates_removes_and_reads() {
+    let (image, mut partition) = Image::new("shared", 32 << 20);
+    let mut files = Vec::new();
+    for n in 0..4 {
+        let data = contents(n, 1000 + n * 3000);
+        let name = format!("read{n}");
+        partition
+            .add_file_to_inode(1, &name, &mut host_file(&name, &data))
+            .unwrap();
+        files.push((common::find(&mut partition, 1, &name).unwrap(), data));
+    }
+    for writer in 0..WRITERS {
+        partition
+            .add_dir_to_inode(1, &format!("dir{writer}"))
+            .unwrap();
+    }
+    let dirs: Vec<_> = (0..WRITERS)
+        .map(|writer| common::find(&mut partition, 1, &format!("dir{writer}")).unwrap())
+        .collect();
+    let shared = SharedPartition::new(partition).unwrap();
+
+    let writers: Vec<_> = dirs
+        .iter()
+        .enumerate()
+        .map(|(writer, dir)| {
+            let (shared, dir) = (shared.clone(), *dir);
+            std::thread::spawn(move || {
+                for round in 0..ROUNDS {
+                    let name = format!("file{round}");
+                    let data = contents(writer * ROUNDS + round, round * 700);
+                    let tag = format!("{writer}-{round}");
+                    shared
+                        .add_file_to_inode(dir, &name, &mut host_file(&tag, &data))
+                        .unwrap();
+                    shared
+                        .add_dir_to_inode(dir, &format!("sub{round}"))
+                        .unwrap();
+
+                    // every other round takes back what it made
+                    if round % 2 == 1 {
+                        let sub = format!("sub{round}");
+                        for item in shared.read_dir(dir).unwrap() {
+                            if item.name == *name || item.name == *sub {
+                                shared.remove_dir_from_inode(dir, item.inode).unwrap();
+                            }
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let readers: Vec<_> = (0..READERS)
+        .map(|reader| {
+            let (shared, files) = (shared.clone(), files.clone());
+            std::thread::spawn(move || {
+                for round in 0..ROUNDS * 4 {
+                    let (inode_idx, data) = &files[(reader + round) % files.len()];
+                    let offset = (round * 397) % data.len();
+                    let mut buf = vec![0; 5000];
+                    let len = shared.read_at(*inode_idx, offset as u64, &mut buf).unwrap();
+                    assert!(len > 0);
+                    assert_eq!(buf[..len], data[offset..offset + len]);
+                }
+            })
+        })
+        .collect();
+
+    for thread in writers.into_iter().chain(readers) {
+        thread.join().unwrap();
+    }
+
+    shared.exclusive(|partition| {
+        check_consistency(partition);
+        for dir in &dirs {
+            // a file and a directory from every even round
+            assert_eq!(partition.read_dir(*dir).count(), ROUNDS);
+        }
+    });
+    drop(shared);
+    check_consistency(&image.reopen());
+}
+
+#[test]
+fn exclusive_keeps_the_block_cache_off() {
+    let (_image, partition) = Image::new("shared-cache", 16 << 20);
+    let shared = SharedPartition::new(partition).unwrap();
+    let result = shared.exclusive(|partition| {
+        partition
+            .set_block_cache(Some(CacheConfig::default()))
+            .map_err(|e| e.to_string())
+    });
+    assert!(result.is_err());
+    assert!(shared
+        .exclusive(|partition| partition.cache_stats())
+        .is_none());
+}
+
+// Growing the image moves the data blocks along with the bitmaps, files created
+// meanwhile must still end up with their own contents
+#[test]
+fn resize_while_creating() {
+    let (image, mut partition) = Image::new("shared-resize", 32 << 20);
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = common::find(&mut partition, 1, "dir").unwrap();
+    let block_size = partition.cfs.super_block().blocksize as usize;
+    let shared = SharedPartition::new(partition).unwrap();
+
+    let creator = {
+        let shared = shared.clone();
+        std::thread::spawn(move || {
+            (0..ROUNDS * 2)
+                .map(|round| {
+                    let name = format!("file{round}");
+                    let data = contents(round, 9 * block_size - round);
+                    shared
+                        .add_file_to_inode(dir, &name, &mut host_file(&name, &data))
+                        .unwrap();
+                    (name, data)
+                })
+                .collect::<Vec<_>>()
+        })
+    };
+    for size in [160u64 << 20, 288 << 20] {
+        shared.exclusive(|partition| partition.resize(size).unwrap());
+    }
+    let files = creator.join().unwrap();
+
+    for (name, data) in &files {
+        let inode_idx = shared
+            .read_dir(dir)
+            .unwrap()
+            .into_iter()
+            .find(|item| item.name == **name)
+            .unwrap()
+            .inode as usize;
+        assert_eq!(shared.get_data_from_inode(inode_idx).unwrap(), *data);
+    }
+    shared.exclusive(|partition| check_consistency(partition));
+    drop(shared);
+    check_consistency(&image.reopen());
+}
@@ -0,0 +1,107 @@
+mod common;
+
+use cfs::partition::CfsPartition;
+use common::{add_file, bitmaps, contents, fill_blocks, find, Image};
+
+// Cloning a range onto itself drops a reference and takes it again, the
+// refcount chain stays where it is
+#[test]
+fn unchanged_refcounts_are_not_rewritten() {
+    let (image, mut partition) = Image::new("refcounts", 16 << 20);
+    let data = contents(1, 3 * 4096);
+    let src = add_file(&mut partition, "src", &data);
+    partition.clone_file(src, 1, "clone").unwrap();
+    let clone = find(&mut partition, 1, "clone").unwrap();
+
+    let refcount_block = partition.cfs.super_block().refcount_block;
+    let before = bitmaps(&partition);
+    assert_ne!(refcount_block, 0);
+    partition.clone_range(src, 0, clone, 0, 0).unwrap();
+    assert_eq!(partition.cfs.super_block().refcount_block, refcount_block);
+    assert_eq!(bitmaps(&partition), before);
+
+    drop(partition);
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(clone).unwrap(), data);
+}
+
+// Blocks the snapshot points to are in use in its view, even once the live tree
+// has let go of them
+#[test]
+fn snapshot_view_keeps_its_blocks() {
+    let (image, mut partition) = Image::new("snapshot-view", 16 << 20);
+    let data = contents(2, 4 * 4096);
+    let file = add_file(&mut partition, "file", &data);
+    partition.snapshot_create("before").unwrap();
+    partition.remove_dir_from_inode(1, file as u32).unwrap();
+    drop(partition);
+
+    let blk_dev = std::fs::File::open(&image.path).unwrap();
+    let mut view = CfsPartition::open_snapshot(blk_dev, "before").unwrap();
+    assert_eq!(view.get_data_from_inode(file).unwrap(), data);
+    let (used_blocks, used_inodes) = bitmaps(&view);
+    for inode_idx in &used_inodes {
+        for block_idx in view.cfs.inode_list().get(*inode_idx).block_addrs() {
+            assert!(used_blocks.contains(&(*block_idx as usize)));
+        }
+    }
+    let statfs = view.statfs();
+    assert_eq!(
+        statfs.free_blocks as usize,
+        view.cfs.data_blocks() as usize - used_blocks.len()
+    );
+    assert_eq!(
+        statfs.free_inodes as usize,
+        statfs.total_inodes as usize - used_inodes.len()
+    );
+}
+
+// Resizing moves the refcount chain, the clones must still share their blocks
+// afterwards
+#[test]
+fn resize_keeps_refcounts() {
+    let (image, mut partition) = Image::new("refcounts-resize", 16 << 20);
+    let data = contents(3, 2 * 4096);
+    let src = add_file(&mut partition, "src", &data);
+    partition.clone_file(src, 1, "clone").unwrap();
+    let clone = find(&mut partition, 1, "clone").unwrap();
+    partition.resize(64 << 20).unwrap();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(clone).unwrap(), data);
+    let block_idx = partition.cfs.inode_list().get(src).data_block(0).unwrap();
+    assert_eq!(partition.cfs.block_refcount(block_idx), 2);
+    let (used_blocks, _) = bitmaps(&partition);
+    assert_eq!(
+        partition.statfs().free_blocks as usize,
+        partition.cfs.data_blocks() as usize - used_blocks.len()
+    );
+}
+
+// Without room for the snapshot's table nothing it did before has to stick: no
+// shared blocks, no copy of the root directory
+#[test]
+fn failed_snapshot_is_undone() {
+    for left in [0, 1] {
+        let (image, mut partition) = Image::new(&format!("snapshot-full{left}"), 16 << 20);
+        let data = contents(4, 2 * 4096);
+        let file = add_file(&mut partition, "file", &data);
+        fill_blocks(&mut partition, left);
+        let before = (partition.statfs(), bitmaps(&partition));
+
+        let error = partition.snapshot_create("full").unwrap_err();
+        assert_eq!(error.to_string(), "No free blocks");
+        assert_eq!((partition.statfs(), bitmaps(&partition)), before);
+        let block_idx = partition.cfs.inode_list().get(file).data_block(0).unwrap();
+        assert_eq!(partition.cfs.block_refcount(block_idx), 1);
+        assert!(partition.snapshot_list().unwrap().is_empty());
+        drop(partition);
+
+        let mut partition = image.reopen();
+        assert_eq!((partition.statfs(), bitmaps(&partition)), before);
+        assert_eq!(partition.cfs.block_refcount(block_idx), 1);
+        assert_eq!(partition.cfs.super_block().refcount_block, 0);
+        assert!(partition.snapshot_list().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,80 @@
+mod common;
+
+use cfs::inode::{self, FileType, S_IFBLK, S_IFCHR, S_IFDIR, S_IFIFO, S_IFSOCK};
+use common::{check_consistency, find, Image};
+
+// Device numbers, types and permissions come back from the device, and none of
+// them takes a block
+#[test]
+fn mknod_round_trip() {
+    let (image, mut partition) = Image::new("mknod", 16 << 20);
+    let before = partition.statfs();
+    let nodes = [
+        (
+            "null",
+            S_IFCHR | 0o666,
+            inode::makedev(1, 3),
+            FileType::CharDevice,
+        ),
+        (
+            "disk",
+            S_IFBLK | 0o660,
+            inode::makedev(259, 0x12345),
+            FileType::BlockDevice,
+        ),
+        ("fifo", S_IFIFO | 0o600, 0, FileType::Fifo),
+        ("socket", S_IFSOCK | 0o755, 0, FileType::Socket),
+    ];
+    for (name, mode, rdev, _) in nodes {
+        partition.mknod(1, name, mode, rdev).unwrap();
+    }
+    assert_eq!(partition.statfs().free_blocks, before.free_blocks);
+    assert_eq!(partition.statfs().free_inodes, before.free_inodes - 4);
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let items = partition
+        .read_dir(1)
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    for (name, mode, rdev, file_type) in nodes {
+        let inode_idx = find(&mut partition, 1, name).unwrap();
+        let stat = partition.stat(inode_idx).unwrap();
+        assert_eq!(stat.file_type, Some(file_type), "{name}");
+        assert_eq!(stat.perm, mode & 0o7777);
+        assert_eq!(stat.rdev, rdev);
+        assert_eq!((stat.size, stat.blocks), (0, 0));
+        let item = items.iter().find(|item| item.name == name).unwrap();
+        assert_eq!(item.file_type, Some(file_type));
+    }
+    let disk = find(&mut partition, 1, "disk").unwrap();
+    let rdev = partition.stat(disk).unwrap().rdev;
+    assert_eq!((inode::major(rdev), inode::minor(rdev)), (259, 0x12345));
+    check_consistency(&partition);
+    drop(partition);
+
+    let mut partition = image.open();
+    for (name, ..) in nodes {
+        let inode_idx = find(&mut partition, 1, name).unwrap();
+        partition
+            .remove_dir_from_inode(1, inode_idx as u32)
+            .unwrap();
+    }
+    assert_eq!(partition.statfs(), before);
+}
+
+// Only files without contents of their own can be made with mknod, and they
+// have no contents to change
+#[test]
+fn mknod_limits() {
+    let (image, mut partition) = Image::new("mknod-limits", 16 << 20);
+    let error = partition.mknod(1, "dir", S_IFDIR | 0o755, 0).unwrap_err();
+    assert_eq!(error.to_string(), "Invalid file type for mknod");
+    assert!(find(&mut partition, 1, "dir").is_none());
+
+    let fifo = partition.mknod(1, "fifo", S_IFIFO | 0o644, 0).unwrap();
+    assert!(partition.truncate(fifo, 10).is_err());
+    assert!(partition.write_at(fifo, 0, b"data").is_err());
+    drop(partition);
+    assert_eq!(image.reopen().stat(fifo).unwrap().size, 0);
+}
@@ -0,0 +1,88 @@
+mod common;
+
+use std::os::unix::fs::FileExt;
+
+use cfs::{partition::CfsPartition, superblock};
+use common::{add_file, bitmaps, check_consistency, contents, find, Image};
+
+// statfs against what the bitmaps say, in memory and on the device
+fn check_counts(image: &Image, partition: &CfsPartition) {
+    let statfs = partition.statfs();
+    let (used_blocks, used_inodes) = bitmaps(partition);
+    assert_eq!(statfs.block_size, 4096);
+    assert_eq!(
+        statfs.total_blocks,
+        partition.cfs.super_block().nblocks as u64
+    );
+    assert_eq!(
+        statfs.free_blocks,
+        partition.cfs.data_blocks() - used_blocks.len() as u64
+    );
+    assert_eq!(statfs.available_blocks, statfs.free_blocks);
+    assert_eq!(
+        statfs.free_inodes,
+        statfs.total_inodes - used_inodes.len() as u64
+    );
+    assert_eq!(image.reopen().statfs(), statfs);
+}
+
+#[test]
+fn counts_follow_creates_and_removes() {
+    let (image, mut partition) = Image::new("statfs", 16 << 20);
+    check_counts(&image, &partition);
+    let empty = partition.statfs();
+    assert_eq!(empty.total_inodes, 64);
+
+    let file = add_file(&mut partition, "file", &contents(1, 3 * 4096));
+    let statfs = partition.statfs();
+    assert_eq!(statfs.free_blocks, empty.free_blocks - 3);
+    assert_eq!(statfs.free_inodes, empty.free_inodes - 1);
+    check_counts(&image, &partition);
+
+    partition.add_dir_to_inode(1, "dir").unwrap();
+    let dir = find(&mut partition, 1, "dir").unwrap();
+    let statfs = partition.statfs();
+    assert_eq!(statfs.free_blocks, empty.free_blocks - 4);
+    assert_eq!(statfs.free_inodes, empty.free_inodes - 2);
+    check_counts(&image, &partition);
+
+    partition.remove_dir_from_inode(1, file as u32).unwrap();
+    partition.remove_dir_from_inode(1, dir as u32).unwrap();
+    assert_eq!(partition.statfs(), empty);
+    check_counts(&image, &partition);
+    check_consistency(&partition);
+}
+
+// Images from before the free counts have them zeroed (and no checksum either),
+// they're counted again from the bitmaps when opened
+#[test]
+fn old_images_are_recounted() {
+    let (image, mut partition) = Image::new("statfs-old", 16 << 20);
+    add_file(&mut partition, "file", &contents(2, 2 * 4096));
+    let statfs = partition.statfs();
+    drop(partition);
+
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    let mut header = [0; 44];
+    blk_dev.read_exact_at(&mut header, 0).unwrap();
+    // checksum, free_blocks and free_inodes, then FEATURE_CHECKSUM
+    header[28..40].fill(0);
+    let features = u32::from_le_bytes(header[40..44].try_into().unwrap());
+    header[40..44].copy_from_slice(&(features & !superblock::FEATURE_CHECKSUM).to_le_bytes());
+    blk_dev.write_all_at(&header, 0).unwrap();
+
+    // the primary is still good, not a backup
+    let reopened = image.reopen();
+    assert!(!reopened
+        .cfs
+        .super_block()
+        .has_feature(superblock::FEATURE_CHECKSUM));
+    assert_eq!(reopened.statfs(), statfs);
+    drop(reopened);
+    let partition = CfsPartition::try_from(blk_dev).unwrap();
+    check_counts(&image, &partition);
+}
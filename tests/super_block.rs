@@ -0,0 +1,64 @@
+mod common;
+
+use std::os::unix::fs::FileExt;
+
+use cfs::{dir_entry, partition::CfsPartition, superblock, MAGIC};
+use common::{add_file, contents, Image};
+
+// Lose the primary super block, open the image from a backup and put it back
+#[test]
+fn repair_from_backup() {
+    let (image, mut partition) = Image::new("repair", 16 << 20);
+    let data = contents(1, 5000);
+    let inode_idx = add_file(&mut partition, "file", &data);
+    let nblocks = partition.cfs.super_block().nblocks as u64;
+    drop(partition);
+
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    blk_dev.write_all_at(&[0; 4096], 0).unwrap();
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(inode_idx).unwrap(), data);
+    drop(partition);
+
+    let mut partition = CfsPartition::try_from(blk_dev.try_clone().unwrap()).unwrap();
+    partition.repair_super_block().unwrap();
+    assert!(!partition.repair_super_block().unwrap());
+    drop(partition);
+
+    let mut block0 = vec![0; 4096];
+    blk_dev.read_exact_at(&mut block0, 0).unwrap();
+    for location in superblock::backup_locations(nblocks) {
+        let mut backup = vec![0; 4096];
+        blk_dev.read_exact_at(&mut backup, location * 4096).unwrap();
+        assert_eq!(block0, backup);
+    }
+    assert_ne!(block0, vec![0; 4096]);
+    let mut partition = image.reopen();
+    assert_eq!(partition.get_data_from_inode(inode_idx).unwrap(), data);
+}
+
+// Images are little endian whatever the host, down to the fields read by hand
+#[test]
+fn little_endian_on_disk() {
+    let (image, mut partition) = Image::new("endian", 16 << 20);
+    let inode_idx = add_file(&mut partition, "file", &contents(2, 10));
+    let root_block = partition.cfs.block_offset(0);
+    drop(partition);
+
+    let bytes = std::fs::read(&image.path).unwrap();
+    assert_eq!(bytes[..4], MAGIC.to_le_bytes());
+    assert_eq!(bytes[4..8], 4096u32.to_le_bytes());
+
+    let slot_size = dir_entry::MAX_NAME_LEN + 4;
+    let root = &bytes[root_block as usize..root_block as usize + 4096];
+    let slot = root
+        .chunks_exact(slot_size)
+        .find(|slot| slot.starts_with(b"file\0"))
+        .unwrap();
+    let inode = &slot[dir_entry::MAX_NAME_LEN..];
+    assert_eq!(inode, (inode_idx as u32).to_le_bytes());
+}
@@ -0,0 +1,65 @@
+mod common;
+
+use std::os::unix::fs::MetadataExt;
+
+use cfs::utils::{self, Timespec};
+use common::{add_file, contents, host_file, Image};
+
+// Nanoseconds, times past 2038 and before 1970 all come back as they were
+#[test]
+fn times_round_trip() {
+    let (image, mut partition) = Image::new("times", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 100));
+    let atime = Timespec::new(4_102_444_800, 123_456_789);
+    let mtime = Timespec::new(-86_400, 999_999_999);
+    let before = utils::now();
+    partition.utimens(file, Some(atime), Some(mtime)).unwrap();
+    let after = utils::now();
+    drop(partition);
+
+    let mut partition = image.reopen();
+    let stat = partition.stat(file).unwrap();
+    assert_eq!((stat.atime, stat.mtime), (atime, mtime));
+    assert!(before <= stat.ctime && stat.ctime <= after);
+    assert!(stat.crtime <= before);
+}
+
+// An import keeps the host file's times, the birth time is when it was made
+#[test]
+fn import_keeps_host_times() {
+    let (image, mut partition) = Image::new("times-import", 16 << 20);
+    let mut host = host_file("times-import", &contents(2, 10));
+    let metadata = host.metadata().unwrap();
+    let modified = Timespec::new(metadata.mtime(), metadata.mtime_nsec() as u32);
+    let before = utils::now();
+    partition.add_file_to_inode(1, "file", &mut host).unwrap();
+    let after = utils::now();
+    let file = common::find(&mut partition, 1, "file").unwrap();
+    drop(partition);
+
+    let stat = image.reopen().stat(file).unwrap();
+    assert_eq!(stat.mtime, modified);
+    assert!(before <= stat.crtime && stat.crtime <= after);
+}
+
+// UTIME_OMIT leaves a time alone, nanoseconds out of range are refused
+#[test]
+fn omit_and_invalid() {
+    let (_image, mut partition) = Image::new("times-omit", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(3, 10));
+    let atime = Timespec::new(1_000, 1);
+    partition.utimens(file, Some(atime), None).unwrap();
+    let mtime = partition.stat(file).unwrap().mtime;
+    partition
+        .utimens(file, None, Some(Timespec::new(2_000, 2)))
+        .unwrap();
+    let stat = partition.stat(file).unwrap();
+    assert_eq!(stat.atime, atime);
+    assert_ne!(stat.mtime, mtime);
+
+    let error = partition
+        .utimens(file, Some(Timespec::new(0, 1_000_000_000)), None)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Invalid nanoseconds");
+    assert_eq!(partition.stat(file).unwrap().atime, atime);
+}
@@ -0,0 +1,82 @@
+mod common;
+
+use cfs::{
+    bitmap::{Bam, Iam},
+    inode::InodeList,
+    partition::CfsPartition,
+    StatFs,
+};
+use common::{add_file, check_consistency, contents, fill_blocks, find, host_file, Image};
+
+// Everything a transaction has to put back
+fn state(partition: &CfsPartition) -> (StatFs, Bam, Iam, InodeList) {
+    let cfs = &partition.cfs;
+    (
+        partition.statfs(),
+        cfs.bam().clone(),
+        cfs.iam().clone(),
+        cfs.inode_list().clone(),
+    )
+}
+
+// A file is created and another one written to before running out of blocks
+// halfway through a third, none of it must be left behind
+#[test]
+fn rollback_after_failing_partway() {
+    let (image, mut partition) = Image::new("rollback", 16 << 20);
+    let old = contents(1, 4096 + 10);
+    let file = add_file(&mut partition, "old", &old);
+    fill_blocks(&mut partition, 4);
+    let before = state(&partition);
+
+    let result = partition.transaction(|tx| {
+        tx.add_file_to_inode(1, "new", &mut host_file("rollback-new", &contents(2, 5000)))?;
+        assert_eq!(tx.write_at(file, 4096 * 2, &[7; 4096])?, 4096);
+        tx.add_file_to_inode(1, "big", &mut host_file("rollback-big", &contents(3, 9000)))
+    });
+    assert_eq!(result.unwrap_err().to_string(), "No free blocks");
+
+    assert_eq!(state(&partition), before);
+    assert!(find(&mut partition, 1, "new").is_none());
+    assert_eq!(partition.get_data_from_inode(file).unwrap(), old);
+    let mut reopened = image.reopen();
+    assert_eq!(state(&reopened), before);
+    assert!(find(&mut reopened, 1, "new").is_none());
+    assert_eq!(reopened.get_data_from_inode(file).unwrap(), old);
+}
+
+// Nothing reaches the device until the transaction commits, then all of it does
+#[test]
+fn commit_reaches_the_device() {
+    let (image, mut partition) = Image::new("commit", 16 << 20);
+    let old = contents(1, 4096 + 10);
+    let file = add_file(&mut partition, "old", &old);
+    let gone = add_file(&mut partition, "gone", &contents(2, 100));
+    let new = contents(3, 5000);
+
+    partition
+        .transaction(|tx| {
+            tx.add_file_to_inode(1, "new", &mut host_file("commit-new", &new))?;
+            tx.write_at(file, 0, &[7; 10])?;
+            tx.remove_dir_from_inode(1, gone as u32)?;
+
+            let mut reopened = image.reopen();
+            assert!(find(&mut reopened, 1, "new").is_none());
+            assert_eq!(reopened.get_data_from_inode(file).unwrap(), old);
+            assert!(find(&mut reopened, 1, "gone").is_some());
+            Ok(())
+        })
+        .unwrap();
+    let after = state(&partition);
+    drop(partition);
+
+    let mut reopened = image.reopen();
+    assert_eq!(state(&reopened), after);
+    let inode_idx = find(&mut reopened, 1, "new").unwrap();
+    assert_eq!(reopened.get_data_from_inode(inode_idx).unwrap(), new);
+    let mut written = old.clone();
+    written[..10].fill(7);
+    assert_eq!(reopened.get_data_from_inode(file).unwrap(), written);
+    assert!(find(&mut reopened, 1, "gone").is_none());
+    check_consistency(&reopened);
+}
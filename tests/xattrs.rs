@@ -0,0 +1,91 @@
+mod common;
+
+use cfs::{
+    partition::CfsPartition,
+    xattr::{XATTR_CREATE, XATTR_REPLACE},
+};
+use common::{add_file, check_consistency, contents, Image};
+
+// An image whose inodes have 128 bytes to spare for xattrs
+fn large_inode_image(name: &str) -> (Image, CfsPartition) {
+    let (image, partition) = Image::new(name, 16 << 20);
+    drop(partition);
+    let blk_dev = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&image.path)
+        .unwrap();
+    let mut partition = CfsPartition::new_with_inode_size(blk_dev, 4096, 256).unwrap();
+    partition.write_cfs().unwrap();
+    partition.setup_root_dir().unwrap();
+    (image, partition)
+}
+
+// Small attributes stay in the inode, the rest spill over to a block of their
+// own which goes away with them
+#[test]
+fn round_trip_and_spill_over() {
+    let (image, mut partition) = large_inode_image("xattrs");
+    let file = add_file(&mut partition, "file", &contents(1, 100));
+    let empty = partition.statfs();
+
+    partition.setxattr(file, "user.small", b"value", 0).unwrap();
+    partition.setxattr(file, "trusted.other", b"", 0).unwrap();
+    assert_eq!(partition.statfs(), empty);
+    let big = contents(2, 1000);
+    partition.setxattr(file, "user.big", &big, 0).unwrap();
+    assert_eq!(partition.statfs().free_blocks, empty.free_blocks - 1);
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert_eq!(partition.getxattr(file, "user.small").unwrap(), b"value");
+    assert_eq!(partition.getxattr(file, "trusted.other").unwrap(), b"");
+    assert_eq!(partition.getxattr(file, "user.big").unwrap(), big);
+    let mut names = partition.listxattr(file).unwrap();
+    names.sort();
+    assert_eq!(names, ["trusted.other", "user.big", "user.small"]);
+    drop(partition);
+
+    let mut partition = image.open();
+    partition.removexattr(file, "user.big").unwrap();
+    assert_eq!(partition.statfs(), empty);
+    partition.setxattr(file, "user.big", &big, 0).unwrap();
+    partition.remove_dir_from_inode(1, file as u32).unwrap();
+    check_consistency(&partition);
+    assert_eq!(image.reopen().statfs().free_blocks, empty.free_blocks + 1);
+}
+
+// XATTR_CREATE and XATTR_REPLACE, and what can't be stored at all
+#[test]
+fn flags_and_limits() {
+    let (image, mut partition) = Image::new("xattrs-flags", 16 << 20);
+    let file = add_file(&mut partition, "file", &contents(1, 100));
+
+    let error = partition.getxattr(file, "user.name").unwrap_err();
+    assert_eq!(error.to_string(), "No such attribute");
+    let error = partition
+        .setxattr(file, "user.name", b"1", XATTR_REPLACE)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "No such attribute");
+    partition
+        .setxattr(file, "user.name", b"1", XATTR_CREATE)
+        .unwrap();
+    let error = partition
+        .setxattr(file, "user.name", b"2", XATTR_CREATE)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "Attribute already exists");
+    partition
+        .setxattr(file, "user.name", b"2", XATTR_REPLACE)
+        .unwrap();
+
+    assert!(partition.setxattr(file, "nonamespace", b"", 0).is_err());
+    let error = partition
+        .setxattr(file, "user.huge", &contents(3, 5000), 0)
+        .unwrap_err();
+    assert_eq!(error.to_string(), "No space left for extended attributes");
+    drop(partition);
+
+    let mut partition = image.reopen();
+    assert_eq!(partition.getxattr(file, "user.name").unwrap(), b"2");
+    assert_eq!(partition.listxattr(file).unwrap(), ["user.name"]);
+}